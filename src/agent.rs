@@ -0,0 +1,654 @@
+use std::io;
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use blake3::Hash;
+use tokio::fs;
+use tokio::fs::File;
+use tokio::io::{
+    stdin, stdout, AsyncRead, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter,
+};
+use tokio::net::TcpListener;
+
+use crate::protocol::{
+    decode_os_str, decode_path, encode_os_str, read_frame, write_frame, Envelope, Request,
+    Response, ATTRIBUTES_PROTOCOL_VERSION, AUTH_PROTOCOL_VERSION, DELTA_PROTOCOL_VERSION,
+    HARD_LINK_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION, SYMLINK_PROTOCOL_VERSION,
+};
+use crate::target::{LocalTarget, SyncTarget, STATE_DIR_NAME};
+use crate::utils::Utils;
+use crate::{info, warn};
+
+/// Partial files of interrupted transfers older than this are removed when the agent starts
+const PARTIAL_FILE_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The side of a remote target which applies the operations of an engine to a local dir
+pub struct Agent;
+
+/// State of a connection to the agent
+struct Session {
+    target: LocalTarget,
+    version: Option<u32>,
+    /// Hash of the token the connection must give before any other request, until it does
+    token: Option<Hash>,
+    upload: Option<Upload>,
+    patch: Option<Patch>,
+}
+
+/// Copy of a file patched by the delta mode, moved over the file once its length is set
+struct Patch {
+    path: PathBuf,
+    temp_path: PathBuf,
+    file: File,
+}
+
+/// File being transferred, written to a partial file until all its bytes are received
+struct Upload {
+    path: PathBuf,
+    partial_path: PathBuf,
+    file: File,
+    len: u64,
+    written: u64,
+}
+
+impl Agent {
+    /// Apply the requests read from stdin to `target` and write the responses to stdout, until
+    /// stdin is closed
+    pub async fn serve_stdio(target: LocalTarget) -> io::Result<()> {
+        Self::remove_old_partial_files(target.root()).await;
+        let (reader, writer) = (BufReader::new(stdin()), BufWriter::new(stdout()));
+        Self::serve(target, None, reader, writer).await
+    }
+
+    /// Accept connections on `addr` and serve each one on `target` once it gave `token`, until
+    /// an error occurs
+    pub async fn listen(addr: &str, target: LocalTarget, token: &str) -> io::Result<()> {
+        let token = blake3::hash(token.as_bytes());
+        Self::remove_old_partial_files(target.root()).await;
+        let listener = TcpListener::bind(addr).await?;
+
+        info!(
+            "Ready - Serving '{}' on {}",
+            Utils::fmt_path(target.root()),
+            listener.local_addr()?
+        );
+        loop {
+            let (stream, peer_addr) = listener.accept().await?;
+            let target = target.clone();
+
+            tokio::spawn(async move {
+                info!("{} connected", peer_addr);
+                let (reader, writer) = stream.into_split();
+                let (reader, writer) = (BufReader::new(reader), BufWriter::new(writer));
+                let result = Self::serve(target, Some(token), reader, writer).await;
+                match result {
+                    Ok(()) => info!("{} disconnected", peer_addr),
+                    Err(err) => warn!("{} disconnected, error: {}", peer_addr, err),
+                }
+            });
+        }
+    }
+
+    /// Serve the requests of a single connection, until it is closed. With a `token`, given as its
+    /// hash, the connection must authenticate right after the handshake
+    pub async fn serve<R, W>(
+        target: LocalTarget,
+        token: Option<Hash>,
+        mut reader: R,
+        mut writer: W,
+    ) -> io::Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut session = Session {
+            target,
+            version: None,
+            token,
+            upload: None,
+            patch: None,
+        };
+
+        while let Some(request) = read_frame::<_, Envelope<Request>>(&mut reader).await? {
+            let is_handshake = matches!(request.body, Request::Hello { .. })
+                || (session.version.is_some()
+                    && matches!(request.body, Request::Authenticate { .. }));
+            let refusal = if session.version.is_none() && !is_handshake {
+                Some("the connection must start with a handshake")
+            } else if session.token.is_some() && !is_handshake {
+                Some("the connection must authenticate first")
+            } else {
+                None
+            };
+            if let Some(refusal) = refusal {
+                let response = Response::Error {
+                    not_found: false,
+                    message: refusal.to_string(),
+                };
+                let envelope = Envelope {
+                    id: request.id,
+                    body: response,
+                };
+                return write_frame(&mut writer, &envelope).await;
+            }
+
+            let response = session
+                .handle_request(request.body)
+                .await
+                .unwrap_or_else(Response::from_err);
+            let handshake_failed = is_handshake && matches!(response, Response::Error { .. });
+
+            let envelope = Envelope {
+                id: request.id,
+                body: response,
+            };
+            write_frame(&mut writer, &envelope).await?;
+
+            if handshake_failed {
+                break;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn remove_old_partial_files(root: &Path) {
+        let Ok(mut entries) = fs::read_dir(Self::partial_dir(root)).await else {
+            return;
+        };
+
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let is_old = entry
+                .metadata()
+                .await
+                .ok()
+                .and_then(|metadata| metadata.modified().ok())
+                .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                .is_some_and(|age| age > PARTIAL_FILE_RETENTION);
+            if is_old {
+                let _ = fs::remove_file(entry.path()).await;
+            }
+        }
+    }
+
+    fn partial_dir(root: &Path) -> PathBuf {
+        root.join(STATE_DIR_NAME).join("partial")
+    }
+}
+
+impl Session {
+    async fn handle_request(&mut self, request: Request) -> io::Result<Response> {
+        let target = &self.target;
+        match request {
+            Request::Hello {
+                min_version,
+                max_version,
+            } => {
+                let version = max_version.min(PROTOCOL_VERSION);
+                if version < min_version.max(MIN_PROTOCOL_VERSION) {
+                    return Err(io::Error::other(format!(
+                        "unsupported protocol versions {} to {}, the agent supports {} to {}",
+                        min_version, max_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+                    )));
+                }
+
+                self.version = Some(version);
+                // Older engines don't know about the symlinks
+                if version < SYMLINK_PROTOCOL_VERSION {
+                    self.target = self.target.clone().with_follow_symlinks(true);
+                }
+                return Ok(Response::Welcome { version });
+            }
+            Request::Authenticate { token } => {
+                self.require_version(AUTH_PROTOCOL_VERSION)?;
+                // Comparing the hashes takes the same time whatever the token
+                if self
+                    .token
+                    .is_some_and(|expected| expected != blake3::hash(token.as_bytes()))
+                {
+                    return Err(io::Error::new(ErrorKind::PermissionDenied, "invalid token"));
+                }
+                self.token = None;
+            }
+            Request::WriteStart {
+                path,
+                len,
+                transfer_id,
+            } => {
+                let path = self.decode_path(&path).await?;
+                let offset = self.start_upload(path, len, &transfer_id).await?;
+                return Ok(Response::Resume { offset });
+            }
+            Request::WriteChunk { offset, data } => self.write_chunk(offset, &data).await?,
+            Request::WriteEnd => self.end_upload().await?,
+            Request::CreateDir { path } => {
+                target.create_dir(&self.decode_path(&path).await?).await?
+            }
+            Request::RemoveFile { path } => {
+                target.remove_file(&self.decode_path(&path).await?).await?
+            }
+            Request::RemoveDir { path } => {
+                target.remove_dir(&self.decode_path(&path).await?).await?
+            }
+            Request::Rename { from, to } => {
+                target
+                    .rename(
+                        &self.decode_path(&from).await?,
+                        &self.decode_path(&to).await?,
+                    )
+                    .await?;
+            }
+            Request::Stat { path } => {
+                return Ok(Response::Stat(
+                    target.stat(&self.decode_path(&path).await?).await?,
+                ));
+            }
+            Request::Hash { path } => {
+                let hash = target.hash(&self.decode_path(&path).await?).await?;
+                return Ok(Response::Hash(*hash.as_bytes()));
+            }
+            Request::ReadDir { path } => {
+                let entries = target.read_dir(&self.decode_path(&path).await?).await?;
+                return Ok(Response::Entries(
+                    entries
+                        .into_iter()
+                        .map(|(name, path_type)| (encode_os_str(&name), path_type))
+                        .collect(),
+                ));
+            }
+            Request::BlockHashes { path, block_size } => {
+                self.require_version(DELTA_PROTOCOL_VERSION)?;
+                let hashes = target
+                    .block_hashes(&self.decode_path(&path).await?, block_size as usize)
+                    .await?;
+                return Ok(Response::BlockHashes(
+                    hashes.iter().map(|hash| *hash.as_bytes()).collect(),
+                ));
+            }
+            Request::WriteBlock { path, offset, data } => {
+                self.require_version(DELTA_PROTOCOL_VERSION)?;
+                let path = self.decode_path(&path).await?;
+                let file = self.patch_file(path).await?;
+                file.seek(io::SeekFrom::Start(offset)).await?;
+                file.write_all(&data).await?;
+            }
+            Request::SetLen { path, len } => {
+                self.require_version(DELTA_PROTOCOL_VERSION)?;
+                let path = self.decode_path(&path).await?;
+                self.end_patch(path, len).await?;
+            }
+            Request::CreateSymlink { path, link } => {
+                self.require_version(SYMLINK_PROTOCOL_VERSION)?;
+                let path = self.decode_path(&path).await?;
+                let link = decode_link(target.root(), &path, &link).await?;
+                target.create_symlink(&path, &link).await?;
+            }
+            Request::ReadLink { path } => {
+                self.require_version(SYMLINK_PROTOCOL_VERSION)?;
+                let link = target.read_link(&self.decode_path(&path).await?).await?;
+                return Ok(Response::Link(encode_os_str(link.as_os_str())));
+            }
+            Request::HardLink { path, original } => {
+                self.require_version(HARD_LINK_PROTOCOL_VERSION)?;
+                target
+                    .hard_link(
+                        &self.decode_path(&path).await?,
+                        &self.decode_path(&original).await?,
+                    )
+                    .await?;
+            }
+            Request::SameFile { path, other } => {
+                self.require_version(HARD_LINK_PROTOCOL_VERSION)?;
+                let same_file = target
+                    .same_file(
+                        &self.decode_path(&path).await?,
+                        &self.decode_path(&other).await?,
+                    )
+                    .await?;
+                return Ok(Response::SameFile(same_file));
+            }
+            Request::SetAttributes { path, attributes } => {
+                self.require_version(ATTRIBUTES_PROTOCOL_VERSION)?;
+                target
+                    .set_attributes(&self.decode_path(&path).await?, &attributes)
+                    .await?;
+            }
+        }
+
+        Ok(Response::Done)
+    }
+
+    /// The paths going through a symlink of the root are refused, it may lead out of it
+    async fn decode_path(&self, path: &[u8]) -> io::Result<PathBuf> {
+        let path = decode_path(path)?;
+        let mut dir = self.target.root().to_path_buf();
+        for component in path.parent().into_iter().flat_map(Path::components) {
+            dir.push(component);
+            match fs::symlink_metadata(&dir).await {
+                Ok(metadata) if metadata.file_type().is_symlink() => {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        format!(
+                            "path '{}' goes through a symlink of the agent",
+                            path.display()
+                        ),
+                    ))
+                }
+                Ok(_) => {}
+                // Created as a dir if needed
+                Err(_) => break,
+            }
+        }
+
+        Ok(path)
+    }
+
+    fn require_version(&self, version: u32) -> io::Result<()> {
+        if self
+            .version
+            .is_some_and(|session_version| session_version >= version)
+        {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                ErrorKind::Unsupported,
+                format!("protocol version {} required", version),
+            ))
+        }
+    }
+
+    /// Open the partial file of the transfer, keeping the bytes received by a previous attempt
+    async fn start_upload(
+        &mut self,
+        path: PathBuf,
+        len: u64,
+        transfer_id: &str,
+    ) -> io::Result<u64> {
+        let partial_dir = Agent::partial_dir(self.target.root());
+        fs::create_dir_all(&partial_dir).await?;
+
+        let key = format!("{}\0{}\0{}", path.display(), len, transfer_id);
+        let partial_path = partial_dir.join(blake3::hash(key.as_bytes()).to_hex().as_str());
+
+        let received = match fs::metadata(&partial_path).await {
+            Ok(metadata) if metadata.len() <= len => metadata.len(),
+            _ => 0,
+        };
+        let file = if received > 0 {
+            fs::OpenOptions::new()
+                .append(true)
+                .open(&partial_path)
+                .await?
+        } else {
+            File::create(&partial_path).await?
+        };
+
+        self.upload = Some(Upload {
+            path,
+            partial_path,
+            file,
+            len,
+            written: received,
+        });
+
+        Ok(received)
+    }
+
+    /// The copy of the file being patched, started by the first block of the file
+    async fn patch_file(&mut self, path: PathBuf) -> io::Result<&mut File> {
+        if self.patch.as_ref().is_some_and(|patch| patch.path != path) {
+            self.discard_patch().await;
+        }
+
+        if self.patch.is_none() {
+            let partial_dir = Agent::partial_dir(self.target.root());
+            fs::create_dir_all(&partial_dir).await?;
+            let temp_path = partial_dir.join(format!("patch{}", LocalTarget::unique_suffix()));
+            let file = match self.target.copy_to_patch(&path, &temp_path).await {
+                Ok(file) => file,
+                Err(err) => {
+                    let _ = fs::remove_file(&temp_path).await;
+                    return Err(err);
+                }
+            };
+            self.patch = Some(Patch {
+                path,
+                temp_path,
+                file,
+            });
+        }
+
+        Ok(&mut self.patch.as_mut().unwrap().file)
+    }
+
+    /// Set the length of the patched copy and move it over the file
+    async fn end_patch(&mut self, path: PathBuf, len: u64) -> io::Result<()> {
+        let file = self.patch_file(path).await?;
+        let resized = async {
+            file.set_len(len).await?;
+            file.flush().await
+        }
+        .await;
+
+        let patch = self.patch.take().unwrap();
+        let result = match resized {
+            Ok(()) => {
+                self.target
+                    .persist(patch.file, &patch.temp_path, &patch.path)
+                    .await
+            }
+            Err(err) => {
+                drop(patch.file);
+                Err(err)
+            }
+        };
+        if result.is_err() {
+            let _ = fs::remove_file(&patch.temp_path).await;
+        }
+
+        result
+    }
+
+    async fn discard_patch(&mut self) {
+        if let Some(patch) = self.patch.take() {
+            drop(patch.file);
+            let _ = fs::remove_file(&patch.temp_path).await;
+        }
+    }
+
+    async fn write_chunk(&mut self, offset: u64, data: &[u8]) -> io::Result<()> {
+        let Some(upload) = &mut self.upload else {
+            return Err(io::Error::other("no transfer started"));
+        };
+        if offset != upload.written || offset + data.len() as u64 > upload.len {
+            return Err(io::Error::other(format!(
+                "unexpected chunk at offset {}, {} bytes received",
+                offset, upload.written
+            )));
+        }
+
+        upload.file.write_all(data).await?;
+        upload.written += data.len() as u64;
+
+        Ok(())
+    }
+
+    async fn end_upload(&mut self) -> io::Result<()> {
+        let Some(mut upload) = self.upload.take() else {
+            return Err(io::Error::other("no transfer started"));
+        };
+        if upload.written != upload.len {
+            return Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                format!("{} of {} bytes received", upload.written, upload.len),
+            ));
+        }
+
+        upload.file.flush().await?;
+        self.target
+            .persist(upload.file, &upload.partial_path, &upload.path)
+            .await
+    }
+}
+
+/// Links leaving the root of the agent are refused, the writes through them would too. The
+/// symlinks the link goes through are followed, `path` itself must not go through any
+async fn decode_link(root: &Path, path: &Path, link: &[u8]) -> io::Result<PathBuf> {
+    let link = PathBuf::from(decode_os_str(link)?);
+    let leaves_root = || {
+        io::Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "link '{}' of '{}' leaves the root of the agent",
+                link.display(),
+                path.display()
+            ),
+        )
+    };
+
+    let mut depth = path.components().count().saturating_sub(1);
+    for component in link.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir if depth > 0 => depth -= 1,
+            _ => return Err(leaves_root()),
+        }
+    }
+
+    let root = fs::canonicalize(root).await?;
+    let parent = root.join(path.parent().unwrap_or(Path::new("")));
+    match resolve(&parent.join(&link)).await {
+        Some(resolved) if resolved.starts_with(&root) => Ok(link),
+        _ => Err(leaves_root()),
+    }
+}
+
+/// The absolute `path` with its existing part resolved by the filesystem, the rest can't go
+/// through a symlink. `None` when it goes through a dangling symlink, it may point anywhere once
+/// its target is created
+async fn resolve(path: &Path) -> Option<PathBuf> {
+    let components: Vec<_> = path.components().collect();
+    for existing in (1..=components.len()).rev() {
+        let existing_path: PathBuf = components[..existing].iter().collect();
+        let mut resolved = match fs::canonicalize(&existing_path).await {
+            Ok(resolved) => resolved,
+            Err(_) => {
+                let is_symlink = fs::symlink_metadata(&existing_path)
+                    .await
+                    .is_ok_and(|metadata| metadata.file_type().is_symlink());
+                if is_symlink {
+                    return None;
+                }
+                continue;
+            }
+        };
+
+        for component in &components[existing..] {
+            match component {
+                Component::ParentDir => {
+                    resolved.pop();
+                }
+                Component::Normal(name) => resolved.push(name),
+                _ => {}
+            }
+        }
+        return Some(resolved);
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    fn session(root: &TempDir) -> Session {
+        Session {
+            target: LocalTarget::new(root.path().to_path_buf()),
+            version: Some(PROTOCOL_VERSION),
+            token: None,
+            upload: None,
+            patch: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn links_inside_the_root_are_accepted() {
+        let root = TempDir::new().unwrap();
+        for (path, link) in [
+            ("l", "f"),
+            ("l", "./dir/f"),
+            ("dir/l", "../f"),
+            ("dir/sub/l", "../../dir/f"),
+            ("l", "dir/../f"),
+        ] {
+            let decoded = decode_link(root.path(), Path::new(path), link.as_bytes())
+                .await
+                .unwrap();
+            assert_eq!(decoded, PathBuf::from(link), "{} -> {}", path, link);
+        }
+    }
+
+    #[tokio::test]
+    async fn links_leaving_the_root_are_refused() {
+        let root = TempDir::new().unwrap();
+        for (path, link) in [
+            ("l", "../f"),
+            ("dir/l", "../../f"),
+            ("l", "dir/../../f"),
+            ("l", "/etc/passwd"),
+        ] {
+            let err = decode_link(root.path(), Path::new(path), link.as_bytes())
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput, "{} -> {}", path, link);
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn links_through_a_symlink_leaving_the_root_are_refused() {
+        let root = TempDir::new().unwrap();
+        std::fs::create_dir(root.path().join("x")).unwrap();
+        std::os::unix::fs::symlink("..", root.path().join("x/l")).unwrap();
+        std::os::unix::fs::symlink("missing", root.path().join("dangling")).unwrap();
+
+        for (path, link) in [("x/m", "l/../.."), ("m", "dangling/f")] {
+            let err = decode_link(root.path(), Path::new(path), link.as_bytes())
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput, "{} -> {}", path, link);
+        }
+        let decoded = decode_link(root.path(), Path::new("x/m"), b"l/f")
+            .await
+            .unwrap();
+        assert_eq!(decoded, Path::new("l/f"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn requests_through_a_symlink_are_refused() {
+        let root = TempDir::new().unwrap();
+        let outside = TempDir::new().unwrap();
+        std::os::unix::fs::symlink(outside.path(), root.path().join("l")).unwrap();
+        let mut session = session(&root);
+
+        let request = Request::CreateDir {
+            path: b"l/dir".to_vec(),
+        };
+        let err = session.handle_request(request).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        assert!(!outside.path().join("dir").exists());
+
+        // The symlink itself is still a path of the root
+        let request = Request::RemoveFile {
+            path: b"l".to_vec(),
+        };
+        session.handle_request(request).await.unwrap();
+        assert!(!root.path().join("l").exists());
+    }
+}
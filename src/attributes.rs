@@ -0,0 +1,179 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use filetime::FileTime;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Preserve;
+
+/// Attributes of a source path given to the target path along with its content, the ones which
+/// aren't preserved are left unset
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Attributes {
+    pub modified: Option<SystemTime>,
+    pub accessed: Option<SystemTime>,
+    /// Unix permission bits
+    pub mode: Option<u32>,
+    /// Unix user and group ids, only applied when running as root
+    pub owner: Option<(u32, u32)>,
+    /// Extended attributes, by name
+    pub xattrs: Vec<(String, Vec<u8>)>,
+}
+
+impl Attributes {
+    /// The attributes of `path` chosen by `preserve`
+    pub async fn read(path: &Path, preserve: &[Preserve]) -> io::Result<Self> {
+        let path = path.to_path_buf();
+        let preserve = preserve.to_vec();
+        tokio::task::spawn_blocking(move || Self::read_blocking(&path, &preserve))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    /// Give the attributes to `path`, the times last as the other changes may update them
+    pub async fn apply(&self, path: PathBuf) -> io::Result<()> {
+        let attributes = self.clone();
+        tokio::task::spawn_blocking(move || attributes.apply_blocking(&path))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    fn read_blocking(path: &Path, preserve: &[Preserve]) -> io::Result<Self> {
+        let preserves =
+            |attribute| preserve.contains(&attribute) || preserve.contains(&Preserve::All);
+        let metadata = std::fs::metadata(path)?;
+        let mut attributes = Self::default();
+
+        if preserves(Preserve::Times) {
+            attributes.modified = metadata.modified().ok();
+            attributes.accessed = metadata.accessed().ok();
+        }
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            if preserves(Preserve::Perms) {
+                attributes.mode = Some(metadata.mode() & 0o7777);
+            }
+            if preserves(Preserve::Owner) {
+                attributes.owner = Some((metadata.uid(), metadata.gid()));
+            }
+            if preserves(Preserve::Xattrs) {
+                for name in xattr::list(path)? {
+                    // Names which aren't valid UTF-8 can't be sent to remote targets
+                    let Some(name) = name.to_str() else {
+                        continue;
+                    };
+                    if let Some(value) = xattr::get(path, name)? {
+                        attributes.xattrs.push((name.to_string(), value));
+                    }
+                }
+            }
+        }
+
+        Ok(attributes)
+    }
+
+    fn apply_blocking(&self, path: &Path) -> io::Result<()> {
+        #[cfg(unix)]
+        {
+            use std::fs::Permissions;
+            use std::os::unix::fs::PermissionsExt;
+
+            // Only root can give a path away, it also clears the setuid bits so it comes first
+            if let Some((uid, gid)) = self.owner {
+                // SAFETY: geteuid has no preconditions and can't fail
+                if unsafe { libc::geteuid() } == 0 {
+                    std::os::unix::fs::chown(path, Some(uid), Some(gid))?;
+                }
+            }
+            if let Some(mode) = self.mode {
+                std::fs::set_permissions(path, Permissions::from_mode(mode))?;
+            }
+            for (name, value) in &self.xattrs {
+                xattr::set(path, name, value)?;
+            }
+        }
+
+        if let Some(modified) = self.modified {
+            let modified = FileTime::from_system_time(modified);
+            let accessed = self.accessed.map_or(modified, FileTime::from_system_time);
+            filetime::set_file_times(path, accessed, modified)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::config::SyncConfig;
+    use crate::pair::SyncPair;
+    use crate::scan::Scanner;
+    use crate::target::LocalTarget;
+    use crate::utils::FileStore;
+
+    fn set_modified(path: &Path, modified: SystemTime) {
+        filetime::set_file_mtime(path, FileTime::from_system_time(modified)).unwrap();
+    }
+
+    #[tokio::test]
+    async fn only_the_preserved_attributes_are_read() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("f");
+        std::fs::write(&path, b"f").unwrap();
+
+        let attributes = Attributes::read(&path, &[]).await.unwrap();
+        assert!(attributes.modified.is_none() && attributes.mode.is_none());
+        let attributes = Attributes::read(&path, &[Preserve::Times]).await.unwrap();
+        assert!(attributes.modified.is_some() && attributes.mode.is_none());
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn times_and_permissions_are_applied() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = TempDir::new().unwrap();
+        let (src_path, dest_path) = (dir.path().join("src"), dir.path().join("dest"));
+        std::fs::write(&src_path, b"f").unwrap();
+        std::fs::write(&dest_path, b"f").unwrap();
+        let modified = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        set_modified(&src_path, modified);
+        std::fs::set_permissions(&src_path, std::fs::Permissions::from_mode(0o640)).unwrap();
+
+        let preserve = [Preserve::Times, Preserve::Perms];
+        let attributes = Attributes::read(&src_path, &preserve).await.unwrap();
+        attributes.apply(dest_path.clone()).await.unwrap();
+        let dest_metadata = std::fs::metadata(&dest_path).unwrap();
+        assert_eq!(dest_metadata.modified().unwrap(), modified);
+        assert_eq!(dest_metadata.permissions().mode() & 0o7777, 0o640);
+    }
+
+    #[tokio::test]
+    async fn synced_paths_keep_their_modification_time() {
+        let (source, target) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        std::fs::create_dir(source.path().join("dir")).unwrap();
+        std::fs::write(source.path().join("dir/f"), b"f").unwrap();
+        let modified = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        set_modified(&source.path().join("dir/f"), modified);
+        set_modified(&source.path().join("dir"), modified);
+        let mut config = SyncConfig::new(source.path().to_path_buf(), target.path().to_path_buf());
+        config.preserve = vec![Preserve::Times];
+        let local = LocalTarget::new(target.path().to_path_buf());
+        let pair = SyncPair::new(config, None, Arc::new(local)).unwrap();
+
+        Scanner::reconcile(&pair, &FileStore::default()).await;
+        for path in ["dir/f", "dir"] {
+            let metadata = std::fs::metadata(target.path().join(path)).unwrap();
+            assert_eq!(metadata.modified().unwrap(), modified, "{}", path);
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use serde::Deserialize;
+
+use crate::filter::FilterConfig;
+use crate::target::TCP_SCHEME;
+use crate::utils::Utils;
+
+pub const CONFIG_FILE_NAME: &str = "oxsync.toml";
+
+/// Content of an `oxsync.toml` file
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    #[serde(default)]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// Named set of options, with the same meaning as the CLI args
+#[derive(Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Profile {
+    pub source: Option<PathBuf>,
+    pub target: Option<PathBuf>,
+    pub agent_command: Option<String>,
+    pub exclude: Vec<String>,
+    pub include: Vec<String>,
+    pub gitignore: bool,
+    pub no_temporary_editor_files: bool,
+    pub no_creation_events: bool,
+    pub ide_mode: bool,
+    pub statistics: bool,
+    pub no_initial_sync: bool,
+    pub delete: bool,
+    pub delta: bool,
+    pub fsync: bool,
+    pub state_dir: Option<PathBuf>,
+    /// In milliseconds
+    pub settle: Option<u64>,
+    pub concurrency: Option<usize>,
+    pub two_way: bool,
+    pub on_target_change: Option<TargetChangePolicy>,
+    pub preserve: Vec<Preserve>,
+    pub symlinks: Option<SymlinkPolicy>,
+    pub hard_links: bool,
+    pub trash: bool,
+    /// In days
+    pub trash_retention: Option<u64>,
+}
+
+/// What to do with a target file changed since oxsync last wrote it, by someone editing the target
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum TargetChangePolicy {
+    /// Copy the source file over it, with a warning
+    #[default]
+    Overwrite,
+    /// Keep it and warn, the source file isn't copied
+    Skip,
+    /// Keep it next to the file as `<NAME>.backup-<TIMESTAMP>`, then copy the source file
+    Backup,
+}
+
+/// Attributes of the source paths given to the target paths after they are copied or created
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Preserve {
+    /// Modification and access times
+    Times,
+    /// Unix permission modes
+    Perms,
+    /// Unix owner and group, when running as root
+    Owner,
+    /// Extended attributes
+    Xattrs,
+    /// All of the above
+    All,
+}
+
+/// How the symlinks of the source dir are synced
+#[derive(Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SymlinkPolicy {
+    /// Sync the file or dir they point to in their place, the links looping back are skipped
+    #[default]
+    Follow,
+    /// Recreate them on the target as they are
+    Preserve,
+    /// Recreate them on the target, the absolute ones pointing into the source dir made relative
+    /// so they point into the target dir
+    Relative,
+    /// Leave them out
+    Skip,
+}
+
+/// Settings of a sync, once the CLI args and the profile are merged
+#[derive(Debug, Clone)]
+pub struct SyncConfig {
+    pub source_dir: PathBuf,
+    pub target_dir: PathBuf,
+    pub filter: FilterConfig,
+    pub no_creation_events: bool,
+    pub statistics: bool,
+    pub no_initial_sync: bool,
+    pub delete: bool,
+    /// Only write the changed blocks of the files already on the target
+    pub delta: bool,
+    /// Flush the files written to a local target to the disk before moving them in place
+    pub fsync: bool,
+    /// Dir where the index of the synced files is saved between runs
+    pub state_dir: Option<PathBuf>,
+    /// How long a path must have no change before its changes are synced, merged into one.
+    /// Zero syncs each change right away
+    pub settle: Duration,
+    /// How many changes of independent paths are synced at the same time
+    pub concurrency: usize,
+    /// Also sync the changes of the target dir to the source dir
+    pub two_way: bool,
+    pub on_target_change: TargetChangePolicy,
+    /// Attributes of the source paths given to the target paths, none when empty
+    pub preserve: Vec<Preserve>,
+    pub symlinks: SymlinkPolicy,
+    /// Recreate the hard links between the source files on the target instead of copying each one
+    pub hard_links: bool,
+    /// Move the deleted paths to the trash of the target instead of removing them
+    pub trash: bool,
+    /// How many days the deleted paths are kept in the trash
+    pub trash_retention: u64,
+    /// Log the events received from the watcher
+    pub trace: bool,
+}
+
+impl ConfigFile {
+    /// Relative `source`, `target` and `state-dir` paths are resolved from the dir of the config
+    /// file, except `tcp://` targets
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let content = fs::read_to_string(path).map_err(|err| {
+            format!(
+                "failed to read config file '{}', error: {}",
+                Utils::fmt_path(path),
+                err
+            )
+        })?;
+        let mut config_file: ConfigFile = toml::from_str(&content).map_err(|err| {
+            format!(
+                "invalid config file '{}', error: {}",
+                Utils::fmt_path(path),
+                err
+            )
+        })?;
+
+        let config_dir = path.parent().unwrap_or(Path::new(""));
+        for profile in config_file.profiles.values_mut() {
+            profile.source = profile.source.take().map(|source| config_dir.join(source));
+            profile.state_dir = profile.state_dir.take().map(|dir| config_dir.join(dir));
+            profile.target = profile.target.take().map(|target| {
+                if target
+                    .to_str()
+                    .is_some_and(|url| url.starts_with(TCP_SCHEME))
+                {
+                    target
+                } else {
+                    config_dir.join(target)
+                }
+            });
+        }
+
+        Ok(config_file)
+    }
+
+    /// Without a name, the profile is the only one of the file or the one named `default`
+    pub fn profile(&self, name: Option<&str>) -> Result<&Profile, String> {
+        let profile = match name {
+            Some(name) => self.profiles.get(name),
+            None if self.profiles.len() == 1 => self.profiles.values().next(),
+            None => self.profiles.get("default"),
+        };
+
+        profile.ok_or_else(|| {
+            let names = self.profiles.keys().cloned().collect::<Vec<_>>();
+            match name {
+                Some(name) => format!(
+                    "profile '{}' not found, available profiles : [{}]",
+                    name,
+                    names.join(", ")
+                ),
+                None => format!(
+                    "a profile must be selected with `--profile`, available profiles : [{}]",
+                    names.join(", ")
+                ),
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+impl SyncConfig {
+    /// The defaults of the CLI
+    pub(crate) fn new(source_dir: PathBuf, target_dir: PathBuf) -> Self {
+        Self {
+            source_dir,
+            target_dir,
+            filter: FilterConfig::default(),
+            no_creation_events: false,
+            statistics: false,
+            no_initial_sync: false,
+            delete: false,
+            delta: false,
+            fsync: false,
+            state_dir: None,
+            settle: Duration::ZERO,
+            concurrency: 4,
+            two_way: false,
+            on_target_change: TargetChangePolicy::default(),
+            preserve: Vec::new(),
+            symlinks: SymlinkPolicy::default(),
+            hard_links: false,
+            trash: false,
+            trash_retention: 30,
+            trace: false,
+        }
+    }
+}
@@ -0,0 +1,220 @@
+use std::io;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use blake3::Hash;
+use tokio::fs::File;
+use tokio::io::AsyncReadExt;
+
+use crate::pair::SyncPair;
+use crate::utils::PathType;
+
+/// Size of the blocks compared in delta mode, smaller files are always copied whole
+pub(crate) const BLOCK_SIZE: usize = 128 * 1024;
+
+/// Hashes of the fixed-size blocks of a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Blocks {
+    pub len: u64,
+    pub hashes: Vec<Hash>,
+}
+
+pub(crate) struct Delta;
+
+impl Delta {
+    /// The hash of the whole file along with the hashes of its blocks, in a single read
+    pub async fn hash_blocks(path: &Path, block_size: usize) -> io::Result<(Hash, Blocks)> {
+        let mut file = File::open(path).await?;
+        let mut hasher = blake3::Hasher::new();
+        let mut blocks = Blocks {
+            len: 0,
+            hashes: Vec::new(),
+        };
+
+        let mut buffer = vec![0; block_size];
+        loop {
+            let read = Self::read_block(&mut file, &mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+
+            hasher.update(&buffer[..read]);
+            blocks.hashes.push(blake3::hash(&buffer[..read]));
+            blocks.len += read as u64;
+        }
+
+        Ok((hasher.finalize(), blocks))
+    }
+
+    /// Fill `buffer` unless the end of the file is reached
+    pub async fn read_block(file: &mut File, buffer: &mut [u8]) -> io::Result<usize> {
+        let mut filled = 0;
+        while filled < buffer.len() {
+            let read = file.read(&mut buffer[filled..]).await?;
+            if read == 0 {
+                break;
+            }
+            filled += read;
+        }
+
+        Ok(filled)
+    }
+
+    /// Indexes of the blocks of `src` which differ from the ones of `dest`
+    pub fn changed_blocks(src: &Blocks, dest: &Blocks) -> Vec<usize> {
+        (0..src.hashes.len())
+            .filter(|&index| dest.hashes.get(index) != Some(&src.hashes[index]))
+            .collect()
+    }
+
+    /// Write only the changed blocks of the file to the target. The blocks of the target come
+    /// from `known_blocks` when its length still matches, otherwise they are read from the
+    /// target. Returns the number of blocks sent, `None` when the file must be copied whole
+    pub async fn patch(
+        pair: &SyncPair,
+        src_path: &Path,
+        relative_path: &Path,
+        src_blocks: &Blocks,
+        known_blocks: Option<&Blocks>,
+    ) -> io::Result<Option<usize>> {
+        let dest_metadata = match pair.target.stat(relative_path).await {
+            Ok(dest_metadata) if dest_metadata.path_type == PathType::File => dest_metadata,
+            _ => return Ok(None),
+        };
+
+        let dest_blocks = match known_blocks {
+            Some(known_blocks) if known_blocks.len == dest_metadata.len => known_blocks.clone(),
+            _ => match pair.target.block_hashes(relative_path, BLOCK_SIZE).await {
+                Ok(hashes) => Blocks {
+                    len: dest_metadata.len,
+                    hashes,
+                },
+                Err(err) if err.kind() == ErrorKind::Unsupported => return Ok(None),
+                Err(err) => return Err(err),
+            },
+        };
+
+        let changed = Self::changed_blocks(src_blocks, &dest_blocks);
+        let result = pair
+            .target
+            .write_blocks(
+                relative_path,
+                src_path,
+                &changed,
+                BLOCK_SIZE,
+                src_blocks.len,
+            )
+            .await;
+        match result {
+            Ok(()) => Ok(Some(changed.len())),
+            Err(err) if err.kind() == ErrorKind::Unsupported => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::config::SyncConfig;
+    use crate::scan::Scanner;
+    use crate::target::{MemoryTarget, SyncTarget};
+    use crate::utils::FileStore;
+
+    fn memory_pair(source: &TempDir) -> (SyncPair, Arc<MemoryTarget>) {
+        let mut config = SyncConfig::new(source.path().to_path_buf(), PathBuf::new());
+        config.delta = true;
+        let target = Arc::new(MemoryTarget::new());
+        let pair = SyncPair::new(config, None, target.clone()).unwrap();
+        (pair, target)
+    }
+
+    #[tokio::test]
+    async fn blocks_are_hashed_with_the_whole_file() {
+        let source = TempDir::new().unwrap();
+        let path = source.path().join("f");
+        std::fs::write(&path, b"aaaabbbbcc").unwrap();
+
+        let (hash, blocks) = Delta::hash_blocks(&path, 4).await.unwrap();
+        assert_eq!(hash, blake3::hash(b"aaaabbbbcc"));
+        assert_eq!(blocks.len, 10);
+        assert_eq!(
+            blocks.hashes,
+            [b"aaaa".as_slice(), b"bbbb", b"cc"].map(blake3::hash)
+        );
+    }
+
+    #[test]
+    fn changed_blocks_include_the_new_ones() {
+        let blocks = |contents: &[&[u8]]| Blocks {
+            len: 0,
+            hashes: contents
+                .iter()
+                .map(|content| blake3::hash(content))
+                .collect(),
+        };
+        let src = blocks(&[b"aaaa", b"XXXX", b"cccc", b"dd"]);
+        let dest = blocks(&[b"aaaa", b"bbbb", b"cccc"]);
+
+        assert_eq!(Delta::changed_blocks(&src, &dest), [1, 3]);
+        assert!(Delta::changed_blocks(&dest, &dest).is_empty());
+    }
+
+    #[tokio::test]
+    async fn only_the_changed_blocks_are_written() {
+        let source = TempDir::new().unwrap();
+        let (pair, target) = memory_pair(&source);
+        let mut content = vec![b'a'; BLOCK_SIZE * 3];
+        let src_path = source.path().join("f");
+        std::fs::write(&src_path, &content).unwrap();
+        target.write_file(Path::new("f"), &src_path).await.unwrap();
+
+        content[BLOCK_SIZE + 1] = b'X';
+        content.extend_from_slice(b"tail");
+        std::fs::write(&src_path, &content).unwrap();
+        let (_, src_blocks) = Delta::hash_blocks(&src_path, BLOCK_SIZE).await.unwrap();
+
+        let sent = Delta::patch(&pair, &src_path, Path::new("f"), &src_blocks, None)
+            .await
+            .unwrap();
+        assert_eq!(sent, Some(2));
+        assert_eq!(target.read(Path::new("f")), Some(content));
+    }
+
+    #[tokio::test]
+    async fn missing_target_files_are_copied_whole() {
+        let source = TempDir::new().unwrap();
+        let (pair, _) = memory_pair(&source);
+        let src_path = source.path().join("f");
+        std::fs::write(&src_path, b"content").unwrap();
+        let (_, src_blocks) = Delta::hash_blocks(&src_path, BLOCK_SIZE).await.unwrap();
+
+        let sent = Delta::patch(&pair, &src_path, Path::new("f"), &src_blocks, None)
+            .await
+            .unwrap();
+        assert_eq!(sent, None);
+    }
+
+    #[tokio::test]
+    async fn delta_syncs_keep_the_target_matching_the_source() {
+        let source = TempDir::new().unwrap();
+        let (pair, target) = memory_pair(&source);
+        let file_store = FileStore::default();
+        let src_path = source.path().join("f");
+        let mut content = vec![b'a'; BLOCK_SIZE * 2];
+        std::fs::write(&src_path, &content).unwrap();
+        Scanner::reconcile(&pair, &file_store).await;
+
+        content[0] = b'X';
+        content.truncate(BLOCK_SIZE + 5);
+        std::fs::write(&src_path, &content).unwrap();
+        let summary = Scanner::reconcile(&pair, &file_store).await;
+        assert_eq!((summary.copied, summary.failed), (1, 0));
+        assert_eq!(target.read(Path::new("f")), Some(content));
+    }
+}
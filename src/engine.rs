@@ -0,0 +1,442 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use notify::{Config, Event, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::sync::mpsc::unbounded_channel;
+use tokio::sync::{watch, Mutex};
+use tokio::time::Instant;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio_stream::StreamExt;
+
+use crate::config::{SymlinkPolicy, SyncConfig};
+use crate::error::Error;
+use crate::index::Index;
+use crate::pair::SyncPair;
+use crate::scan::{ScanSummary, Scanner};
+use crate::settle::Settler;
+use crate::target::{LocalTarget, SyncTarget, STATE_DIR_NAME};
+use crate::trash::Trash;
+use crate::two_way::TwoWay;
+use crate::utils::Utils;
+use crate::workers::{EventHandler, WorkerPool};
+use crate::{err, info, warn};
+
+/// Quiet time after a change before the index is saved
+const INDEX_SAVE_DELAY: Duration = Duration::from_secs(2);
+/// How often the trash is purged while watching, it is also purged at startup
+const TRASH_PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Syncs a source dir to a target dir, either once or by watching the changes of the source dir
+pub struct SyncEngine {
+    pair: Arc<SyncPair>,
+    handler: EventHandler,
+    state: Mutex<EngineState>,
+    shutdown_tx: watch::Sender<bool>,
+    /// Held by `run` until it returns
+    running: Mutex<()>,
+}
+
+struct EngineState {
+    /// Where the file store is saved, when the config has a state dir
+    index: Option<Index>,
+    /// The index is loaded and the temp files of an interrupted run are removed only once
+    started: bool,
+}
+
+impl SyncEngine {
+    /// Check the dirs of the config and load its filter. The label prefixes the log lines, to
+    /// tell several engines apart
+    pub fn new(mut config: SyncConfig, label: Option<String>) -> Result<Self, Error> {
+        config.source_dir = Self::resolve_dir("source", &config.source_dir)?;
+        config.target_dir = Self::resolve_dir("target", &config.target_dir)?;
+        // Both dirs of a two-way sync are read the same way
+        let target = LocalTarget::new(config.target_dir.clone())
+            .with_fsync(config.fsync)
+            .with_follow_symlinks(config.two_way && config.symlinks == SymlinkPolicy::Follow);
+        let state_dir = config.target_dir.join(STATE_DIR_NAME);
+        config.state_dir.get_or_insert(state_dir);
+        let target = Arc::new(target);
+
+        Self::build(config, label, target)
+    }
+
+    /// Like `new`, but the changes are written to `target` instead of the target dir of the
+    /// config, which is left unchecked. The index is only saved when the config has a state dir.
+    /// Two-way syncs need the target dir of the config
+    pub fn with_target(
+        mut config: SyncConfig,
+        label: Option<String>,
+        target: Arc<dyn SyncTarget>,
+    ) -> Result<Self, Error> {
+        if config.two_way {
+            return Err(Error::TwoWayRemoteTarget);
+        }
+        config.source_dir = Self::resolve_dir("source", &config.source_dir)?;
+
+        Self::build(config, label, target)
+    }
+
+    fn build(
+        mut config: SyncConfig,
+        label: Option<String>,
+        target: Arc<dyn SyncTarget>,
+    ) -> Result<Self, Error> {
+        for pattern in &mut config.filter.exclude_patterns {
+            // Absolute paths inside the source dir are anchored to its root
+            let v_path = Utils::path_to_verbatim(Path::new(pattern));
+            if let Ok(relative_path) = v_path.strip_prefix(&config.source_dir) {
+                *pattern = format!("/{}", relative_path.to_str().unwrap());
+            }
+        }
+
+        let (shutdown_tx, _) = watch::channel(false);
+        let index = config
+            .state_dir
+            .as_ref()
+            .map(|state_dir| Index::new(state_dir, &config.source_dir));
+
+        let pair = Arc::new(SyncPair::new(config, label, target)?);
+        Ok(Self {
+            handler: EventHandler::new(pair.clone()),
+            pair,
+            state: Mutex::new(EngineState {
+                index,
+                started: false,
+            }),
+            shutdown_tx,
+            running: Mutex::new(()),
+        })
+    }
+
+    pub fn config(&self) -> &SyncConfig {
+        &self.pair.config
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.pair.label.as_deref()
+    }
+
+    /// Make the target dir match the source dir once
+    pub async fn sync_once(&self) -> ScanSummary {
+        let mut state = self.state.lock().await;
+        self.start(&mut state).await;
+        let summary = if self.pair.config.two_way {
+            TwoWay::reconcile(&self.pair, &self.handler.file_store).await
+        } else {
+            Scanner::reconcile(&self.pair, &self.handler.file_store).await
+        };
+        self.save_index(&state).await;
+
+        summary
+    }
+
+    /// Run the initial sync unless disabled, then sync the changes of the source dir until
+    /// `shutdown` is called
+    pub async fn run(&self) -> Result<(), Error> {
+        let _running = self.running.lock().await;
+        let mut shutdown_rx = self.shutdown_tx.subscribe();
+        if *shutdown_rx.borrow_and_update() {
+            return Ok(());
+        }
+
+        let (tx, rx) = unbounded_channel();
+        // Automatically select the best implementation for your platform.
+        // You can also access each implementation directly e.g. INotifyWatcher.
+        let mut watcher = RecommendedWatcher::new(
+            move |res| {
+                // The receiver is dropped once the engine is shut down
+                let _ = tx.send(res);
+            },
+            Config::default(),
+        )?;
+        let mut rx = UnboundedReceiverStream::new(rx);
+
+        // Add a path to be watched. All files and directories at that path and
+        // below will be monitored for changes.
+        watcher.watch(&self.pair.config.source_dir, RecursiveMode::Recursive)?;
+        if self.pair.config.two_way {
+            watcher.watch(&self.pair.config.target_dir, RecursiveMode::Recursive)?;
+        }
+
+        if !self.pair.config.no_initial_sync {
+            self.sync_once().await;
+        } else {
+            self.start(&mut *self.state.lock().await).await;
+        }
+
+        info!(
+            "{}Ready - Waiting for changes on '{}'",
+            self.pair.log_prefix(),
+            Utils::fmt_path(&self.pair.config.source_dir)
+        );
+        let settle = self.pair.config.settle;
+        let mut settler = Settler::new(settle);
+        let mut workers = WorkerPool::new(self.handler.clone(), self.pair.config.concurrency);
+        // The index is saved once the changes stop for a while, and when the engine stops
+        let mut save_index_at = None;
+        let mut purge_trash_at = Instant::now() + TRASH_PURGE_INTERVAL;
+        loop {
+            tokio::select! {
+                res = rx.next() => match res {
+                    Some(Ok(event)) => {
+                        let Some(event) = self.source_event(event) else {
+                            continue;
+                        };
+
+                        if settle.is_zero() {
+                            workers.push(event, Instant::now());
+                        } else {
+                            settler.push(event, Instant::now());
+                        }
+                    }
+                    Some(Err(e)) => err!("{}watch error: {:?}", self.pair.log_prefix(), e),
+                    None => break,
+                },
+                _ = tokio::time::sleep_until(settler.next_due().unwrap_or_else(Instant::now)),
+                    if settler.next_due().is_some() =>
+                {
+                    for event in settler.take_due(Instant::now()) {
+                        workers.push(event, Instant::now());
+                    }
+                }
+                _ = workers.join_next(), if !workers.is_idle() => {
+                    save_index_at = Some(Instant::now() + INDEX_SAVE_DELAY);
+                }
+                _ = tokio::time::sleep_until(save_index_at.unwrap_or_else(Instant::now)),
+                    if save_index_at.is_some() =>
+                {
+                    save_index_at = None;
+                    self.save_index(&*self.state.lock().await).await;
+                }
+                _ = tokio::time::sleep_until(purge_trash_at), if self.pair.config.trash => {
+                    purge_trash_at = Instant::now() + TRASH_PURGE_INTERVAL;
+                    for dir in self.dirs() {
+                        Trash::purge(&self.pair, dir.as_ref()).await;
+                    }
+                }
+                _ = shutdown_rx.changed() => break,
+            }
+        }
+        // The changes already reported by the watcher, still settling or waiting for a worker are
+        // synced before stopping
+        while let Ok(res) = rx.as_mut().try_recv() {
+            if let Some(event) = res.ok().and_then(|event| self.source_event(event)) {
+                settler.push(event, Instant::now());
+            }
+        }
+        let events = settler.take_all();
+        for event in events.iter().cloned() {
+            workers.push(event, Instant::now());
+        }
+        workers.join_all().await;
+        if save_index_at.is_some() || !events.is_empty() {
+            self.save_index(&*self.state.lock().await).await;
+        }
+
+        Ok(())
+    }
+
+    /// Stop `run`, waiting for the events being handled to be done
+    pub async fn shutdown(&self) {
+        self.shutdown_tx.send_replace(true);
+        let _stopped = self.running.lock().await;
+    }
+
+    /// Sync the paths of a change of the source dir to the target dir, right away even with a
+    /// settle window, and without waiting for the changes being synced by `run`
+    pub async fn handle_event(&self, event: Event) {
+        self.handler.handle_event(event, Instant::now()).await
+    }
+
+    /// The event with the paths of the source dir, `None` when it only concerns the state dir
+    fn source_event(&self, mut event: Event) -> Option<Event> {
+        if self.pair.config.two_way {
+            self.to_source_paths(&mut event);
+        }
+        // The index itself may be saved in the state dir of the source dir
+        let state_dir = self.pair.config.source_dir.join(STATE_DIR_NAME);
+        let in_state_dir = event
+            .paths
+            .iter()
+            .all(|path| Utils::path_to_verbatim(path).starts_with(&state_dir));
+
+        (!in_state_dir).then_some(event)
+    }
+
+    /// The changes of the target dir of a two-way sync are handled as the ones of the same paths
+    /// of the source dir
+    fn to_source_paths(&self, event: &mut Event) {
+        for path in &mut event.paths {
+            let v_path = Utils::path_to_verbatim(path);
+            if let Ok(relative_path) = v_path.strip_prefix(&self.pair.config.target_dir) {
+                *path = self.pair.config.source_dir.join(relative_path);
+            }
+        }
+    }
+
+    async fn start(&self, state: &mut EngineState) {
+        if state.started {
+            return;
+        }
+        state.started = true;
+
+        if let Some(index) = &state.index {
+            match index.load(&self.handler.file_store).await {
+                Ok(()) => {}
+                Err(err) => warn!(
+                    "{}failed to load the index '{}', starting from an empty one, error: {}",
+                    self.pair.log_prefix(),
+                    Utils::fmt_path(index.path()),
+                    err
+                ),
+            }
+        }
+
+        for dir in self.dirs() {
+            match dir.remove_temp_files().await {
+                Ok(0) => {}
+                Ok(removed) => info!(
+                    "{}{} temp files of an interrupted run removed",
+                    self.pair.log_prefix(),
+                    removed
+                ),
+                Err(err) => warn!(
+                    "{}failed to remove the temp files of an interrupted run, error: {}",
+                    self.pair.log_prefix(),
+                    err
+                ),
+            }
+            Trash::purge(&self.pair, dir.as_ref()).await;
+        }
+    }
+
+    /// The dirs written by the sync, each with its own temp files and trash
+    fn dirs(&self) -> Vec<Arc<dyn SyncTarget>> {
+        let mut dirs: Vec<Arc<dyn SyncTarget>> = vec![self.pair.target.clone()];
+        // The files pulled from the target are written to the source dir the same way
+        if self.pair.config.two_way {
+            dirs.push(Arc::new(LocalTarget::new(
+                self.pair.config.source_dir.clone(),
+            )));
+        }
+        dirs
+    }
+
+    async fn save_index(&self, state: &EngineState) {
+        let Some(index) = &state.index else {
+            return;
+        };
+
+        if let Err(err) = index.save(&self.handler.file_store).await {
+            err!(
+                "{}failed to save the index '{}', error: {}",
+                self.pair.log_prefix(),
+                Utils::fmt_path(index.path()),
+                err
+            );
+        }
+    }
+
+    fn resolve_dir(name: &'static str, path: &Path) -> Result<PathBuf, Error> {
+        if !path.exists() {
+            return Err(Error::MissingDir(name, path.to_path_buf()));
+        }
+
+        std::fs::canonicalize(path).map_err(|_| Error::InvalidDir(name, path.to_path_buf()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::target::MemoryTarget;
+
+    fn memory_engine(config: SyncConfig) -> (SyncEngine, Arc<MemoryTarget>) {
+        let target = Arc::new(MemoryTarget::new());
+        let engine = SyncEngine::with_target(config, None, target.clone()).unwrap();
+        (engine, target)
+    }
+
+    #[tokio::test]
+    async fn sync_once_makes_the_target_match_the_source() {
+        let source = TempDir::new().unwrap();
+        fs::create_dir(source.path().join("dir")).unwrap();
+        fs::write(source.path().join("dir/f"), b"f").unwrap();
+        fs::write(source.path().join("g"), b"g").unwrap();
+        let mut config = SyncConfig::new(source.path().to_path_buf(), PathBuf::new());
+        config.delete = true;
+        let (engine, target) = memory_engine(config);
+        let src = tempfile::NamedTempFile::new().unwrap();
+        target
+            .write_file(Path::new("old"), src.path())
+            .await
+            .unwrap();
+
+        let summary = engine.sync_once().await;
+        assert_eq!(
+            (
+                summary.copied,
+                summary.created,
+                summary.deleted,
+                summary.failed
+            ),
+            (2, 1, 1, 0)
+        );
+        assert_eq!(target.read(Path::new("dir/f")), Some(b"f".to_vec()));
+        assert_eq!(target.read(Path::new("g")), Some(b"g".to_vec()));
+        assert!(target.stat(Path::new("old")).await.is_err());
+
+        let summary = engine.sync_once().await;
+        assert_eq!(
+            (summary.copied, summary.created, summary.deleted),
+            (0, 0, 0)
+        );
+        assert_eq!(summary.identical, 2);
+    }
+
+    #[tokio::test]
+    async fn extraneous_paths_are_kept_without_delete() {
+        let source = TempDir::new().unwrap();
+        let config = SyncConfig::new(source.path().to_path_buf(), PathBuf::new());
+        let (engine, target) = memory_engine(config);
+        target.create_dir(Path::new("kept")).await.unwrap();
+
+        let summary = engine.sync_once().await;
+        assert_eq!((summary.deleted, summary.failed), (0, 0));
+        assert!(target.stat(Path::new("kept")).await.is_ok());
+    }
+
+    #[test]
+    fn missing_source_dirs_are_refused() {
+        let source = TempDir::new().unwrap();
+        let config = SyncConfig::new(source.path().join("missing"), PathBuf::new());
+        let target = Arc::new(MemoryTarget::new());
+
+        let result = SyncEngine::with_target(config, None, target);
+        assert!(matches!(result, Err(Error::MissingDir("source", _))));
+    }
+
+    #[tokio::test]
+    async fn the_index_is_loaded_by_the_next_run() {
+        let source = TempDir::new().unwrap();
+        let state_dir = TempDir::new().unwrap();
+        fs::write(source.path().join("f"), b"f").unwrap();
+        let mut config = SyncConfig::new(source.path().to_path_buf(), PathBuf::new());
+        config.state_dir = Some(state_dir.path().to_path_buf());
+        let (engine, _) = memory_engine(config.clone());
+        engine.sync_once().await;
+
+        let (engine, _) = memory_engine(config);
+        engine.start(&mut *engine.state.lock().await).await;
+        let f = engine
+            .handler
+            .file_store
+            .get(&engine.config().source_dir.join("f"));
+        assert_eq!(f.unwrap().hash, Some(blake3::hash(b"f")));
+    }
+}
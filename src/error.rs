@@ -0,0 +1,54 @@
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+
+use crate::utils::Utils;
+
+#[derive(Debug)]
+pub enum Error {
+    /// The source or target dir doesn't exist
+    MissingDir(&'static str, PathBuf),
+    /// The source or target dir can't be canonicalized
+    InvalidDir(&'static str, PathBuf),
+    Filter(ignore::Error),
+    /// Two-way syncs watch the target dir, it must be local
+    TwoWayRemoteTarget,
+    Watch(notify::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::MissingDir(name, path) => {
+                write!(
+                    f,
+                    "{} dir : '{}' does not exists",
+                    name,
+                    Utils::fmt_path(path)
+                )
+            }
+            Error::InvalidDir(name, path) => write!(
+                f,
+                "impossible to convert {} dir '{}' to a valid path",
+                name,
+                Utils::fmt_path(path)
+            ),
+            Error::Filter(err) => write!(f, "invalid exclude or include pattern : {}", err),
+            Error::TwoWayRemoteTarget => write!(f, "two-way sync needs a local target dir"),
+            Error::Watch(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<ignore::Error> for Error {
+    fn from(err: ignore::Error) -> Self {
+        Error::Filter(err)
+    }
+}
+
+impl From<notify::Error> for Error {
+    fn from(err: notify::Error) -> Self {
+        Error::Watch(err)
+    }
+}
@@ -1,189 +1,324 @@
+use std::fs::Metadata;
+use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
 use std::time::SystemTime;
 
-use blake3::{hash, Hash};
-use lru::LruCache;
-use notify::event::{ModifyKind, RenameMode};
+use blake3::Hash;
+use notify::event::{ModifyKind, RemoveKind, RenameMode};
 use notify::Event;
-use notify::EventKind::Modify;
+use notify::EventKind::{Modify, Remove};
 use tokio::fs;
-use tokio::fs::File;
 use tokio::time::Instant;
 
-use crate::utils::{PathType, Utils};
-use crate::{err, info, PathMetadata};
+use crate::attributes::Attributes;
+use crate::config::Preserve;
+use crate::delta::Blocks;
+use crate::pair::SyncPair;
+use crate::scan::Scanner;
+use crate::trash::Trash;
+use crate::utils::{FileStore, PathMetadata, PathType, Utils};
+use crate::{err, info};
 
 pub(crate) struct FileOperationsManager;
 
 impl FileOperationsManager {
-    pub async fn copy(
-        file_store: &mut LruCache<PathBuf, PathMetadata>,
-        emit_time: Instant,
-        event: Event,
-    ) {
+    pub async fn copy(pair: &SyncPair, file_store: &FileStore, emit_time: Instant, event: Event) {
         // "paths" length is always 1 on Windows
         for src_path in event.paths {
             let v_path = Utils::path_to_verbatim(&src_path);
+            let relative_path = pair.relative_path(&v_path);
+            let Some(path_type) = pair.source_path_type(&v_path).await else {
+                continue;
+            };
 
-            if is_in_excluded_paths(&v_path) {
+            if !pair.is_synced(relative_path, path_type == PathType::Dir) {
                 continue;
             }
 
-            let path_str = v_path
-                .strip_prefix(&Utils::args().source_dir)
-                .unwrap()
-                .to_str()
-                .unwrap();
+            let path_str = &Utils::fmt_path(relative_path);
+            let dirs = relative_path.parent().unwrap();
 
-            if Utils::args().no_temporary_editor_files && path_str.ends_with('~') {
+            if path_type == PathType::Symlink {
+                Self::copy_symlink(pair, file_store, &v_path, emit_time).await;
+                continue;
+            }
+            if path_type == PathType::Dir {
+                Self::create_dir(pair, file_store, v_path, emit_time).await;
                 continue;
             }
 
-            let relative_path = v_path.strip_prefix(&Utils::args().source_dir).unwrap();
-            let (dest_path, dirs) = Utils::get_destination_path_and_dirs(relative_path);
-
-            if let Some(path_metadata) = file_store.get(&v_path) {
+            let known = file_store
+                .get(&v_path)
+                .filter(|path_metadata| path_metadata.path_type == path_type);
+            if let Some(path_metadata) = known {
                 match path_metadata.path_type {
-                    PathType::Dir => {
-                        if !dest_path.is_dir()
-                            && Utils::create_dirs(&dest_path, path_str, &emit_time, false)
-                                .await
-                                .is_ok()
-                        {
-                            Self::write_in_file_store(file_store, v_path, PathType::Dir, None)
-                                .await;
-                        }
-                    }
+                    // Created above
+                    PathType::Dir => {}
                     PathType::File => {
-                        let current_hash = if let Ok(file_content) = fs::read(&v_path).await {
-                            Some(hash(&file_content))
-                        } else {
-                            None
-                        };
+                        // Taken before reading the content, a later change makes it outdated
+                        let src_metadata = fs::metadata(&v_path).await.ok();
+                        let (current_hash, src_blocks) = Utils::hash_source(pair, &v_path).await;
+                        let known_blocks = path_metadata.blocks.clone();
 
                         if current_hash.is_none() {
-                            Self::create_depends_dirs(dirs, path_str, file_store, &emit_time).await;
-
-                            if Utils::copy_file(&v_path, &dest_path, path_str, emit_time)
-                                .await
-                                .is_ok()
-                            {
-                                Self::write_in_file_store(file_store, v_path, PathType::File, None)
-                                    .await;
+                            Self::create_depends_dirs(pair, dirs, path_str, file_store, &emit_time)
+                                .await;
+
+                            let copied = Utils::copy_file(
+                                pair,
+                                &v_path,
+                                relative_path,
+                                path_str,
+                                emit_time,
+                                None,
+                                None,
+                            )
+                            .await;
+                            if let Ok(copied_hash) = copied {
+                                Self::write_in_file_store(
+                                    pair,
+                                    file_store,
+                                    v_path.clone(),
+                                    PathType::File,
+                                    copied_hash,
+                                    None,
+                                    src_metadata.as_ref(),
+                                )
+                                .await;
+                                Self::update_hard_links(pair, file_store, &v_path, emit_time).await;
                             }
                             continue;
                         }
 
                         let file_is_identical = current_hash == path_metadata.hash;
-                        let last_change_superior_to_one_sec = SystemTime::now()
-                            .duration_since(path_metadata.last_change)
-                            .unwrap()
+                        // A clock set back makes the last change look in the future
+                        let last_change_superior_to_one_sec = path_metadata
+                            .last_change
+                            .elapsed()
+                            .unwrap_or_default()
                             .as_millis()
                             > 1000;
 
                         if file_is_identical && last_change_superior_to_one_sec {
-                            info!("file '{}' not copied : content is identical", path_str);
+                            info!(
+                                "{}file '{}' not copied : content is identical",
+                                pair.log_prefix(),
+                                path_str
+                            );
                         } else if file_is_identical {
                         } else {
-                            Self::create_depends_dirs(dirs, path_str, file_store, &emit_time).await;
+                            let Ok(target_changed) = Utils::check_target_change(
+                                pair,
+                                &path_metadata,
+                                relative_path,
+                                path_str,
+                            )
+                            .await
+                            else {
+                                continue;
+                            };
+                            // The blocks written last time aren't on the target anymore
+                            let known_blocks = known_blocks.filter(|_| !target_changed);
 
-                            if Utils::copy_file(&v_path, &dest_path, path_str, emit_time)
-                                .await
-                                .is_ok()
-                            {
+                            Self::create_depends_dirs(pair, dirs, path_str, file_store, &emit_time)
+                                .await;
+
+                            let copied = Utils::copy_file(
+                                pair,
+                                &v_path,
+                                relative_path,
+                                path_str,
+                                emit_time,
+                                src_blocks.as_ref(),
+                                known_blocks.as_ref(),
+                            )
+                            .await;
+                            if let Ok(copied_hash) = copied {
                                 Self::write_in_file_store(
+                                    pair,
                                     file_store,
-                                    v_path,
+                                    v_path.clone(),
                                     PathType::File,
-                                    current_hash,
+                                    copied_hash.or(current_hash),
+                                    src_blocks,
+                                    src_metadata.as_ref(),
                                 )
                                 .await;
+                                Self::update_hard_links(pair, file_store, &v_path, emit_time).await;
                             }
                         }
                     }
+                    PathType::Symlink => {}
                 }
                 continue;
             }
 
-            if v_path.is_file() {
-                Self::create_depends_dirs(dirs, path_str, file_store, &emit_time).await;
-
-                if Utils::copy_file(&v_path, &dest_path, path_str, emit_time)
-                    .await
-                    .is_ok()
+            if path_type == PathType::File {
+                Self::create_depends_dirs(pair, dirs, path_str, file_store, &emit_time).await;
+                let src_metadata = fs::metadata(&v_path).await.ok();
+                if Self::link_to_synced_file(
+                    pair,
+                    file_store,
+                    &v_path,
+                    src_metadata.as_ref(),
+                    emit_time,
+                )
+                .await
                 {
-                    let current_hash = if let Ok(file_content) = fs::read(&v_path).await {
-                        Some(hash(&file_content))
-                    } else {
-                        None
-                    };
+                    continue;
+                }
+                // Outside of delta mode, the hash comes from the copy itself
+                let (current_hash, src_blocks) = if pair.config.delta {
+                    Utils::hash_source(pair, &v_path).await
+                } else {
+                    (None, None)
+                };
 
-                    Self::write_in_file_store(file_store, v_path, PathType::File, current_hash)
-                        .await;
+                let copied = Utils::copy_file(
+                    pair,
+                    &v_path,
+                    relative_path,
+                    path_str,
+                    emit_time,
+                    src_blocks.as_ref(),
+                    None,
+                )
+                .await;
+                if let Ok(copied_hash) = copied {
+                    Self::write_in_file_store(
+                        pair,
+                        file_store,
+                        v_path,
+                        PathType::File,
+                        copied_hash.or(current_hash),
+                        src_blocks,
+                        src_metadata.as_ref(),
+                    )
+                    .await;
                 }
                 continue;
             }
-
-            if v_path.is_dir()
-                && !dest_path.is_dir()
-                && Utils::create_dirs(&dest_path, path_str, &emit_time, false)
-                    .await
-                    .is_ok()
-            {
-                Self::write_in_file_store(file_store, v_path, PathType::Dir, None).await;
-            }
         }
     }
 
-    pub async fn remove(
-        file_store: &mut LruCache<PathBuf, PathMetadata>,
+    /// Give the modification time and permissions of the source paths to the target ones, along
+    /// with the other preserved attributes, without reading their content again. The paths not
+    /// synced yet or with a new size are copied instead
+    pub async fn update_metadata(
+        pair: &SyncPair,
+        file_store: &FileStore,
         emit_time: Instant,
         event: Event,
     ) {
         // "paths" length is always 1 on Windows
         for src_path in event.paths {
             let v_path = Utils::path_to_verbatim(&src_path);
+            let relative_path = pair.relative_path(&v_path);
+            // The attributes of the symlinks themselves aren't synced
+            if !matches!(
+                pair.source_path_type(&v_path).await,
+                Some(PathType::File | PathType::Dir)
+            ) {
+                continue;
+            }
+            let Ok(src_metadata) = fs::metadata(&v_path).await else {
+                continue;
+            };
 
-            if is_in_excluded_paths(&v_path) {
+            if !pair.is_synced(relative_path, src_metadata.is_dir()) {
                 continue;
             }
-            let path_str = v_path
-                .strip_prefix(&Utils::args().source_dir)
-                .unwrap()
-                .to_str()
-                .unwrap();
 
-            if Utils::args().no_temporary_editor_files && path_str.ends_with('~') {
+            let path_str = &Utils::fmt_path(relative_path);
+            let (path_type, type_str) = if src_metadata.is_dir() {
+                (PathType::Dir, "dir")
+            } else {
+                (PathType::File, "file")
+            };
+            let content_is_synced = file_store.get(&v_path).is_some_and(|path_metadata| {
+                path_metadata.path_type == path_type
+                    && (path_type == PathType::Dir
+                        || path_metadata.hash.is_some() && path_metadata.len == src_metadata.len())
+            });
+            if !content_is_synced
+                || pair.target_path_type(relative_path).await != Some(path_type.clone())
+            {
+                let event = Event::new(Modify(ModifyKind::Any)).add_path(src_path);
+                Self::copy(pair, file_store, emit_time, event).await;
                 continue;
             }
 
-            let relative_path = v_path.strip_prefix(&Utils::args().source_dir).unwrap();
-            let dest_path = Utils::get_destination_path(relative_path);
+            let mut preserve = vec![Preserve::Times, Preserve::Perms];
+            preserve.extend_from_slice(&pair.config.preserve);
+            let result = match Attributes::read(&v_path, &preserve).await {
+                Ok(attributes) => pair.target.set_attributes(relative_path, &attributes).await,
+                Err(err) => Err(err),
+            };
+            match result {
+                Ok(()) => {
+                    pair.print_action("metadata updated", type_str, path_str, &emit_time);
+                    // The content is unchanged, its hash stays valid with the new modification time
+                    file_store.update(&v_path, |path_metadata| {
+                        if path_type == PathType::File {
+                            path_metadata.modified = src_metadata.modified().ok();
+                        }
+                        path_metadata.last_change = SystemTime::now();
+                    });
+                }
+                // Agents older than the attributes get the whole file
+                Err(err) if err.kind() == ErrorKind::Unsupported => {
+                    let event = Event::new(Modify(ModifyKind::Any)).add_path(src_path);
+                    Self::copy(pair, file_store, emit_time, event).await;
+                }
+                Err(err) => {
+                    err!(
+                        "{}failed to update the metadata of '{}', error: {}",
+                        pair.log_prefix(),
+                        path_str,
+                        err.to_string()
+                    );
+                }
+            }
+        }
+    }
 
-            if !dest_path.exists() {
+    pub async fn remove(pair: &SyncPair, file_store: &FileStore, emit_time: Instant, event: Event) {
+        // "paths" length is always 1 on Windows
+        for src_path in event.paths {
+            let v_path = Utils::path_to_verbatim(&src_path);
+            let relative_path = pair.relative_path(&v_path);
+            let Some(dest_type) = pair.target_path_type(relative_path).await else {
                 return;
-            } else if dest_path.is_file() {
-                if let Err(err) = fs::remove_file(dest_path).await {
-                    handle_remove_err(err, path_str, PathType::File);
-                } else {
-                    Utils::print_action("deleted", "file", path_str, &emit_time);
-                };
-                file_store.pop(&v_path);
-            } else if dest_path.is_dir() {
-                if let Err(err) = fs::remove_dir_all(dest_path).await {
-                    handle_remove_err(err, path_str, PathType::Dir);
-                } else {
-                    Utils::print_action("deleted", "dir", path_str, &emit_time);
-                };
-                file_store.pop(&v_path);
+            };
+
+            // The source path doesn't exist anymore, the target tells whether it was a dir
+            if !pair.is_synced(relative_path, dest_type == PathType::Dir) {
+                continue;
+            }
+
+            let path_str = &Utils::fmt_path(relative_path);
+            let type_str = match dest_type {
+                PathType::File => "file",
+                PathType::Dir => "dir",
+                PathType::Symlink => "symlink",
+            };
+
+            // Moved to the trash of the target instead when it is enabled
+            if let Err(err) =
+                Trash::remove(pair, pair.target.as_ref(), relative_path, &dest_type).await
+            {
+                handle_remove_err(pair, err, path_str, dest_type);
             } else {
-                err!("remove error: '{}' is not a file or a directory", path_str);
+                pair.print_action("deleted", type_str, path_str, &emit_time);
             }
+            // The content of a removed dir goes along with it
+            file_store.remove_all(&v_path);
         }
     }
 
     pub async fn rename(
-        file_store: &mut LruCache<PathBuf, PathMetadata>,
+        pair: &SyncPair,
+        file_store: &FileStore,
         emit_time: Instant,
         event: Event,
         rename_from: &mut Option<PathBuf>,
@@ -192,59 +327,34 @@ impl FileOperationsManager {
         for src_path in event.paths {
             let v_path = Utils::path_to_verbatim(&src_path);
 
-            if is_in_excluded_paths(&v_path) {
-                continue;
-            }
-
-            let path_str = v_path
-                .strip_prefix(&Utils::args().source_dir)
-                .unwrap()
-                .to_str()
-                .unwrap();
-
-            if Utils::args().no_temporary_editor_files && path_str.ends_with('~') {
-                continue;
-            }
-
-            let relative_path = v_path.strip_prefix(&Utils::args().source_dir).unwrap();
-            let (dest_path, _) = Utils::get_destination_path_and_dirs(relative_path);
-
             match event.kind {
                 Modify(ModifyKind::Name(RenameMode::From)) => {
-                    *rename_from = Some(dest_path);
+                    *rename_from = Some(v_path);
                 }
                 Modify(ModifyKind::Name(RenameMode::To)) => {
-                    if rename_from.is_some() {
-                        let old_path = rename_from.take().unwrap();
-
-                        if fs::rename(&old_path, dest_path).await.is_ok() {
-                            let path_type;
-                            let path_type_str;
-                            if v_path.is_file() {
-                                path_type = PathType::File;
-                                path_type_str = "file";
-                            } else if v_path.is_dir() {
-                                path_type = PathType::Dir;
-                                path_type_str = "dir";
-                            } else {
-                                err!("'{}' is not a file or a directory", path_str);
-                                return;
-                            };
-
-                            Utils::print_action("renamed", path_type_str, path_str, &emit_time);
-
-                            if let Some(mut metadata) = file_store.pop(&old_path) {
-                                metadata.last_change = SystemTime::now();
-                                file_store.put(v_path, metadata);
-                            } else {
-                                let metadata = PathMetadata {
-                                    path_type,
-                                    hash: None,
-                                    last_change: SystemTime::now(),
-                                };
-                                file_store.put(v_path, metadata);
-                            }
+                    let relative_path = pair.relative_path(&v_path);
+                    let is_dir = pair.source_path_type(&v_path).await == Some(PathType::Dir);
+                    let to_is_synced = pair.is_synced(relative_path, is_dir);
+                    let from_path = rename_from
+                        .take()
+                        .filter(|from_path| pair.is_synced(pair.relative_path(from_path), is_dir));
+
+                    match (from_path, to_is_synced) {
+                        (Some(from_path), true) => {
+                            Self::rename_in_target(pair, file_store, emit_time, from_path, v_path)
+                                .await;
+                        }
+                        // Moved out of the synced paths
+                        (Some(from_path), false) => {
+                            let event = Event::new(Remove(RemoveKind::Any)).add_path(from_path);
+                            Self::remove(pair, file_store, emit_time, event).await;
                         }
+                        // Moved into the synced paths, like editors saving through a temporary file
+                        (None, true) => {
+                            let event = Event::new(Modify(ModifyKind::Any)).add_path(v_path);
+                            Self::copy(pair, file_store, emit_time, event).await;
+                        }
+                        (None, false) => {}
                     }
                 }
                 _ => {}
@@ -252,141 +362,423 @@ impl FileOperationsManager {
         }
     }
 
-    pub async fn create(
-        file_store: &mut LruCache<PathBuf, PathMetadata>,
+    async fn rename_in_target(
+        pair: &SyncPair,
+        file_store: &FileStore,
         emit_time: Instant,
-        event: Event,
+        from_path: PathBuf,
+        v_path: PathBuf,
     ) {
+        let relative_path = pair.relative_path(&v_path);
+        let path_str = &Utils::fmt_path(relative_path);
+        let old_relative_path = pair.relative_path(&from_path);
+
+        if pair
+            .target
+            .rename(old_relative_path, relative_path)
+            .await
+            .is_ok()
+        {
+            let (path_type, path_type_str) = match pair.source_path_type(&v_path).await {
+                Some(PathType::File) => (PathType::File, "file"),
+                Some(PathType::Dir) => (PathType::Dir, "dir"),
+                Some(PathType::Symlink) => (PathType::Symlink, "symlink"),
+                None => {
+                    err!(
+                        "{}'{}' is not a file or a directory",
+                        pair.log_prefix(),
+                        path_str
+                    );
+                    return;
+                }
+            };
+
+            pair.print_action("renamed", path_type_str, path_str, &emit_time);
+
+            // The content of a renamed dir is moved along with it
+            let renamed = file_store.update(&from_path, |metadata| {
+                metadata.last_change = SystemTime::now();
+            });
+            if renamed {
+                file_store.rename(&from_path, &v_path);
+            } else {
+                file_store.insert(v_path, PathMetadata::new(path_type));
+            }
+        }
+    }
+
+    pub async fn create(pair: &SyncPair, file_store: &FileStore, emit_time: Instant, event: Event) {
         for src_path in event.paths {
             let v_path = Utils::path_to_verbatim(&src_path);
+            let relative_path = pair.relative_path(&v_path);
+            let Some(path_type) = pair.source_path_type(&v_path).await else {
+                continue;
+            };
 
-            if is_in_excluded_paths(&v_path) {
+            if !pair.is_synced(relative_path, path_type == PathType::Dir) {
                 continue;
             }
 
-            let path_str = v_path
-                .strip_prefix(&Utils::args().source_dir)
-                .unwrap()
-                .to_str()
-                .unwrap();
+            let path_str = &Utils::fmt_path(relative_path);
+            let dirs = relative_path.parent().unwrap();
 
-            if Utils::args().no_temporary_editor_files && path_str.ends_with('~') {
+            if file_store.contains(&v_path) {
                 continue;
             }
 
-            let relative_path = v_path.strip_prefix(&Utils::args().source_dir).unwrap();
-            let (dest_path, dirs) = Utils::get_destination_path_and_dirs(relative_path);
+            let dest_exists = pair.target_path_type(relative_path).await.is_some();
 
-            if file_store.get(&v_path).is_some() {
+            if path_type == PathType::Symlink && !dest_exists {
+                Self::copy_symlink(pair, file_store, &v_path, emit_time).await;
                 continue;
             }
 
-            if v_path.is_file() && !dest_path.exists() {
-                Self::create_depends_dirs(dirs, path_str, file_store, &emit_time).await;
-
-                if let Err(err) = File::create(dest_path).await {
-                    err!(
-                        "failed to create '{}', error: {}",
-                        path_str,
-                        err.to_string()
-                    );
-                } else {
-                    Utils::print_action("created", "file", path_str, &emit_time);
-                    Self::write_in_file_store(file_store, v_path, PathType::File, None).await;
+            if path_type == PathType::File && !dest_exists {
+                Self::create_depends_dirs(pair, dirs, path_str, file_store, &emit_time).await;
+
+                let src_metadata = fs::metadata(&v_path).await.ok();
+                if Self::link_to_synced_file(
+                    pair,
+                    file_store,
+                    &v_path,
+                    src_metadata.as_ref(),
+                    emit_time,
+                )
+                .await
+                {
+                    continue;
+                }
+                match pair.target.write_file(relative_path, &v_path).await {
+                    Ok(hash) => {
+                        Utils::preserve_attributes(
+                            pair,
+                            pair.target.as_ref(),
+                            &v_path,
+                            relative_path,
+                            path_str,
+                        )
+                        .await;
+                        pair.print_action("created", "file", path_str, &emit_time);
+                        Self::write_in_file_store(
+                            pair,
+                            file_store,
+                            v_path,
+                            PathType::File,
+                            Some(hash),
+                            None,
+                            src_metadata.as_ref(),
+                        )
+                        .await;
+                    }
+                    Err(err) => {
+                        err!(
+                            "{}failed to create '{}', error: {}",
+                            pair.log_prefix(),
+                            path_str,
+                            err.to_string()
+                        );
+                    }
                 }
                 continue;
             }
 
-            if v_path.is_dir() && !dest_path.exists() {
-                Self::create_depends_dirs(dirs, path_str, file_store, &emit_time).await;
-
-                if Utils::create_dirs(&dest_path, path_str, &emit_time, false)
-                    .await
-                    .is_ok()
-                {
-                    Utils::print_action("created", "dir", path_str, &emit_time);
-                    Self::write_in_file_store(file_store, v_path, PathType::Dir, None).await;
-                }
+            if path_type == PathType::Dir && !dest_exists {
+                Self::create_depends_dirs(pair, dirs, path_str, file_store, &emit_time).await;
+                Self::create_dir(pair, file_store, v_path, emit_time).await;
             }
         }
     }
 
+    /// Create the dir on the target along with its content, unless it is there already. A dir
+    /// moved into the source dir comes with a single event, none for its content
+    async fn create_dir(
+        pair: &SyncPair,
+        file_store: &FileStore,
+        v_path: PathBuf,
+        emit_time: Instant,
+    ) {
+        let relative_path = pair.relative_path(&v_path);
+        if pair.target_path_type(relative_path).await == Some(PathType::Dir) {
+            return;
+        }
+
+        let path_str = &Utils::fmt_path(relative_path);
+        if Utils::create_dirs(pair, relative_path, path_str, &emit_time, false)
+            .await
+            .is_ok()
+        {
+            Self::write_in_file_store(
+                pair,
+                file_store,
+                v_path.clone(),
+                PathType::Dir,
+                None,
+                None,
+                None,
+            )
+            .await;
+            Scanner::reconcile_dir(pair, file_store, &v_path).await;
+        }
+    }
+
     async fn write_in_file_store(
-        file_store: &mut LruCache<PathBuf, PathMetadata>,
+        pair: &SyncPair,
+        file_store: &FileStore,
         path: PathBuf,
         path_type: PathType,
         current_hash_opt: Option<Hash>,
+        blocks: Option<Blocks>,
+        src_metadata: Option<&Metadata>,
     ) {
-        if file_store.get(&path).is_none() {
-            file_store.put(
+        let len = src_metadata.map_or(0, Metadata::len);
+        let modified = src_metadata.and_then(|metadata| metadata.modified().ok());
+        let file_id = src_metadata.and_then(Utils::file_id);
+        let (target_len, target_modified) = if path_type == PathType::File {
+            Utils::target_stamp(pair, pair.relative_path(&path)).await
+        } else {
+            (0, None)
+        };
+
+        let mut new_blocks = Some(blocks);
+        let known = file_store.update(&path, |path_metadata| {
+            path_metadata.path_type = path_type.clone();
+            if path_type != PathType::Dir {
+                path_metadata.hash = current_hash_opt;
+                path_metadata.target_hash = current_hash_opt;
+                path_metadata.len = len;
+                path_metadata.modified = modified;
+                path_metadata.target_len = target_len;
+                path_metadata.target_modified = target_modified;
+                path_metadata.blocks = new_blocks.take().unwrap();
+                path_metadata.file_id = file_id;
+            }
+            path_metadata.last_change = SystemTime::now();
+        });
+        if !known {
+            file_store.insert(
                 path,
                 PathMetadata {
-                    path_type,
-                    hash: current_hash_opt,
-                    last_change: SystemTime::now(),
+                    len,
+                    modified,
+                    target_len,
+                    target_modified,
+                    blocks: new_blocks.unwrap(),
+                    file_id,
+                    ..PathMetadata::file(path_type, current_hash_opt)
                 },
             );
-        } else {
-            let path_metadata = file_store.get_mut(&path).unwrap();
-            if path_type == PathType::File {
-                path_metadata.hash = current_hash_opt;
+        }
+    }
+
+    /// Link the new file to the target file of another synced hard link of the same source file.
+    /// `false` when there is none, or when it must be copied instead
+    async fn link_to_synced_file(
+        pair: &SyncPair,
+        file_store: &FileStore,
+        v_path: &Path,
+        src_metadata: Option<&Metadata>,
+        emit_time: Instant,
+    ) -> bool {
+        let Some(original) = src_metadata
+            .and_then(|metadata| Utils::hard_link_of(pair, file_store, v_path, metadata))
+        else {
+            return false;
+        };
+        let relative_path = pair.relative_path(v_path);
+        let path_str = &Utils::fmt_path(relative_path);
+
+        match Utils::link_file(pair, &original, relative_path, path_str, emit_time).await {
+            Ok(true) => {
+                if let Some(mut path_metadata) = file_store.get(&original) {
+                    path_metadata.last_change = SystemTime::now();
+                    file_store.insert(v_path.to_path_buf(), path_metadata);
+                }
+                true
             }
-            path_metadata.last_change = SystemTime::now();
+            Ok(false) => false,
+            Err(()) => true,
+        }
+    }
+
+    /// The content was written to a new target file, the other synced hard links of the same
+    /// source file are linked to it. They are copied when the target can't link files
+    async fn update_hard_links(
+        pair: &SyncPair,
+        file_store: &FileStore,
+        v_path: &Path,
+        emit_time: Instant,
+    ) {
+        let Some(path_metadata) = file_store.get(v_path) else {
+            return;
+        };
+        let Some(file_id) = path_metadata.file_id.filter(|_| pair.config.hard_links) else {
+            return;
+        };
+        let relative_path = pair.relative_path(v_path);
+
+        for linked_path in file_store.hard_links(file_id) {
+            let linked_relative_path = pair.relative_path(&linked_path);
+            if linked_path == v_path
+                || pair
+                    .target
+                    .same_file(linked_relative_path, relative_path)
+                    .await
+                    .unwrap_or(false)
+            {
+                continue;
+            }
+            let path_str = &Utils::fmt_path(linked_relative_path);
+
+            let synced =
+                match Utils::link_file(pair, v_path, linked_relative_path, path_str, emit_time)
+                    .await
+                {
+                    Ok(true) => true,
+                    Ok(false) => Utils::copy_file(
+                        pair,
+                        v_path,
+                        linked_relative_path,
+                        path_str,
+                        emit_time,
+                        None,
+                        None,
+                    )
+                    .await
+                    .is_ok(),
+                    Err(()) => false,
+                };
+            if synced {
+                let mut path_metadata = path_metadata.clone();
+                path_metadata.last_change = SystemTime::now();
+                file_store.insert(linked_path, path_metadata);
+            }
+        }
+    }
+
+    async fn copy_symlink(
+        pair: &SyncPair,
+        file_store: &FileStore,
+        v_path: &Path,
+        emit_time: Instant,
+    ) {
+        let relative_path = pair.relative_path(v_path);
+        let path_str = &Utils::fmt_path(relative_path);
+        let dirs = relative_path.parent().unwrap();
+        Self::create_depends_dirs(pair, dirs, path_str, file_store, &emit_time).await;
+
+        if let Ok(link) =
+            Utils::copy_symlink(pair, v_path, relative_path, path_str, emit_time).await
+        {
+            Self::write_in_file_store(
+                pair,
+                file_store,
+                v_path.to_path_buf(),
+                PathType::Symlink,
+                Some(Utils::hash_link(&link)),
+                None,
+                None,
+            )
+            .await;
         }
     }
 
     async fn create_depends_dirs(
-        dirs: PathBuf,
+        pair: &SyncPair,
+        dirs: &Path,
         path_str: &str,
-        file_store: &mut LruCache<PathBuf, PathMetadata>,
+        file_store: &FileStore,
         emit_time: &Instant,
     ) {
-        if !dirs.exists()
-            && Utils::create_dirs(&dirs, path_str, emit_time, true)
+        if pair.target_path_type(dirs).await.is_none()
+            && Utils::create_dirs(pair, dirs, path_str, emit_time, true)
                 .await
                 .is_ok()
         {
-            Self::write_in_file_store(file_store, dirs, PathType::Dir, None).await;
+            let v_path = pair.config.source_dir.join(dirs);
+            Self::write_in_file_store(pair, file_store, v_path, PathType::Dir, None, None, None)
+                .await;
         }
     }
 }
 
-fn is_in_excluded_paths(path: &Path) -> bool {
-    if Utils::excluded_paths().is_empty() {
-        return false;
-    }
-
-    for excluded_path in Utils::excluded_paths() {
-        if path.starts_with(excluded_path) {
-            return true;
-        }
-    }
-
-    false
-}
-
-fn handle_remove_err(err: std::io::Error, path_str: &str, entry_type: PathType) {
+pub(crate) fn handle_remove_err(
+    pair: &SyncPair,
+    err: std::io::Error,
+    path_str: &str,
+    entry_type: PathType,
+) {
     let entry_type_str = match entry_type {
         PathType::File => "file",
         PathType::Dir => "dir",
+        PathType::Symlink => "symlink",
     };
 
-    if let Some(os_error_code) = err.raw_os_error() {
-        // Mute errors 2 & 3 which means that the path does not exists
-        if os_error_code != 2 && os_error_code != 3 {
-            err!(
-                "failed to remove {} '{}', error: {}",
-                entry_type_str,
-                path_str,
-                err.to_string()
-            );
-        };
-    } else {
+    // Mute errors meaning that the path does not exists
+    if err.kind() != ErrorKind::NotFound {
         err!(
-            "failed to remove {} '{}', error: {}",
+            "{}failed to remove {} '{}', error: {}",
+            pair.log_prefix(),
             entry_type_str,
             path_str,
             err.to_string()
         );
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use notify::event::MetadataKind;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::config::SyncConfig;
+    use crate::target::{MemoryTarget, SyncTarget};
+
+    async fn metadata_event(pair: &SyncPair, file_store: &FileStore, path: PathBuf) {
+        let event = Event::new(Modify(ModifyKind::Metadata(MetadataKind::Any))).add_path(path);
+        Utils::handle_event(pair, event, file_store, Instant::now(), &mut None).await;
+    }
+
+    #[tokio::test]
+    async fn metadata_changes_are_applied_without_the_content() {
+        let source = TempDir::new().unwrap();
+        let config = SyncConfig::new(source.path().to_path_buf(), PathBuf::new());
+        let target = Arc::new(MemoryTarget::new());
+        let pair = SyncPair::new(config, None, target.clone()).unwrap();
+        let file_store = FileStore::default();
+        let src_path = source.path().join("f");
+        std::fs::write(&src_path, b"synced").unwrap();
+        Scanner::reconcile(&pair, &file_store).await;
+        // Changed behind the back of the sync, a copy would overwrite it
+        let other = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(other.path(), b"target").unwrap();
+        target
+            .write_file(Path::new("f"), other.path())
+            .await
+            .unwrap();
+
+        let modified = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        filetime::set_file_mtime(&src_path, modified.into()).unwrap();
+        metadata_event(&pair, &file_store, src_path.clone()).await;
+        let dest_metadata = target.stat(Path::new("f")).await.unwrap();
+        assert_eq!(dest_metadata.modified, Some(modified));
+        assert_eq!(target.read(Path::new("f")), Some(b"target".to_vec()));
+        assert_eq!(file_store.get(&src_path).unwrap().modified, Some(modified));
+    }
+
+    #[tokio::test]
+    async fn metadata_changes_of_unsynced_files_copy_them() {
+        let source = TempDir::new().unwrap();
+        let config = SyncConfig::new(source.path().to_path_buf(), PathBuf::new());
+        let target = Arc::new(MemoryTarget::new());
+        let pair = SyncPair::new(config, None, target.clone()).unwrap();
+        let src_path = source.path().join("f");
+        std::fs::write(&src_path, b"content").unwrap();
+
+        metadata_event(&pair, &FileStore::default(), src_path).await;
+        assert_eq!(target.read(Path::new("f")), Some(b"content".to_vec()));
+    }
+}
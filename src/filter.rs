@@ -0,0 +1,396 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::future::Future;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+
+use crate::target::{STATE_DIR_NAME, TEMP_FILE_PREFIX, TRASH_DIR_NAME};
+use crate::utils::Utils;
+use crate::warn;
+
+pub const IGNORE_FILE_NAME: &str = ".oxsyncignore";
+const GITIGNORE_FILE_NAME: &str = ".gitignore";
+
+#[derive(Debug, Clone, Default)]
+pub struct FilterConfig {
+    pub exclude_patterns: Vec<String>,
+    pub include_patterns: Vec<String>,
+    pub use_gitignore: bool,
+    pub no_temporary_editor_files: bool,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FilterDecision {
+    Included,
+    Excluded,
+    /// Not matched by the include patterns, the children of a dir may still be included
+    NotIncluded,
+}
+
+/// Gitignore-style patterns matched against paths relative to the source dir
+#[derive(Debug)]
+pub struct Filter {
+    source_dir: PathBuf,
+    config: FilterConfig,
+    excludes: Gitignore,
+    includes: Option<Gitignore>,
+    /// Ignore files found in the source dir, sorted from the lowest to the highest precedence,
+    /// along with the dir they apply to, relative to the source dir
+    ignore_files: Vec<(PathBuf, Gitignore)>,
+}
+
+impl Filter {
+    pub fn new(source_dir: &Path, config: FilterConfig) -> Result<Self, ignore::Error> {
+        let excludes = Self::build_patterns(source_dir, &config.exclude_patterns)?;
+        let includes = if config.include_patterns.is_empty() {
+            None
+        } else {
+            Some(Self::build_patterns(source_dir, &config.include_patterns)?)
+        };
+
+        let mut filter = Self {
+            source_dir: source_dir.to_path_buf(),
+            config,
+            excludes,
+            includes,
+            ignore_files: Vec::new(),
+        };
+        filter.ignore_files = filter.load_ignore_files();
+
+        Ok(filter)
+    }
+
+    /// Build the filter again, to take into account changes made to the ignore files. The source
+    /// dir is walked on a blocking thread
+    pub fn reload(&self) -> impl Future<Output = Result<Self, ignore::Error>> {
+        let source_dir = self.source_dir.clone();
+        let config = self.config.clone();
+        async move {
+            tokio::task::spawn_blocking(move || Self::new(&source_dir, config))
+                .await
+                .map_err(|err| ignore::Error::Io(io::Error::other(err)))?
+        }
+    }
+
+    /// Whether a change to this path requires to reload the filter
+    pub fn is_ignore_file(&self, path: &Path) -> bool {
+        let Some(file_name) = path.file_name() else {
+            return false;
+        };
+
+        file_name == IGNORE_FILE_NAME
+            || (self.config.use_gitignore
+                && (file_name == GITIGNORE_FILE_NAME || path.ends_with(".git/info/exclude")))
+    }
+
+    /// The shared decision on whether a path is synced, in this order of precedence:
+    /// - the `.oxsync` state dir at the root and the temp files of the writes are always excluded
+    /// - files with names ending by a tilde `~` are excluded, if enabled
+    /// - the `--exclude` patterns, then `.oxsyncignore` files, `.gitignore` files and finally
+    ///   `.git/info/exclude`, the deepest ignore file of each kind winning
+    /// - when `--include` patterns are given, the paths they don't match are not included
+    ///
+    /// A path matches a set of patterns when it or one of its parent dirs matches the last
+    /// pattern, unless it is a negated one (`!keep.log`).
+    pub fn decision(&self, relative_path: &Path, is_dir: bool) -> FilterDecision {
+        if self.is_excluded(relative_path, is_dir) {
+            return FilterDecision::Excluded;
+        }
+
+        match &self.includes {
+            Some(includes)
+                if !includes
+                    .matched_path_or_any_parents(relative_path, is_dir)
+                    .is_ignore() =>
+            {
+                FilterDecision::NotIncluded
+            }
+            _ => FilterDecision::Included,
+        }
+    }
+
+    fn is_excluded(&self, relative_path: &Path, is_dir: bool) -> bool {
+        self.is_excluded_by(self.ignore_files.iter(), relative_path, is_dir)
+    }
+
+    /// `ignore_files` are sorted from the lowest to the highest precedence
+    fn is_excluded_by<'a>(
+        &self,
+        ignore_files: impl DoubleEndedIterator<Item = &'a (PathBuf, Gitignore)>,
+        relative_path: &Path,
+        is_dir: bool,
+    ) -> bool {
+        let is_temp_file = relative_path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with(TEMP_FILE_PREFIX));
+        if relative_path.starts_with(STATE_DIR_NAME)
+            || relative_path.starts_with(TRASH_DIR_NAME)
+            || is_temp_file
+        {
+            return true;
+        }
+
+        if self.config.no_temporary_editor_files
+            && relative_path
+                .to_str()
+                .is_some_and(|path_str| path_str.ends_with('~'))
+        {
+            return true;
+        }
+
+        let matched = self
+            .excludes
+            .matched_path_or_any_parents(relative_path, is_dir);
+        if !matched.is_none() {
+            return matched.is_ignore();
+        }
+
+        for (dir, ignore_file) in ignore_files.rev() {
+            let Ok(path_in_dir) = relative_path.strip_prefix(dir) else {
+                continue;
+            };
+
+            let matched = ignore_file.matched_path_or_any_parents(path_in_dir, is_dir);
+            if !matched.is_none() {
+                return matched.is_ignore();
+            }
+        }
+
+        false
+    }
+
+    fn build_patterns(source_dir: &Path, patterns: &[String]) -> Result<Gitignore, ignore::Error> {
+        let mut builder = GitignoreBuilder::new(source_dir);
+        for pattern in patterns {
+            builder.add_line(None, pattern)?;
+        }
+
+        builder.build()
+    }
+
+    /// The dirs excluded by the patterns or the ignore files of their parents aren't walked
+    fn load_ignore_files(&self) -> Vec<(PathBuf, Gitignore)> {
+        let mut git_excludes = Vec::new();
+        let mut gitignores = Vec::new();
+        let mut oxsyncignores = Vec::new();
+
+        if self.config.use_gitignore {
+            let info_exclude = self.source_dir.join(".git").join("info").join("exclude");
+            if info_exclude.is_file() {
+                git_excludes.extend(self.parse_ignore_file(PathBuf::new(), &info_exclude));
+            }
+        }
+
+        // Breadth-first, so parent dirs come before their children
+        let mut dirs_to_visit = VecDeque::from([PathBuf::new()]);
+        while let Some(relative_dir) = dirs_to_visit.pop_front() {
+            let dir = self.source_dir.join(&relative_dir);
+
+            let gitignore = dir.join(GITIGNORE_FILE_NAME);
+            if self.config.use_gitignore && gitignore.is_file() {
+                gitignores.extend(self.parse_ignore_file(relative_dir.clone(), &gitignore));
+            }
+
+            let oxsyncignore = dir.join(IGNORE_FILE_NAME);
+            if oxsyncignore.is_file() {
+                oxsyncignores.extend(self.parse_ignore_file(relative_dir.clone(), &oxsyncignore));
+            }
+
+            let Ok(entries) = fs::read_dir(&dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let is_dir = entry.file_type().is_ok_and(|file_type| file_type.is_dir());
+                let relative_path = relative_dir.join(entry.file_name());
+                let loaded = git_excludes.iter().chain(&gitignores).chain(&oxsyncignores);
+                if is_dir
+                    && entry.file_name() != ".git"
+                    && !self.is_excluded_by(loaded, &relative_path, true)
+                {
+                    dirs_to_visit.push_back(relative_path);
+                }
+            }
+        }
+
+        git_excludes.extend(gitignores);
+        git_excludes.extend(oxsyncignores);
+        git_excludes
+    }
+
+    fn parse_ignore_file(
+        &self,
+        relative_dir: PathBuf,
+        path: &Path,
+    ) -> Option<(PathBuf, Gitignore)> {
+        let mut builder = GitignoreBuilder::new(self.source_dir.join(&relative_dir));
+        if let Some(err) = builder.add(path) {
+            warn!("invalid ignore file '{}' : {}", Utils::fmt_path(path), err);
+        }
+
+        match builder.build() {
+            Ok(ignore_file) => Some((relative_dir, ignore_file)),
+            Err(err) => {
+                warn!("invalid ignore file '{}' : {}", Utils::fmt_path(path), err);
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(source_dir: &Path, config: FilterConfig) -> Filter {
+        Filter::new(source_dir, config).unwrap()
+    }
+
+    fn excludes(patterns: &[&str]) -> FilterConfig {
+        FilterConfig {
+            exclude_patterns: patterns.iter().map(|pattern| pattern.to_string()).collect(),
+            ..FilterConfig::default()
+        }
+    }
+
+    fn decision(filter: &Filter, path: &str) -> FilterDecision {
+        filter.decision(Path::new(path), false)
+    }
+
+    #[test]
+    fn internal_paths_are_always_excluded() {
+        let dir = tempfile::tempdir().unwrap();
+        let filter = filter(dir.path(), excludes(&["!*"]));
+
+        assert_eq!(decision(&filter, ".oxsync/index"), FilterDecision::Excluded);
+        assert_eq!(
+            decision(&filter, ".oxsync-trash/2024-01-01/f"),
+            FilterDecision::Excluded
+        );
+        assert_eq!(
+            decision(&filter, "dir/.oxsync-tmp-f.1-0"),
+            FilterDecision::Excluded
+        );
+        assert_eq!(decision(&filter, "dir/f"), FilterDecision::Included);
+    }
+
+    #[test]
+    fn editor_files_are_excluded_when_enabled() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = FilterConfig {
+            no_temporary_editor_files: true,
+            ..FilterConfig::default()
+        };
+
+        assert_eq!(
+            decision(&filter(dir.path(), config), "f~"),
+            FilterDecision::Excluded
+        );
+        assert_eq!(
+            decision(&filter(dir.path(), FilterConfig::default()), "f~"),
+            FilterDecision::Included
+        );
+    }
+
+    #[test]
+    fn exclude_patterns_come_before_the_ignore_files() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join(IGNORE_FILE_NAME), "*.log\n!debug.log\n").unwrap();
+        let filter = filter(dir.path(), excludes(&["debug.log", "!keep.log"]));
+
+        assert_eq!(decision(&filter, "debug.log"), FilterDecision::Excluded);
+        assert_eq!(decision(&filter, "keep.log"), FilterDecision::Included);
+        assert_eq!(decision(&filter, "other.log"), FilterDecision::Excluded);
+    }
+
+    #[test]
+    fn parent_dirs_match_the_patterns() {
+        let dir = tempfile::tempdir().unwrap();
+        let filter = filter(dir.path(), excludes(&["build/"]));
+
+        assert_eq!(decision(&filter, "build/out/f"), FilterDecision::Excluded);
+        assert_eq!(decision(&filter, "src/build"), FilterDecision::Included);
+    }
+
+    #[test]
+    fn oxsyncignore_comes_before_gitignore() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join(".git").join("info")).unwrap();
+        fs::write(dir.path().join(".git/info/exclude"), "*.bak\n").unwrap();
+        fs::write(dir.path().join(GITIGNORE_FILE_NAME), "*.tmp\n!f.bak\n").unwrap();
+        fs::write(dir.path().join(IGNORE_FILE_NAME), "!f.tmp\n").unwrap();
+        let config = FilterConfig {
+            use_gitignore: true,
+            ..FilterConfig::default()
+        };
+        let filter = filter(dir.path(), config);
+
+        assert_eq!(decision(&filter, "f.tmp"), FilterDecision::Included);
+        assert_eq!(decision(&filter, "g.tmp"), FilterDecision::Excluded);
+        assert_eq!(decision(&filter, "f.bak"), FilterDecision::Included);
+        assert_eq!(decision(&filter, "g.bak"), FilterDecision::Excluded);
+    }
+
+    #[test]
+    fn gitignore_is_only_read_when_enabled() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(dir.path().join(GITIGNORE_FILE_NAME), "*.tmp\n").unwrap();
+        let filter = filter(dir.path(), FilterConfig::default());
+
+        assert_eq!(decision(&filter, "f.tmp"), FilterDecision::Included);
+    }
+
+    #[test]
+    fn deepest_ignore_file_wins() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        fs::write(dir.path().join(IGNORE_FILE_NAME), "*.txt\n").unwrap();
+        fs::write(dir.path().join("sub").join(IGNORE_FILE_NAME), "!keep.txt\n").unwrap();
+        let filter = filter(dir.path(), FilterConfig::default());
+
+        assert_eq!(decision(&filter, "sub/keep.txt"), FilterDecision::Included);
+        assert_eq!(decision(&filter, "sub/other.txt"), FilterDecision::Excluded);
+        assert_eq!(decision(&filter, "keep.txt"), FilterDecision::Excluded);
+    }
+
+    #[tokio::test]
+    async fn ignore_files_of_ignored_dirs_are_not_read() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("build/out")).unwrap();
+        fs::write(dir.path().join(IGNORE_FILE_NAME), "build/\n").unwrap();
+        fs::write(dir.path().join("build/out").join(IGNORE_FILE_NAME), "!*\n").unwrap();
+        let filter = filter(dir.path(), FilterConfig::default());
+        assert_eq!(decision(&filter, "build/out/f"), FilterDecision::Excluded);
+
+        fs::write(dir.path().join(IGNORE_FILE_NAME), "*.log\n").unwrap();
+        let filter = filter.reload().await.unwrap();
+        assert_eq!(decision(&filter, "build/out/f"), FilterDecision::Included);
+        assert_eq!(
+            decision(&filter, "build/out/f.log"),
+            FilterDecision::Included
+        );
+    }
+
+    #[test]
+    fn include_patterns_come_last() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = FilterConfig {
+            include_patterns: vec!["src/".to_string()],
+            ..excludes(&["src/generated/"])
+        };
+        let filter = filter(dir.path(), config);
+
+        assert_eq!(decision(&filter, "src/main.rs"), FilterDecision::Included);
+        assert_eq!(
+            decision(&filter, "src/generated/f.rs"),
+            FilterDecision::Excluded
+        );
+        assert_eq!(decision(&filter, "README.md"), FilterDecision::NotIncluded);
+        assert_eq!(
+            filter.decision(Path::new("docs"), true),
+            FilterDecision::NotIncluded
+        );
+    }
+}
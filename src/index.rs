@@ -0,0 +1,233 @@
+use std::io;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use blake3::Hash;
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::delta::Blocks;
+use crate::protocol::{decode_path, encode_path};
+use crate::utils::{FileId, FileStore, PathMetadata, PathType};
+
+/// Bumped when the format of the index changes, the indexes of other versions are ignored
+const INDEX_VERSION: u32 = 4;
+
+/// The file store of a source dir, saved to a file of the state dir between runs
+#[derive(Debug)]
+pub(crate) struct Index {
+    path: PathBuf,
+    source_dir: PathBuf,
+}
+
+#[derive(Serialize, Deserialize)]
+struct IndexFile {
+    version: u32,
+    entries: Vec<IndexEntry>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct IndexEntry {
+    /// Relative to the source dir, with `/` separators
+    path: Vec<u8>,
+    path_type: PathType,
+    hash: Option<[u8; 32]>,
+    target_hash: Option<[u8; 32]>,
+    len: u64,
+    modified: Option<SystemTime>,
+    last_change: SystemTime,
+    target_len: u64,
+    target_modified: Option<SystemTime>,
+    blocks: Option<(u64, Vec<[u8; 32]>)>,
+    /// Device and inode of the source file, the restored hard links are known from the start
+    file_id: Option<FileId>,
+}
+
+impl Index {
+    /// Several source dirs can share a state dir, each one has its own index
+    pub fn new(state_dir: &Path, source_dir: &Path) -> Self {
+        let source_hash = blake3::hash(source_dir.as_os_str().as_encoded_bytes());
+        let name = format!("index-{}", &source_hash.to_hex()[..16]);
+
+        Self {
+            path: state_dir.join(name),
+            source_dir: source_dir.to_path_buf(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Add the saved entries to `file_store`, none when the index doesn't exist yet
+    pub async fn load(&self, file_store: &FileStore) -> io::Result<()> {
+        let content = match fs::read(&self.path).await {
+            Ok(content) => content,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let index_file: IndexFile = bincode::deserialize(&content)
+            .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+        if index_file.version != INDEX_VERSION {
+            return Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported index version {}", index_file.version),
+            ));
+        }
+
+        let mut paths = Vec::with_capacity(index_file.entries.len());
+        for entry in index_file.entries {
+            let path = self.source_dir.join(decode_path(&entry.path)?);
+            paths.push((
+                path,
+                PathMetadata {
+                    path_type: entry.path_type,
+                    hash: entry.hash.map(Hash::from),
+                    target_hash: entry.target_hash.map(Hash::from),
+                    len: entry.len,
+                    modified: entry.modified,
+                    last_change: entry.last_change,
+                    target_len: entry.target_len,
+                    target_modified: entry.target_modified,
+                    blocks: entry.blocks.map(|(len, hashes)| Blocks {
+                        len,
+                        hashes: hashes.into_iter().map(Hash::from).collect(),
+                    }),
+                    file_id: entry.file_id,
+                },
+            ));
+        }
+        for (path, metadata) in paths {
+            file_store.insert(path, metadata);
+        }
+
+        Ok(())
+    }
+
+    /// Written to a temp file first, an interrupted save leaves the previous index intact
+    pub async fn save(&self, file_store: &FileStore) -> io::Result<()> {
+        let mut entries = Vec::new();
+        file_store.for_each(|path, metadata| {
+            if let Ok(relative_path) = path.strip_prefix(&self.source_dir) {
+                entries.push(IndexEntry {
+                    path: encode_path(relative_path),
+                    path_type: metadata.path_type.clone(),
+                    hash: metadata.hash.map(|hash| *hash.as_bytes()),
+                    target_hash: metadata.target_hash.map(|hash| *hash.as_bytes()),
+                    len: metadata.len,
+                    modified: metadata.modified,
+                    last_change: metadata.last_change,
+                    target_len: metadata.target_len,
+                    target_modified: metadata.target_modified,
+                    blocks: metadata.blocks.as_ref().map(|blocks| {
+                        let hashes = blocks.hashes.iter().map(|hash| *hash.as_bytes());
+                        (blocks.len, hashes.collect())
+                    }),
+                    file_id: metadata.file_id,
+                });
+            }
+        });
+        let index_file = IndexFile {
+            version: INDEX_VERSION,
+            entries,
+        };
+        let content = bincode::serialize(&index_file).map_err(io::Error::other)?;
+
+        if let Some(state_dir) = self.path.parent() {
+            fs::create_dir_all(state_dir).await?;
+        }
+        let temp_path = self.path.with_extension("tmp");
+        fs::write(&temp_path, content).await?;
+        fs::rename(&temp_path, &self.path).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn saved_entries_are_loaded_back() {
+        let state_dir = TempDir::new().unwrap();
+        let source_dir = Path::new("/source");
+        let index = Index::new(state_dir.path(), source_dir);
+        let file_store = FileStore::default();
+        let modified = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        file_store.insert(source_dir.join("dir"), PathMetadata::dir());
+        file_store.insert(
+            source_dir.join("dir/f"),
+            PathMetadata {
+                len: 7,
+                modified: Some(modified),
+                blocks: Some(Blocks {
+                    len: 7,
+                    hashes: vec![blake3::hash(b"content")],
+                }),
+                file_id: Some((1, 2)),
+                ..PathMetadata::file(PathType::File, Some(blake3::hash(b"content")))
+            },
+        );
+        // Outside the source dir
+        file_store.insert(PathBuf::from("/other/f"), PathMetadata::dir());
+        index.save(&file_store).await.unwrap();
+
+        let loaded = FileStore::default();
+        index.load(&loaded).await.unwrap();
+        assert_eq!(
+            loaded.get(&source_dir.join("dir")).unwrap().path_type,
+            PathType::Dir
+        );
+        let f = loaded.get(&source_dir.join("dir/f")).unwrap();
+        assert_eq!(f.hash, Some(blake3::hash(b"content")));
+        assert_eq!(f.target_hash, f.hash);
+        assert_eq!(
+            (f.len, f.modified, f.file_id),
+            (7, Some(modified), Some((1, 2)))
+        );
+        assert_eq!(f.blocks.unwrap().hashes, [blake3::hash(b"content")]);
+        assert!(!loaded.contains(Path::new("/other/f")));
+    }
+
+    #[tokio::test]
+    async fn missing_indexes_are_empty() {
+        let state_dir = TempDir::new().unwrap();
+        let index = Index::new(state_dir.path(), Path::new("/source"));
+        let file_store = FileStore::default();
+
+        index.load(&file_store).await.unwrap();
+        let mut count = 0;
+        file_store.for_each(|_, _| count += 1);
+        assert_eq!(count, 0);
+    }
+
+    #[tokio::test]
+    async fn indexes_of_other_versions_are_refused() {
+        let state_dir = TempDir::new().unwrap();
+        let index = Index::new(state_dir.path(), Path::new("/source"));
+        let index_file = IndexFile {
+            version: INDEX_VERSION - 1,
+            entries: Vec::new(),
+        };
+        fs::write(index.path(), bincode::serialize(&index_file).unwrap())
+            .await
+            .unwrap();
+
+        let err = index.load(&FileStore::default()).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn source_dirs_have_their_own_index() {
+        let state_dir = Path::new("/state");
+
+        assert_ne!(
+            Index::new(state_dir, Path::new("/a")).path(),
+            Index::new(state_dir, Path::new("/b")).path()
+        );
+    }
+}
@@ -0,0 +1,39 @@
+//! oxsync is a file synchronization tool. It enables fast, local reads with a remote filesystem.
+//!
+//! A [`SyncEngine`] is built from a [`SyncConfig`], then it either makes the target dir match the
+//! source dir once, or keeps syncing the changes of the source dir until shut down.
+
+pub use agent::Agent;
+pub use attributes::Attributes;
+pub use config::{
+    ConfigFile, Preserve, Profile, SymlinkPolicy, SyncConfig, TargetChangePolicy, CONFIG_FILE_NAME,
+};
+pub use engine::SyncEngine;
+pub use error::Error;
+pub use filter::FilterConfig;
+pub use scan::ScanSummary;
+pub use target::{
+    LocalTarget, MemoryEntry, MemoryTarget, RemoteTarget, SyncTarget, TargetMetadata,
+    STATE_DIR_NAME, TCP_SCHEME, TEMP_FILE_PREFIX, TRASH_DIR_NAME,
+};
+pub use utils::PathType;
+
+mod agent;
+mod attributes;
+mod config;
+mod delta;
+mod engine;
+mod error;
+mod file_operations;
+mod filter;
+mod index;
+mod macros;
+mod pair;
+mod protocol;
+mod scan;
+mod settle;
+mod target;
+mod trash;
+mod two_way;
+mod utils;
+mod workers;
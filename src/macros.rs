@@ -4,11 +4,13 @@ macro_rules! get_timestamp {
         // Ensure the `time` crate is in scope where the macro is used
         let now =
             time::OffsetDateTime::now_local().unwrap_or_else(|_| time::OffsetDateTime::now_utc());
-        let format =
-            time::format_description::parse("[hour]:[minute]:[second].[subsecond digits:3]")
-                .unwrap();
-        let timestamp = now.format(&format).unwrap();
-        timestamp
+        format!(
+            "{:02}:{:02}:{:02}.{:03}",
+            now.hour(),
+            now.minute(),
+            now.second(),
+            now.millisecond()
+        )
     }};
 }
 
@@ -39,9 +41,7 @@ macro_rules! warn {
 #[macro_export]
 macro_rules! trace {
     ($($arg:tt)*) => {{
-        if *LOG_TRACE.get().unwrap() {
-            let timestamp = $crate::get_timestamp!();
-            println!("{} TRACE {}", timestamp, format_args!($($arg)*));
-        }
+        let timestamp = $crate::get_timestamp!();
+        println!("{} TRACE {}", timestamp, format_args!($($arg)*));
     }};
 }
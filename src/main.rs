@@ -1,37 +1,113 @@
-use std::hash::BuildHasherDefault;
-use std::num::NonZeroUsize;
 use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
 
-use ahash::AHasher;
-use clap::Parser;
-use lru::LruCache;
-use notify::{RecursiveMode, Watcher};
-use tokio::sync::OnceCell;
-use tokio::time::Instant;
-use tokio_stream::StreamExt;
+use clap::{Parser, Subcommand, ValueEnum};
+use oxsync::{
+    err, info, Agent, LocalTarget, Preserve, SymlinkPolicy, SyncEngine, TargetChangePolicy,
+};
+use tokio::task::JoinSet;
 
-use start::Start;
-use utils::PathMetadata;
-use utils::Utils;
+use start::{Mode, Start};
 
-mod file_operations;
-mod macros;
 mod start;
-mod utils;
 
 /// Sync changes from a directory to another
 #[derive(Parser, Debug)]
-#[command(author, version, long_about = None)]
-pub struct Args {
-    /// Path of the directory to watch changes from
-    #[arg(index(1), required(true))]
-    source_dir: PathBuf,
+#[command(
+    author,
+    version,
+    long_about = None,
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true
+)]
+pub struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    args: Args,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Sync the changes of <SOURCE_DIR> to <TARGET_DIR> until stopped (default)
+    Watch(Args),
+    /// Make <TARGET_DIR> match <SOURCE_DIR> once, then exit
+    Sync(Args),
+    /// Write the changes sent by an oxsync started with `--agent-command` to <ROOT>, through stdin
+    /// and stdout
+    Agent(AgentArgs),
+    /// Accept oxsync connections on <LISTEN> and write the changes they send to <ROOT>, synced with
+    /// `oxsync <SOURCE_DIR> tcp://<HOST>:<PORT>`
+    Serve(ServeArgs),
+}
+
+#[derive(clap::Args, Debug)]
+pub struct AgentArgs {
     /// Path of the directory to write changes to
-    #[arg(index(2), required(true))]
-    target_dir: PathBuf,
-    /// Exclude file or dir from the <SOURCE_DIR>, can be used multiple times
+    root: PathBuf,
+    /// Flush the written files to the disk before moving them in place, slower but they survive a
+    /// power loss
+    #[arg(long)]
+    fsync: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ServeArgs {
+    /// Address to listen on. Only the local connections are accepted by default, listening on
+    /// `0.0.0.0:7070` accepts the ones of the network
+    #[arg(long, default_value = "127.0.0.1:7070")]
+    listen: String,
+    /// Secret the connecting oxsync must give before sending any change
+    #[arg(long, env = "OXSYNC_TOKEN", hide_env_values = true)]
+    token: String,
+    /// Path of the directory to write changes to
+    #[arg(long)]
+    root: PathBuf,
+    /// Flush the written files to the disk before moving them in place, slower but they survive a
+    /// power loss
+    #[arg(long)]
+    fsync: bool,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct Args {
+    /// Path of the directory to watch changes from, optional when set by the profile
+    #[arg(index(1))]
+    source_dir: Option<PathBuf>,
+    /// Path of the directory to write changes to, or `tcp://<HOST>:<PORT>` of an `oxsync serve`,
+    /// optional when set by the profile
+    #[arg(index(2))]
+    target_dir: Option<PathBuf>,
+    /// Path of the TOML config file declaring the profiles [default: ./oxsync.toml]
+    #[arg(long, short)]
+    config: Option<PathBuf>,
+    /// Name of a profile of the config file to sync, can be used multiple times
+    #[arg(long, short)]
+    profile: Vec<String>,
+    /// Additional directories to sync, formatted as <SOURCE_DIR>=<TARGET_DIR>, can be used
+    /// multiple times
+    #[arg(long)]
+    pair: Vec<String>,
+    /// Write the changes through the `oxsync agent` started by this command, like
+    /// `ssh host oxsync agent`. <TARGET_DIR> is then a path of the agent side
+    #[arg(long)]
+    agent_command: Option<String>,
+    /// Secret of the `oxsync serve` of a `tcp://<HOST>:<PORT>` <TARGET_DIR>
+    #[arg(long, env = "OXSYNC_TOKEN", hide_env_values = true)]
+    token: Option<String>,
+    /// Exclude paths of the <SOURCE_DIR> matching a gitignore-style pattern (`**/node_modules`,
+    /// `*.log`, `target/`, `!keep.log`), can be used multiple times
+    #[arg(long, short)]
+    exclude: Vec<String>,
+    /// Only sync paths of the <SOURCE_DIR> matching a gitignore-style pattern (`src/**`, `*.toml`),
+    /// can be used multiple times. Excluded paths stay excluded
     #[arg(long, short)]
-    exclude: Vec<PathBuf>,
+    include: Vec<String>,
+    /// Also exclude the paths ignored by `.gitignore` files and `.git/info/exclude`, `.oxsyncignore`
+    /// files are always used
+    #[arg(long)]
+    gitignore: bool,
     /// Exclude files with names ending by a tilde `~`
     #[arg(long, visible_alias("no-tmp"))]
     no_temporary_editor_files: bool,
@@ -44,53 +120,249 @@ pub struct Args {
     /// Display the time spent copying the file
     #[arg(long, visible_alias("stats"))]
     statistics: bool,
+    /// Skip the initial sync of the <SOURCE_DIR> content to the <TARGET_DIR>
+    #[arg(long, visible_alias("no-scan"))]
+    no_initial_sync: bool,
+    /// Delete files and dirs of the <TARGET_DIR> missing from the <SOURCE_DIR> during the initial sync
+    #[arg(long)]
+    delete: bool,
+    /// Only write the changed blocks of the files already on the <TARGET_DIR>, faster for big files
+    /// on slow targets
+    #[arg(long)]
+    delta: bool,
+    /// Flush the files written to the <TARGET_DIR> to the disk before moving them in place, slower
+    /// but they survive a power loss. Set on the `agent` and `serve` side for remote targets
+    #[arg(long)]
+    fsync: bool,
+    /// Directory where the index of the synced files is saved between runs, so the unchanged
+    /// files are skipped at startup [default: the `.oxsync` directory of the <TARGET_DIR>, or of
+    /// the <SOURCE_DIR> for remote targets]
+    #[arg(long)]
+    state_dir: Option<PathBuf>,
+    /// Wait until a path had no change for <MS> milliseconds before syncing it, its changes
+    /// merged into one. Useful with the bursts of events of editors and build tools [default: 0]
+    #[arg(long, value_name = "MS")]
+    settle: Option<u64>,
+    /// Sync up to <N> changes at the same time, so a big file doesn't hold back the others. The
+    /// changes of a path and of the dirs containing it stay in order [default: 4]
+    #[arg(long, value_name = "N")]
+    concurrency: Option<usize>,
+    /// Also sync the changes made in the <TARGET_DIR> back to the <SOURCE_DIR>. A file changed on
+    /// both sides since the last sync is a conflict, the <TARGET_DIR> copy is kept next to it as
+    /// `<NAME>.conflict-<TIMESTAMP>`. Local <TARGET_DIR> only
+    #[arg(long)]
+    two_way: bool,
+    /// What to do when a file of the <TARGET_DIR> was changed since oxsync last wrote it: `overwrite`
+    /// it with a warning, `skip` it with a warning, or `backup` it as `<NAME>.backup-<TIMESTAMP>`
+    /// first [default: overwrite]
+    #[arg(long, value_name = "POLICY", hide_possible_values = true)]
+    on_target_change: Option<TargetChangePolicyArg>,
+    /// Give the attributes of the <SOURCE_DIR> paths to the <TARGET_DIR> ones after each copy and
+    /// dir creation, a comma-separated list of `times`, `perms`, `owner` (when running as root),
+    /// `xattrs` or `all`
+    #[arg(
+        long,
+        value_name = "ATTRS",
+        value_delimiter = ',',
+        hide_possible_values = true
+    )]
+    preserve: Vec<PreserveArg>,
+    /// What to do with the symlinks of the <SOURCE_DIR>: `follow` them and sync what they point
+    /// to, `preserve` them as they are, recreate them with the absolute links into the
+    /// <SOURCE_DIR> made `relative`, or `skip` them [default: follow]
+    #[arg(long, value_name = "POLICY", hide_possible_values = true)]
+    symlinks: Option<SymlinkPolicyArg>,
+    /// Recreate the hard links between the files of the <SOURCE_DIR> on the <TARGET_DIR>, instead
+    /// of copying each one of them. Unix <SOURCE_DIR> only, not with `--two-way`
+    #[arg(long)]
+    hard_links: bool,
+    /// Move the files and dirs deleted from the <TARGET_DIR> to a `.oxsync-trash/<YYYY-MM-DD>`
+    /// directory of the <TARGET_DIR>, named after the day they were deleted, instead of removing
+    /// them for good. With `--two-way`, the <SOURCE_DIR> gets its own trash
+    #[arg(long)]
+    trash: bool,
+    /// Purge the days of the trash older than <DAYS> days, at startup and then every hour
+    /// [default: 30]
+    #[arg(long, value_name = "DAYS")]
+    trash_retention: Option<u64>,
     /// Set the log level to trace
     #[arg(long)]
     trace: bool,
 }
 
-pub static LOG_TRACE: OnceCell<bool> = OnceCell::const_new();
+/// The values of `--on-target-change`, the library types don't depend on clap
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum TargetChangePolicyArg {
+    Overwrite,
+    Skip,
+    Backup,
+}
+
+impl From<TargetChangePolicyArg> for TargetChangePolicy {
+    fn from(policy: TargetChangePolicyArg) -> Self {
+        match policy {
+            TargetChangePolicyArg::Overwrite => TargetChangePolicy::Overwrite,
+            TargetChangePolicyArg::Skip => TargetChangePolicy::Skip,
+            TargetChangePolicyArg::Backup => TargetChangePolicy::Backup,
+        }
+    }
+}
+
+/// The values of `--preserve`
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum PreserveArg {
+    Times,
+    Perms,
+    Owner,
+    Xattrs,
+    All,
+}
+
+impl From<PreserveArg> for Preserve {
+    fn from(attribute: PreserveArg) -> Self {
+        match attribute {
+            PreserveArg::Times => Preserve::Times,
+            PreserveArg::Perms => Preserve::Perms,
+            PreserveArg::Owner => Preserve::Owner,
+            PreserveArg::Xattrs => Preserve::Xattrs,
+            PreserveArg::All => Preserve::All,
+        }
+    }
+}
+
+/// The values of `--symlinks`
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum SymlinkPolicyArg {
+    Follow,
+    Preserve,
+    Relative,
+    Skip,
+}
 
-#[cfg(not(windows))]
-compile_error!("non-windows targets aren't supported on this version");
+impl From<SymlinkPolicyArg> for SymlinkPolicy {
+    fn from(policy: SymlinkPolicyArg) -> Self {
+        match policy {
+            SymlinkPolicyArg::Follow => SymlinkPolicy::Follow,
+            SymlinkPolicyArg::Preserve => SymlinkPolicy::Preserve,
+            SymlinkPolicyArg::Relative => SymlinkPolicy::Relative,
+            SymlinkPolicyArg::Skip => SymlinkPolicy::Skip,
+        }
+    }
+}
 
 #[tokio::main]
-async fn main() {
-    Start::parse_args().await;
-    if let Err(e) = init_event_loop().await {
-        err!("{}", e);
-    };
-}
-
-async fn init_event_loop() -> notify::Result<()> {
-    let (mut watcher, mut rx) = Start::fs_watcher()?;
-
-    // Add a path to be watched. All files and directories at that path and
-    // below will be monitored for changes.
-    watcher.watch(&Utils::args().source_dir, RecursiveMode::Recursive)?;
-
-    let mut file_store: LruCache<PathBuf, PathMetadata> = LruCache::with_hasher(
-        NonZeroUsize::new(32_768).unwrap(),
-        BuildHasherDefault::<AHasher>::default(),
-    );
-
-    let mut rename_from: Option<PathBuf> = None;
-
-    info!(
-        "Ready - Waiting for changes on '{}'",
-        Utils::fmt_path(&Utils::args().source_dir)
-    );
-    while let Some(res) = rx.next().await {
-        match res {
-            Ok(event) => {
-                let emit_time = Instant::now();
-                trace!("{:?}", event);
-
-                Utils::handle_event(event, &mut file_store, emit_time, &mut rename_from).await
+async fn main() -> ExitCode {
+    let (mode, engines) = Start::parse_args().await;
+
+    match mode {
+        Mode::Watch => watch(engines).await,
+        Mode::Sync => sync_once(engines).await,
+        Mode::Agent(target) => agent(target).await,
+        Mode::Serve(listen, token, target) => serve(listen, token, target).await,
+    }
+}
+
+async fn serve(listen: String, token: String, target: LocalTarget) -> ExitCode {
+    if !target.root().is_dir() {
+        err!("target dir : '{}' does not exists", target.root().display());
+        return ExitCode::FAILURE;
+    }
+
+    if let Err(e) = Agent::listen(&listen, target, &token).await {
+        err!("serve error: {}", e);
+        return ExitCode::FAILURE;
+    }
+
+    ExitCode::SUCCESS
+}
+
+async fn agent(target: LocalTarget) -> ExitCode {
+    if !target.root().is_dir() {
+        err!("target dir : '{}' does not exists", target.root().display());
+        return ExitCode::FAILURE;
+    }
+
+    if let Err(e) = Agent::serve_stdio(target).await {
+        err!("agent error: {}", e);
+        return ExitCode::FAILURE;
+    }
+
+    ExitCode::SUCCESS
+}
+
+async fn sync_once(engines: Vec<SyncEngine>) -> ExitCode {
+    let mut exit_code = ExitCode::SUCCESS;
+
+    for engine in engines {
+        let summary = engine.sync_once().await;
+        if summary.failed > 0 {
+            exit_code = ExitCode::FAILURE;
+        }
+    }
+
+    exit_code
+}
+
+async fn watch(engines: Vec<SyncEngine>) -> ExitCode {
+    let engines: Vec<_> = engines.into_iter().map(Arc::new).collect();
+    let mut event_loops = JoinSet::new();
+    for engine in &engines {
+        let engine = engine.clone();
+        event_loops.spawn(async move {
+            let result = engine.run().await;
+            if let Err(e) = &result {
+                match engine.label() {
+                    Some(label) => err!("[{}] {}", label, e),
+                    None => err!("{}", e),
+                }
+            }
+            result
+        });
+    }
+
+    let mut exit_code = ExitCode::SUCCESS;
+    let mut stopping = false;
+    loop {
+        tokio::select! {
+            result = event_loops.join_next() => match result {
+                Some(result) => {
+                    if !matches!(result, Ok(Ok(()))) {
+                        exit_code = ExitCode::FAILURE;
+                    }
+                }
+                None => break,
+            },
+            _ = stop_signal(), if !stopping => {
+                stopping = true;
+                info!("Stopping - Syncing the pending changes");
+                // The engines sync the changes they already got and save their index
+                for engine in &engines {
+                    engine.shutdown().await;
+                }
             }
-            Err(e) => err!("watch error: {:?}", e),
         }
     }
 
-    Ok(())
+    exit_code
+}
+
+/// Ctrl-C, or SIGTERM on Unix
+async fn stop_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            },
+            Err(e) => {
+                err!("signal error: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
 }
@@ -0,0 +1,152 @@
+use std::path::Path;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+
+use tokio::fs;
+use tokio::time::Instant;
+
+use crate::config::{SymlinkPolicy, SyncConfig};
+use crate::filter::{Filter, FilterDecision};
+use crate::target::SyncTarget;
+use crate::utils::PathType;
+use crate::{err, info};
+
+/// A source dir synced to a target dir, with its own settings and filter
+#[derive(Debug)]
+pub struct SyncPair {
+    pub config: SyncConfig,
+    /// Prefix of the log lines, to tell the pairs apart when there are several
+    pub label: Option<String>,
+    pub target: Arc<dyn SyncTarget>,
+    filter: RwLock<Filter>,
+}
+
+impl SyncPair {
+    pub fn new(
+        config: SyncConfig,
+        label: Option<String>,
+        target: Arc<dyn SyncTarget>,
+    ) -> Result<Self, ignore::Error> {
+        let filter = Filter::new(&config.source_dir, config.filter.clone())?;
+
+        Ok(Self {
+            config,
+            label,
+            target,
+            filter: RwLock::new(filter),
+        })
+    }
+
+    pub fn filter(&self) -> RwLockReadGuard<'_, Filter> {
+        self.filter.read().unwrap()
+    }
+
+    pub async fn reload_filter(&self) {
+        // The filter isn't locked while it is reloaded
+        let reload = self.filter().reload();
+        match reload.await {
+            Ok(filter) => {
+                *self.filter.write().unwrap() = filter;
+                info!("{}ignore files reloaded", self.log_prefix());
+            }
+            Err(err) => err!(
+                "{}failed to reload ignore files, error: {}",
+                self.log_prefix(),
+                err
+            ),
+        }
+    }
+
+    pub fn is_synced(&self, relative_path: &Path, is_dir: bool) -> bool {
+        self.filter().decision(relative_path, is_dir) == FilterDecision::Included
+    }
+
+    pub fn relative_path<'a>(&self, v_path: &'a Path) -> &'a Path {
+        v_path.strip_prefix(&self.config.source_dir).unwrap()
+    }
+
+    /// The type the source path is synced as under the symlink policy, `None` when it doesn't
+    /// exist, isn't a file or a dir, or is a skipped or broken symlink
+    pub async fn source_path_type(&self, v_path: &Path) -> Option<PathType> {
+        let mut metadata = fs::symlink_metadata(v_path).await.ok()?;
+        if metadata.is_symlink() {
+            match self.config.symlinks {
+                SymlinkPolicy::Follow => metadata = fs::metadata(v_path).await.ok()?,
+                SymlinkPolicy::Preserve | SymlinkPolicy::Relative => {
+                    return Some(PathType::Symlink)
+                }
+                SymlinkPolicy::Skip => return None,
+            }
+        }
+
+        if metadata.is_dir() {
+            Some(PathType::Dir)
+        } else if metadata.is_file() {
+            Some(PathType::File)
+        } else {
+            None
+        }
+    }
+
+    /// Whether the source path is reached through a symlink which isn't followed, the watcher
+    /// reports the changes of the dirs they point to under them too
+    pub fn is_inside_symlink(&self, v_path: &Path) -> bool {
+        let Ok(relative_path) = v_path.strip_prefix(&self.config.source_dir) else {
+            return false;
+        };
+
+        self.config.symlinks != SymlinkPolicy::Follow
+            && relative_path
+                .ancestors()
+                .skip(1)
+                .filter(|dir| !dir.as_os_str().is_empty())
+                .any(|dir| self.config.source_dir.join(dir).is_symlink())
+    }
+
+    /// The type of the path on the target, `None` when it doesn't exist
+    pub async fn target_path_type(&self, relative_path: &Path) -> Option<PathType> {
+        self.target
+            .stat(relative_path)
+            .await
+            .ok()
+            .map(|metadata| metadata.path_type)
+    }
+
+    pub fn log_prefix(&self) -> String {
+        self.label
+            .as_ref()
+            .map_or_else(String::new, |label| format!("[{}] ", label))
+    }
+
+    pub fn print_action(
+        &self,
+        action_verb: &str,
+        type_path: &str,
+        path_str: &str,
+        emit_time: &Instant,
+    ) {
+        let prefix = self.log_prefix();
+
+        if self.config.statistics {
+            let elapsed = emit_time.elapsed().as_micros();
+            if elapsed >= 1000 {
+                info!(
+                    "{}{} '{}' {} in {} ms",
+                    prefix,
+                    type_path,
+                    path_str,
+                    action_verb,
+                    elapsed / 1000
+                );
+            } else {
+                info!(
+                    "{}{} '{}' {} in {} μs",
+                    prefix, type_path, path_str, action_verb, elapsed
+                );
+            }
+
+            return;
+        }
+
+        info!("{}{} '{}' {}", prefix, type_path, path_str, action_verb);
+    }
+}
@@ -0,0 +1,368 @@
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::io::ErrorKind;
+#[cfg(unix)]
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Component, Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::attributes::Attributes;
+use crate::target::TargetMetadata;
+use crate::utils::PathType;
+
+/// The highest version of the protocol, sent along with the lowest one still supported during
+/// the handshake. Version 2 adds the requests of the delta mode, version 3 the preserved attributes,
+/// version 4 the symlinks, version 5 the hard links and version 6 the authentication
+pub(crate) const PROTOCOL_VERSION: u32 = 6;
+pub(crate) const MIN_PROTOCOL_VERSION: u32 = 1;
+pub(crate) const DELTA_PROTOCOL_VERSION: u32 = 2;
+pub(crate) const ATTRIBUTES_PROTOCOL_VERSION: u32 = 3;
+/// Older agents follow the symlinks of their root, they never report them
+pub(crate) const SYMLINK_PROTOCOL_VERSION: u32 = 4;
+pub(crate) const HARD_LINK_PROTOCOL_VERSION: u32 = 5;
+pub(crate) const AUTH_PROTOCOL_VERSION: u32 = 6;
+
+/// Size of the `WriteChunk` requests
+pub(crate) const CHUNK_SIZE: usize = 1024 * 1024;
+/// Larger frames are refused before their payload is read, a corrupted or hostile length can't
+/// make the other side allocate gigabytes
+pub(crate) const MAX_FRAME_SIZE: u32 = 64 * 1024 * 1024;
+
+/// Each request gets a response with the same id, once the agent is done with it
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Envelope<T> {
+    pub id: u64,
+    pub body: T,
+}
+
+/// Operations sent to an agent, the paths are relative to its root and use `/` separators. The
+/// paths, names and links are sent as bytes, they may not be valid UTF-8. Bincode encodes them
+/// like strings, the agents of the same version which expect strings still read the UTF-8 ones
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum Request {
+    /// First request of a connection, answered by `Welcome` with the version chosen by the agent
+    Hello {
+        min_version: u32,
+        max_version: u32,
+    },
+    /// Second request of the connections to an `oxsync serve`, which refuses the other requests
+    /// until the token it was started with is given
+    Authenticate {
+        token: String,
+    },
+    /// Start the transfer of a file of `len` bytes, answered by `Resume` with the number of bytes
+    /// the agent already received from a previous attempt with the same `transfer_id`
+    WriteStart {
+        path: Vec<u8>,
+        len: u64,
+        transfer_id: String,
+    },
+    WriteChunk {
+        offset: u64,
+        data: Vec<u8>,
+    },
+    /// Move the transferred file to its path, once all its bytes are received
+    WriteEnd,
+    CreateDir {
+        path: Vec<u8>,
+    },
+    RemoveFile {
+        path: Vec<u8>,
+    },
+    RemoveDir {
+        path: Vec<u8>,
+    },
+    Rename {
+        from: Vec<u8>,
+        to: Vec<u8>,
+    },
+    Stat {
+        path: Vec<u8>,
+    },
+    Hash {
+        path: Vec<u8>,
+    },
+    ReadDir {
+        path: Vec<u8>,
+    },
+    BlockHashes {
+        path: Vec<u8>,
+        block_size: u64,
+    },
+    /// Overwrite the bytes at `offset` of a copy of an existing file, the copy starting with the
+    /// first block of the file
+    WriteBlock {
+        path: Vec<u8>,
+        offset: u64,
+        data: Vec<u8>,
+    },
+    /// Set the length of the copy patched by `WriteBlock`, then move it over the file
+    SetLen {
+        path: Vec<u8>,
+        len: u64,
+    },
+    SetAttributes {
+        path: Vec<u8>,
+        attributes: Attributes,
+    },
+    /// `link` is sent as it is, only the links staying in the root of the agent are created
+    CreateSymlink {
+        path: Vec<u8>,
+        link: Vec<u8>,
+    },
+    ReadLink {
+        path: Vec<u8>,
+    },
+    HardLink {
+        path: Vec<u8>,
+        original: Vec<u8>,
+    },
+    SameFile {
+        path: Vec<u8>,
+        other: Vec<u8>,
+    },
+}
+
+/// Answer of the agent to each request, in the same order
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) enum Response {
+    Welcome { version: u32 },
+    Resume { offset: u64 },
+    Done,
+    Stat(TargetMetadata),
+    Hash([u8; 32]),
+    Entries(Vec<(Vec<u8>, PathType)>),
+    BlockHashes(Vec<[u8; 32]>),
+    Link(Vec<u8>),
+    SameFile(bool),
+    Error { not_found: bool, message: String },
+}
+
+impl Request {
+    /// Whether the request can be sent again on a new connection when the previous one was lost
+    /// before its response. The agent may have applied the others already, or lost the state
+    /// they depend on with the connection
+    pub fn is_idempotent(&self) -> bool {
+        !matches!(
+            self,
+            Request::Rename { .. }
+                | Request::RemoveFile { .. }
+                | Request::RemoveDir { .. }
+                | Request::CreateSymlink { .. }
+                | Request::HardLink { .. }
+                | Request::WriteBlock { .. }
+                | Request::SetLen { .. }
+        )
+    }
+}
+
+impl Response {
+    pub fn from_err(err: io::Error) -> Self {
+        Response::Error {
+            not_found: err.kind() == ErrorKind::NotFound,
+            message: err.to_string(),
+        }
+    }
+}
+
+/// Each frame is the length of the message as a big-endian `u32`, followed by the message
+/// serialized with bincode
+pub(crate) async fn write_frame<W, T>(writer: &mut W, message: &T) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+    T: Serialize,
+{
+    let payload = bincode::serialize(message).map_err(io::Error::other)?;
+    let len = u32::try_from(payload.len())
+        .ok()
+        .filter(|len| *len <= MAX_FRAME_SIZE)
+        .ok_or_else(|| io::Error::other("message too large to be sent"))?;
+
+    writer.write_u32(len).await?;
+    writer.write_all(&payload).await?;
+    writer.flush().await
+}
+
+/// `None` when the connection is closed between two frames
+pub(crate) async fn read_frame<R, T>(reader: &mut R) -> io::Result<Option<T>>
+where
+    R: AsyncRead + Unpin,
+    T: DeserializeOwned,
+{
+    let len = match reader.read_u32().await {
+        Ok(len) => len,
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    if len > MAX_FRAME_SIZE {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "frame of {} bytes larger than {} bytes",
+                len, MAX_FRAME_SIZE
+            ),
+        ));
+    }
+
+    let mut payload = vec![0; len as usize];
+    reader.read_exact(&mut payload).await?;
+    bincode::deserialize(&payload)
+        .map(Some)
+        .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+}
+
+/// The components joined by `/`
+pub(crate) fn encode_path(path: &Path) -> Vec<u8> {
+    path.components()
+        .map(|component| encode_os_str(component.as_os_str()))
+        .collect::<Vec<_>>()
+        .join(&b'/')
+}
+
+/// Paths leaving the root of the agent are refused
+pub(crate) fn decode_path(path: &[u8]) -> io::Result<PathBuf> {
+    let mut decoded = PathBuf::new();
+    for part in path
+        .split(|byte| *byte == b'/')
+        .filter(|part| !part.is_empty())
+    {
+        let part = decode_os_str(part)?;
+        let mut components = Path::new(&part).components();
+        match (components.next(), components.next()) {
+            (Some(Component::Normal(name)), None) => decoded.push(name),
+            _ => {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("invalid path '{}'", String::from_utf8_lossy(path)),
+                ))
+            }
+        }
+    }
+
+    Ok(decoded)
+}
+
+/// The bytes of the name on Unix. Elsewhere the names are sent as UTF-8, their unpaired
+/// surrogates replaced by `U+FFFD`
+pub(crate) fn encode_os_str(name: &OsStr) -> Vec<u8> {
+    #[cfg(unix)]
+    return name.as_bytes().to_vec();
+    #[cfg(not(unix))]
+    return name.to_string_lossy().into_owned().into_bytes();
+}
+
+/// Outside of Unix, only the UTF-8 names are accepted
+pub(crate) fn decode_os_str(bytes: &[u8]) -> io::Result<OsString> {
+    #[cfg(unix)]
+    return Ok(OsString::from_vec(bytes.to_vec()));
+    #[cfg(not(unix))]
+    return String::from_utf8(bytes.to_vec())
+        .map(OsString::from)
+        .map_err(|err| io::Error::new(ErrorKind::InvalidData, err));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn round_trip(request: Request) -> Request {
+        let mut buffer = Vec::new();
+        write_frame(
+            &mut buffer,
+            &Envelope {
+                id: 7,
+                body: request,
+            },
+        )
+        .await
+        .unwrap();
+        let envelope: Envelope<Request> =
+            read_frame(&mut buffer.as_slice()).await.unwrap().unwrap();
+        assert_eq!(envelope.id, 7);
+        envelope.body
+    }
+
+    #[test]
+    fn paths_round_trip() {
+        for path in ["", "f", "dir/sub/f", "dir/ü f"] {
+            let encoded = encode_path(Path::new(path));
+            assert_eq!(encoded, path.as_bytes());
+            assert_eq!(decode_path(&encoded).unwrap(), PathBuf::from(path));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_paths_round_trip() {
+        let path = PathBuf::from(OsStr::from_bytes(b"dir/caf\xe9"));
+        let encoded = encode_path(&path);
+
+        assert_eq!(encoded, b"dir/caf\xe9");
+        assert_eq!(decode_path(&encoded).unwrap(), path);
+    }
+
+    #[test]
+    fn paths_leaving_the_root_are_refused() {
+        for path in ["..", "dir/../../f", "dir/./f"] {
+            let err = decode_path(path.as_bytes()).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput, "{}", path);
+        }
+        // The empty components are skipped, absolute paths stay in the root
+        assert_eq!(decode_path(b"/dir//f").unwrap(), PathBuf::from("dir/f"));
+    }
+
+    #[tokio::test]
+    async fn frames_round_trip() {
+        let request = round_trip(Request::Rename {
+            from: b"a".to_vec(),
+            to: b"dir/b".to_vec(),
+        })
+        .await;
+        assert!(matches!(
+            request,
+            Request::Rename { from, to } if from == b"a" && to == b"dir/b"
+        ));
+
+        let data = vec![1; CHUNK_SIZE];
+        let request = round_trip(Request::WriteChunk {
+            offset: 3,
+            data: data.clone(),
+        })
+        .await;
+        assert!(matches!(
+            request,
+            Request::WriteChunk { offset: 3, data: received } if received == data
+        ));
+    }
+
+    #[tokio::test]
+    async fn closed_connection_between_frames() {
+        let frame = read_frame::<_, Envelope<Request>>(&mut [].as_slice()).await;
+        assert!(frame.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn frames_larger_than_the_max_are_refused() {
+        let mut buffer = (MAX_FRAME_SIZE + 1).to_be_bytes().to_vec();
+        buffer.extend_from_slice(&[0; 16]);
+        let err = read_frame::<_, Envelope<Request>>(&mut buffer.as_slice())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn only_idempotent_requests_are_retried() {
+        let stat = Request::Stat { path: Vec::new() };
+        let rename = Request::Rename {
+            from: b"a".to_vec(),
+            to: b"b".to_vec(),
+        };
+
+        assert!(stat.is_idempotent());
+        assert!(!rename.is_idempotent());
+    }
+}
@@ -0,0 +1,669 @@
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::fs::Metadata;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use tokio::fs;
+use tokio::time::Instant;
+
+use crate::file_operations::handle_remove_err;
+use crate::filter::FilterDecision;
+use crate::pair::SyncPair;
+use crate::trash::Trash;
+use crate::utils::{FileStore, PathMetadata, PathType, Utils};
+use crate::{err, info};
+
+pub(crate) struct Scanner;
+
+#[derive(Debug, Default)]
+pub struct ScanSummary {
+    pub copied: usize,
+    pub created: usize,
+    pub deleted: usize,
+    pub identical: usize,
+    /// Changed on both sides of a two-way sync
+    pub conflicts: usize,
+    pub failed: usize,
+}
+
+impl ScanSummary {
+    /// Number of paths written to the target
+    fn written(&self) -> usize {
+        self.copied + self.created + self.deleted
+    }
+}
+
+impl Scanner {
+    /// Walk the source dir and make the target dir match it, filling the file store on the way.
+    /// The paths of the file store which weren't found are removed from it
+    pub async fn reconcile(pair: &SyncPair, file_store: &FileStore) -> ScanSummary {
+        let start_time = Instant::now();
+        let mut synced_paths = HashSet::new();
+        let source_dir = pair.config.source_dir.clone();
+        let summary = Self::reconcile_tree(pair, file_store, source_dir, &mut synced_paths).await;
+        file_store.retain(|path, _| synced_paths.contains(path));
+
+        info!(
+            "{}Sync done in {} ms - {} copied, {} dirs created, {} deleted, {} identical, {} failed",
+            pair.log_prefix(),
+            start_time.elapsed().as_millis(),
+            summary.copied,
+            summary.created,
+            summary.deleted,
+            summary.identical,
+            summary.failed
+        );
+
+        summary
+    }
+
+    /// Make the target match the content of the source dir `v_path`, like a dir moved into the
+    /// source dir, which comes without events for its content
+    pub async fn reconcile_dir(pair: &SyncPair, file_store: &FileStore, v_path: &Path) {
+        Self::reconcile_tree(pair, file_store, v_path.to_path_buf(), &mut HashSet::new()).await;
+    }
+
+    /// Walk the source dir `dir`, the paths found synced are added to `synced_paths`
+    async fn reconcile_tree(
+        pair: &SyncPair,
+        file_store: &FileStore,
+        dir: PathBuf,
+        synced_paths: &mut HashSet<PathBuf>,
+    ) -> ScanSummary {
+        let mut summary = ScanSummary::default();
+        // Along with the dirs the followed symlinks on the way point to
+        let mut dirs_to_visit = vec![(dir.clone(), vec![dir])];
+        // Their entries were written, their preserved times must be given again
+        let mut changed_dirs = Vec::new();
+
+        while let Some((dir, followed_dirs)) = dirs_to_visit.pop() {
+            let mut entries = match fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(err) => {
+                    err!(
+                        "{}failed to read dir '{}', error: {}",
+                        pair.log_prefix(),
+                        Utils::fmt_path(&dir),
+                        err.to_string()
+                    );
+                    summary.failed += 1;
+                    continue;
+                }
+            };
+
+            let mut source_names = HashSet::new();
+            let written = summary.written();
+            loop {
+                let entry = match entries.next_entry().await {
+                    Ok(Some(entry)) => entry,
+                    Ok(None) => break,
+                    Err(err) => {
+                        err!(
+                            "{}failed to read dir '{}', error: {}",
+                            pair.log_prefix(),
+                            Utils::fmt_path(&dir),
+                            err.to_string()
+                        );
+                        summary.failed += 1;
+                        break;
+                    }
+                };
+                source_names.insert(entry.file_name());
+                let emit_time = Instant::now();
+
+                let v_path = Utils::path_to_verbatim(&entry.path());
+                let relative_path = pair.relative_path(&v_path);
+                let path_str = &Utils::fmt_path(relative_path);
+                let Some(path_type) = pair.source_path_type(&v_path).await else {
+                    continue;
+                };
+                let mut followed_dirs = followed_dirs.clone();
+                if path_type == PathType::Dir
+                    && Utils::is_symlink_loop(pair, &v_path, &mut followed_dirs).await
+                {
+                    continue;
+                }
+
+                match pair
+                    .filter()
+                    .decision(relative_path, path_type == PathType::Dir)
+                {
+                    FilterDecision::Included => {}
+                    FilterDecision::NotIncluded if path_type == PathType::Dir => {
+                        // Included paths may still be found below
+                        dirs_to_visit.push((v_path, followed_dirs));
+                        continue;
+                    }
+                    _ => continue,
+                }
+                synced_paths.insert(v_path.clone());
+
+                if path_type == PathType::Symlink {
+                    Self::reconcile_symlink(pair, file_store, &mut summary, &v_path, emit_time)
+                        .await;
+                    continue;
+                }
+                // What the followed symlinks point to
+                let Ok(src_metadata) = fs::metadata(&v_path).await else {
+                    continue;
+                };
+
+                if src_metadata.is_dir() {
+                    if pair.target_path_type(relative_path).await != Some(PathType::Dir) {
+                        if Self::remove_mismatched(pair, &mut summary, relative_path, PathType::Dir)
+                            .await
+                            .is_err()
+                            || Utils::create_dirs(pair, relative_path, path_str, &emit_time, false)
+                                .await
+                                .is_err()
+                        {
+                            summary.failed += 1;
+                            continue;
+                        }
+                        summary.created += 1;
+                    }
+                    file_store.insert(v_path.clone(), PathMetadata::dir());
+                    dirs_to_visit.push((v_path, followed_dirs));
+                } else if src_metadata.is_file() {
+                    Self::reconcile_file(
+                        pair,
+                        file_store,
+                        &mut summary,
+                        &v_path,
+                        &src_metadata,
+                        emit_time,
+                    )
+                    .await;
+                }
+            }
+
+            if pair.config.delete {
+                Self::delete_extraneous(pair, &dir, &source_names, &mut summary).await;
+            }
+            if summary.written() != written {
+                changed_dirs.push(pair.relative_path(&dir).to_path_buf());
+            }
+        }
+        Utils::restore_dir_times(pair, changed_dirs).await;
+
+        summary
+    }
+
+    async fn reconcile_file(
+        pair: &SyncPair,
+        file_store: &FileStore,
+        summary: &mut ScanSummary,
+        v_path: &Path,
+        src_metadata: &Metadata,
+        emit_time: Instant,
+    ) {
+        if let Some(original) = Utils::hard_link_of(pair, file_store, v_path, src_metadata) {
+            if Self::reconcile_hard_link(pair, file_store, summary, v_path, &original, emit_time)
+                .await
+            {
+                return;
+            }
+        }
+
+        let relative_path = pair.relative_path(v_path);
+        let path_str = &Utils::fmt_path(relative_path);
+        let dirs = relative_path.parent().unwrap();
+        // The content is known from the index while the source file is unchanged since then
+        let indexed = file_store.get(v_path).filter(|path_metadata| {
+            path_metadata.hash.is_some()
+                && path_metadata.len == src_metadata.len()
+                && path_metadata.modified.is_some()
+                && path_metadata.modified == src_metadata.modified().ok()
+                && (!pair.config.delta || path_metadata.blocks.is_some())
+        });
+        // Outside of delta mode, the hash of a copied file comes from the copy itself
+        let (mut src_hash, src_blocks) = match &indexed {
+            Some(path_metadata) => (path_metadata.hash, path_metadata.blocks.clone()),
+            None if pair.config.delta => Utils::hash_source(pair, v_path).await,
+            None => (None, None),
+        };
+        let known = file_store.get(v_path);
+
+        let dest_metadata = pair
+            .target
+            .stat(relative_path)
+            .await
+            .ok()
+            .filter(|dest_metadata| dest_metadata.path_type == PathType::File);
+        let is_identical = match &dest_metadata {
+            Some(dest_metadata) => {
+                // Not modified on the target since the indexed content was written there
+                let unchanged_since_indexed = indexed.as_ref().is_some_and(|path_metadata| {
+                    path_metadata.target_hash == path_metadata.hash
+                        && path_metadata.target_unchanged(dest_metadata)
+                });
+
+                if dest_metadata.len != src_metadata.len() {
+                    false
+                } else if dest_metadata.modified == src_metadata.modified().ok()
+                    || unchanged_since_indexed
+                {
+                    true
+                } else {
+                    if src_hash.is_none() {
+                        src_hash = Utils::hash_file(v_path).await;
+                    }
+                    src_hash.is_some() && src_hash == pair.target.hash(relative_path).await.ok()
+                }
+            }
+            None => false,
+        };
+
+        let mut known_blocks = known
+            .as_ref()
+            .and_then(|path_metadata| path_metadata.blocks.clone());
+        if let Some(known) = known.as_ref().filter(|_| !is_identical) {
+            match Utils::check_target_change(pair, known, relative_path, path_str).await {
+                // The blocks written last time aren't on the target anymore
+                Ok(true) => known_blocks = None,
+                Ok(false) => {}
+                Err(()) => return,
+            }
+        }
+
+        // The parent dirs aren't created by the scan when they aren't included themselves
+        if !is_identical
+            && pair.target_path_type(dirs).await.is_none()
+            && Utils::create_dirs(pair, dirs, path_str, &emit_time, true)
+                .await
+                .is_err()
+        {
+            summary.failed += 1;
+            return;
+        }
+        if !is_identical
+            && Self::remove_mismatched(pair, summary, relative_path, PathType::File)
+                .await
+                .is_err()
+        {
+            return;
+        }
+
+        let (target_len, target_modified) = if is_identical {
+            summary.identical += 1;
+            if src_hash.is_none() {
+                src_hash = Utils::hash_file(v_path).await;
+            }
+            dest_metadata.map_or((0, None), |dest_metadata| {
+                (dest_metadata.len, dest_metadata.modified)
+            })
+        } else {
+            let copied = Utils::copy_file(
+                pair,
+                v_path,
+                relative_path,
+                path_str,
+                emit_time,
+                src_blocks.as_ref(),
+                known_blocks.as_ref(),
+            )
+            .await;
+            match copied {
+                Ok(copied_hash) => {
+                    summary.copied += 1;
+                    src_hash = copied_hash.or(src_hash);
+                }
+                Err(()) => {
+                    summary.failed += 1;
+                    return;
+                }
+            }
+            Utils::target_stamp(pair, relative_path).await
+        };
+
+        file_store.insert(
+            v_path.to_path_buf(),
+            PathMetadata {
+                len: src_metadata.len(),
+                modified: src_metadata.modified().ok(),
+                target_len,
+                target_modified,
+                blocks: src_blocks,
+                file_id: Utils::file_id(src_metadata),
+                ..PathMetadata::file(PathType::File, src_hash)
+            },
+        );
+    }
+
+    /// Link the target file to the one of `original`, a hard link of the same source file synced
+    /// already, unless it already is. `false` when it must be copied instead
+    async fn reconcile_hard_link(
+        pair: &SyncPair,
+        file_store: &FileStore,
+        summary: &mut ScanSummary,
+        v_path: &Path,
+        original: &Path,
+        emit_time: Instant,
+    ) -> bool {
+        let relative_path = pair.relative_path(v_path);
+        let path_str = &Utils::fmt_path(relative_path);
+        let dirs = relative_path.parent().unwrap();
+        let Some(mut path_metadata) = file_store.get(original) else {
+            return false;
+        };
+
+        let is_identical = pair
+            .target
+            .same_file(relative_path, pair.relative_path(original))
+            .await
+            .unwrap_or(false);
+        if is_identical {
+            summary.identical += 1;
+        } else {
+            if let Some(known) = file_store.get(v_path) {
+                if Utils::check_target_change(pair, &known, relative_path, path_str)
+                    .await
+                    .is_err()
+                {
+                    return true;
+                }
+            }
+            // The parent dirs aren't created by the scan when they aren't included themselves
+            if pair.target_path_type(dirs).await.is_none()
+                && Utils::create_dirs(pair, dirs, path_str, &emit_time, true)
+                    .await
+                    .is_err()
+            {
+                summary.failed += 1;
+                return true;
+            }
+            match Utils::link_file(pair, original, relative_path, path_str, emit_time).await {
+                Ok(true) => summary.copied += 1,
+                Ok(false) => return false,
+                Err(()) => {
+                    summary.failed += 1;
+                    return true;
+                }
+            }
+        }
+
+        // Same content as the original
+        path_metadata.last_change = SystemTime::now();
+        file_store.insert(v_path.to_path_buf(), path_metadata);
+        true
+    }
+
+    /// Recreate the symlink on the target unless it already points to the same path
+    async fn reconcile_symlink(
+        pair: &SyncPair,
+        file_store: &FileStore,
+        summary: &mut ScanSummary,
+        v_path: &Path,
+        emit_time: Instant,
+    ) {
+        let relative_path = pair.relative_path(v_path);
+        let path_str = &Utils::fmt_path(relative_path);
+        let dirs = relative_path.parent().unwrap();
+
+        let link = match fs::read_link(v_path).await {
+            Ok(link) => Utils::synced_link(pair, &pair.config.source_dir, relative_path, link),
+            Err(err) => {
+                err!(
+                    "{}failed to read symlink '{}', error: {}",
+                    pair.log_prefix(),
+                    path_str,
+                    err.to_string()
+                );
+                summary.failed += 1;
+                return;
+            }
+        };
+        let is_identical = pair
+            .target
+            .read_link(relative_path)
+            .await
+            .is_ok_and(|dest_link| dest_link == link);
+
+        if is_identical {
+            summary.identical += 1;
+        } else {
+            // The parent dirs aren't created by the scan when they aren't included themselves
+            if pair.target_path_type(dirs).await.is_none()
+                && Utils::create_dirs(pair, dirs, path_str, &emit_time, true)
+                    .await
+                    .is_err()
+            {
+                summary.failed += 1;
+                return;
+            }
+            if Self::remove_mismatched(pair, summary, relative_path, PathType::Symlink)
+                .await
+                .is_err()
+            {
+                return;
+            }
+            if Utils::copy_symlink(pair, v_path, relative_path, path_str, emit_time)
+                .await
+                .is_err()
+            {
+                summary.failed += 1;
+                return;
+            }
+            summary.copied += 1;
+        }
+
+        let hash = Utils::hash_link(&link);
+        file_store.insert(
+            v_path.to_path_buf(),
+            PathMetadata::file(PathType::Symlink, Some(hash)),
+        );
+    }
+
+    /// Remove the target path when it is a dir and the source one isn't, or the other way around,
+    /// the copy can't replace it. A file and a symlink replace each other
+    async fn remove_mismatched(
+        pair: &SyncPair,
+        summary: &mut ScanSummary,
+        relative_path: &Path,
+        path_type: PathType,
+    ) -> Result<(), ()> {
+        let Ok(dest_metadata) = pair.target.stat(relative_path).await else {
+            return Ok(());
+        };
+        let dest_type = dest_metadata.path_type;
+        if (dest_type == PathType::Dir) == (path_type == PathType::Dir) {
+            return Ok(());
+        }
+
+        let emit_time = Instant::now();
+        let path_str = &Utils::fmt_path(relative_path);
+        match Trash::remove(pair, pair.target.as_ref(), relative_path, &dest_type).await {
+            Ok(()) => {
+                pair.print_action("deleted", Self::type_str(&dest_type), path_str, &emit_time);
+                summary.deleted += 1;
+                Ok(())
+            }
+            Err(err) => {
+                handle_remove_err(pair, err, path_str, dest_type);
+                summary.failed += 1;
+                Err(())
+            }
+        }
+    }
+
+    fn type_str(path_type: &PathType) -> &'static str {
+        match path_type {
+            PathType::Dir => "dir",
+            PathType::Symlink => "symlink",
+            PathType::File => "file",
+        }
+    }
+
+    async fn delete_extraneous(
+        pair: &SyncPair,
+        src_dir: &Path,
+        source_names: &HashSet<OsString>,
+        summary: &mut ScanSummary,
+    ) {
+        let Ok(entries) = pair.target.read_dir(pair.relative_path(src_dir)).await else {
+            return;
+        };
+
+        for (name, path_type) in entries {
+            if source_names.contains(&name) {
+                continue;
+            }
+
+            let v_path = src_dir.join(name);
+            let relative_path = pair.relative_path(&v_path);
+            let path_str = &Utils::fmt_path(relative_path);
+
+            // Paths not synced from the source are left untouched on the target
+            if !pair.is_synced(relative_path, path_type == PathType::Dir) {
+                continue;
+            }
+            let emit_time = Instant::now();
+
+            let result = Trash::remove(pair, pair.target.as_ref(), relative_path, &path_type).await;
+            if let Err(err) = result {
+                handle_remove_err(pair, err, path_str, path_type);
+                summary.failed += 1;
+            } else {
+                pair.print_action("deleted", Self::type_str(&path_type), path_str, &emit_time);
+                summary.deleted += 1;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::config::SyncConfig;
+    use crate::target::{LocalTarget, MemoryEntry, MemoryTarget, SyncTarget, TRASH_DIR_NAME};
+
+    fn memory_pair(
+        source: &TempDir,
+        customize: impl FnOnce(&mut SyncConfig),
+    ) -> (SyncPair, Arc<MemoryTarget>) {
+        let mut config = SyncConfig::new(source.path().to_path_buf(), PathBuf::new());
+        customize(&mut config);
+        let target = Arc::new(MemoryTarget::new());
+        let pair = SyncPair::new(config, None, target.clone()).unwrap();
+        (pair, target)
+    }
+
+    async fn write_target(target: &MemoryTarget, path: &str, content: &[u8]) {
+        let src = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(src.path(), content).unwrap();
+        target
+            .write_file(Path::new(path), src.path())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn scan_copies_the_changed_paths_and_deletes_the_extraneous_ones() {
+        let source = TempDir::new().unwrap();
+        std::fs::create_dir(source.path().join("dir")).unwrap();
+        std::fs::write(source.path().join("dir/f"), b"new").unwrap();
+        std::fs::write(source.path().join("same"), b"same").unwrap();
+        let (pair, target) = memory_pair(&source, |config| config.delete = true);
+        write_target(&target, "same", b"same").await;
+        write_target(&target, "old", b"old").await;
+        let file_store = FileStore::default();
+
+        let summary = Scanner::reconcile(&pair, &file_store).await;
+        assert_eq!(
+            (
+                summary.copied,
+                summary.created,
+                summary.deleted,
+                summary.identical,
+                summary.failed
+            ),
+            (1, 1, 1, 1, 0)
+        );
+        assert_eq!(target.read(Path::new("dir/f")), Some(b"new".to_vec()));
+        assert!(target.stat(Path::new("old")).await.is_err());
+
+        let summary = Scanner::reconcile(&pair, &file_store).await;
+        assert_eq!(summary.written(), 0);
+        assert_eq!(summary.identical, 2);
+    }
+
+    #[tokio::test]
+    async fn scan_replaces_the_target_paths_of_another_type() {
+        let source = TempDir::new().unwrap();
+        std::fs::write(source.path().join("a"), b"file").unwrap();
+        std::fs::create_dir(source.path().join("b")).unwrap();
+        std::fs::write(source.path().join("b/f"), b"in dir").unwrap();
+        let (pair, target) = memory_pair(&source, |_| {});
+        target.create_dir(Path::new("a/sub")).await.unwrap();
+        write_target(&target, "b", b"file").await;
+        let file_store = FileStore::default();
+
+        let summary = Scanner::reconcile(&pair, &file_store).await;
+        assert_eq!(summary.failed, 0);
+        assert_eq!(summary.deleted, 2);
+        assert_eq!(target.read(Path::new("a")), Some(b"file".to_vec()));
+        assert_eq!(target.read(Path::new("b/f")), Some(b"in dir".to_vec()));
+        assert!(target.stat(Path::new("a/sub")).await.is_err());
+
+        let summary = Scanner::reconcile(&pair, &file_store).await;
+        assert_eq!((summary.written(), summary.failed), (0, 0));
+    }
+
+    #[tokio::test]
+    async fn mismatched_target_paths_are_moved_to_the_trash() {
+        let source = TempDir::new().unwrap();
+        std::fs::write(source.path().join("a"), b"file").unwrap();
+        let (pair, target) = memory_pair(&source, |config| config.trash = true);
+        target.create_dir(Path::new("a")).await.unwrap();
+        write_target(&target, "a/kept", b"kept").await;
+
+        let summary = Scanner::reconcile(&pair, &FileStore::default()).await;
+        assert_eq!(summary.failed, 0);
+        assert_eq!(target.read(Path::new("a")), Some(b"file".to_vec()));
+        let trashed = target
+            .snapshot()
+            .into_iter()
+            .find(|(path, _)| path.starts_with(TRASH_DIR_NAME) && path.ends_with("a/kept"));
+        assert!(matches!(trashed, Some((_, MemoryEntry::File { .. }))));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn hard_links_are_recreated_on_the_target() {
+        let (source, target) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        std::fs::create_dir(source.path().join("dir")).unwrap();
+        std::fs::write(source.path().join("f"), b"linked").unwrap();
+        std::fs::hard_link(source.path().join("f"), source.path().join("dir/g")).unwrap();
+        let mut config = SyncConfig::new(source.path().to_path_buf(), target.path().to_path_buf());
+        config.hard_links = true;
+        let local = Arc::new(LocalTarget::new(target.path().to_path_buf()));
+        let pair = SyncPair::new(config, None, local.clone()).unwrap();
+
+        let summary = Scanner::reconcile(&pair, &FileStore::default()).await;
+        assert_eq!(summary.failed, 0);
+        let same_file = local.same_file(Path::new("dir/g"), Path::new("f")).await;
+        assert!(same_file.unwrap());
+        assert_eq!(
+            std::fs::read(target.path().join("dir/g")).unwrap(),
+            b"linked"
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn hard_links_are_copied_to_the_targets_without_links() {
+        let source = TempDir::new().unwrap();
+        std::fs::write(source.path().join("f"), b"linked").unwrap();
+        std::fs::hard_link(source.path().join("f"), source.path().join("g")).unwrap();
+        let (pair, target) = memory_pair(&source, |config| config.hard_links = true);
+
+        let summary = Scanner::reconcile(&pair, &FileStore::default()).await;
+        assert_eq!((summary.copied, summary.failed), (2, 0));
+        assert_eq!(target.read(Path::new("g")), Some(b"linked".to_vec()));
+    }
+}
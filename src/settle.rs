@@ -0,0 +1,449 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use notify::event::{CreateKind, DataChange, ModifyKind, RemoveKind, RenameMode};
+use notify::{Event, EventKind};
+use tokio::time::Instant;
+
+/// Collects the events of each path until it has been quiet for the settle window, then gives
+/// them back merged into a single net operation
+pub(crate) struct Settler {
+    window: Duration,
+    pending: HashMap<PathBuf, Pending>,
+    /// First half of a rename, a removal unless the second half follows
+    rename_from: Option<(PathBuf, Instant)>,
+    next_seq: u64,
+}
+
+struct Pending {
+    op: NetOp,
+    due: Instant,
+    /// Order of the last event of the path, the operations are run in this order
+    seq: u64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum NetOp {
+    Create(CreateKind),
+    Modify(ModifyKind),
+    Remove(RemoveKind),
+    /// Renamed from `from`, whose content was modified afterwards when `modified` is set
+    Rename {
+        from: PathBuf,
+        modified: bool,
+    },
+}
+
+impl Settler {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            pending: HashMap::new(),
+            rename_from: None,
+            next_seq: 0,
+        }
+    }
+
+    /// Merge the event with the operations pending on its paths. Access events are dropped, as
+    /// well as the renames reported with both paths, inotify reports each half before them
+    pub fn push(&mut self, event: Event, now: Instant) {
+        if !matches!(
+            event.kind,
+            EventKind::Modify(ModifyKind::Name(RenameMode::To))
+        ) {
+            self.moved_out();
+        }
+
+        match event.kind {
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                for path in event.paths {
+                    self.rename_from = Some((path, now));
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                for path in event.paths {
+                    let op = match self.rename_from.take() {
+                        Some((from_path, _)) => {
+                            self.move_children(&from_path, &path);
+                            let prior = self.pending.remove(&from_path).map(|pending| pending.op);
+                            Self::renamed(from_path, prior, &path)
+                        }
+                        // Moved into the source dir
+                        None => Some(NetOp::Modify(ModifyKind::Any)),
+                    };
+                    match op {
+                        Some(op) => self.replace(path, op, now),
+                        None => {
+                            self.pending.remove(&path);
+                        }
+                    }
+                }
+            }
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) | EventKind::Access(_) => {}
+            EventKind::Create(kind) => {
+                for path in event.paths {
+                    self.merge(path, NetOp::Create(kind), now);
+                }
+            }
+            EventKind::Modify(kind) => {
+                for path in event.paths {
+                    self.merge(path, NetOp::Modify(kind), now);
+                }
+            }
+            EventKind::Remove(kind) => {
+                for path in event.paths {
+                    self.merge(path, NetOp::Remove(kind), now);
+                }
+            }
+            EventKind::Any | EventKind::Other => {
+                for path in event.paths {
+                    self.merge(path, NetOp::Modify(ModifyKind::Any), now);
+                }
+            }
+        }
+    }
+
+    /// When the next path is quiet for long enough
+    pub fn next_due(&self) -> Option<Instant> {
+        let rename_due = self
+            .rename_from
+            .as_ref()
+            .map(|(_, time)| *time + self.window);
+        self.pending
+            .values()
+            .map(|pending| pending.due)
+            .chain(rename_due)
+            .min()
+    }
+
+    /// The net events of the paths which are quiet for long enough, in the order of their last
+    /// change. The two paths of a rename are given back together
+    pub fn take_due(&mut self, now: Instant) -> Vec<Event> {
+        if self
+            .rename_from
+            .as_ref()
+            .is_some_and(|(_, time)| *time + self.window <= now)
+        {
+            self.moved_out();
+        }
+
+        let mut due_paths = self
+            .pending
+            .iter()
+            .filter(|(_, pending)| pending.due <= now)
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+
+        // The source of a rename can't be changed before the rename is done
+        let mut linked_paths = Vec::new();
+        for path in &due_paths {
+            for (other_path, pending) in &self.pending {
+                if let NetOp::Rename { from, .. } = &pending.op {
+                    if from == path || other_path == path {
+                        linked_paths.push(other_path.clone());
+                        linked_paths.push(from.clone());
+                    }
+                }
+            }
+        }
+        for path in linked_paths {
+            if self.pending.contains_key(&path) && !due_paths.contains(&path) {
+                due_paths.push(path);
+            }
+        }
+
+        self.take(due_paths)
+    }
+
+    /// The net events of all the pending paths
+    pub fn take_all(&mut self) -> Vec<Event> {
+        self.moved_out();
+        let paths = self.pending.keys().cloned().collect();
+        self.take(paths)
+    }
+
+    fn take(&mut self, paths: Vec<PathBuf>) -> Vec<Event> {
+        let mut taken = paths
+            .into_iter()
+            .filter_map(|path| self.pending.remove(&path).map(|pending| (path, pending)))
+            .collect::<Vec<_>>();
+        // Renames first, their source may be created again afterwards
+        taken
+            .sort_by_key(|(_, pending)| (!matches!(pending.op, NetOp::Rename { .. }), pending.seq));
+
+        let mut events = Vec::new();
+        for (path, pending) in taken {
+            match pending.op {
+                NetOp::Create(kind) => {
+                    events.push(Event::new(EventKind::Create(kind)).add_path(path))
+                }
+                NetOp::Modify(kind) => {
+                    events.push(Event::new(EventKind::Modify(kind)).add_path(path))
+                }
+                NetOp::Remove(kind) => {
+                    events.push(Event::new(EventKind::Remove(kind)).add_path(path))
+                }
+                NetOp::Rename { from, modified } => {
+                    let rename = |mode| Event::new(EventKind::Modify(ModifyKind::Name(mode)));
+                    events.push(rename(RenameMode::From).add_path(from));
+                    events.push(rename(RenameMode::To).add_path(path.clone()));
+                    if modified {
+                        let modify = ModifyKind::Data(DataChange::Any);
+                        events.push(Event::new(EventKind::Modify(modify)).add_path(path));
+                    }
+                }
+            }
+        }
+
+        events
+    }
+
+    /// The changes pending inside a renamed dir now apply to its new path
+    fn move_children(&mut self, from_path: &Path, path: &Path) {
+        let children = self
+            .pending
+            .keys()
+            .filter(|child| child.starts_with(from_path) && *child != from_path)
+            .cloned()
+            .collect::<Vec<_>>();
+        for child in children {
+            let pending = self.pending.remove(&child).unwrap();
+            let new_child = path.join(child.strip_prefix(from_path).unwrap());
+            self.pending.insert(new_child, pending);
+        }
+    }
+
+    /// The first half of a rename without the second one, the path was moved out of the source
+    /// dir. The removal is due a window after the rename, not after it was found out
+    fn moved_out(&mut self) {
+        if let Some((path, time)) = self.rename_from.take() {
+            self.merge(path, NetOp::Remove(RemoveKind::Any), time);
+        }
+    }
+
+    /// The net operation of `path` once renamed from `from_path`, `None` when nothing changed
+    fn renamed(from_path: PathBuf, prior: Option<NetOp>, path: &PathBuf) -> Option<NetOp> {
+        let (from, modified) = match prior {
+            // Created during the window, the target doesn't have it yet
+            Some(NetOp::Create(_)) | Some(NetOp::Remove(_)) => {
+                return Some(NetOp::Modify(ModifyKind::Any))
+            }
+            Some(NetOp::Modify(_)) => (from_path, true),
+            Some(NetOp::Rename { from, modified }) => (from, modified),
+            None => (from_path, false),
+        };
+
+        if &from != path {
+            Some(NetOp::Rename { from, modified })
+        } else if modified {
+            Some(NetOp::Modify(ModifyKind::Any))
+        } else {
+            // Renamed back
+            None
+        }
+    }
+
+    fn merge(&mut self, path: PathBuf, op: NetOp, now: Instant) {
+        let merged = match (self.pending.get(&path).map(|pending| &pending.op), op) {
+            (None, op) => op,
+            // Removing a renamed path removes the path the target still has
+            (Some(NetOp::Rename { from, .. }), NetOp::Remove(kind)) => {
+                let from = from.clone();
+                self.pending.remove(&path);
+                self.replace(from, NetOp::Remove(kind), now);
+                return;
+            }
+            (Some(_), NetOp::Remove(kind)) => NetOp::Remove(kind),
+            (Some(NetOp::Rename { from, .. }), _) => NetOp::Rename {
+                from: from.clone(),
+                modified: true,
+            },
+            // Replaced, or created with its content
+            (Some(NetOp::Remove(_)), _) | (Some(NetOp::Create(_)), NetOp::Modify(_)) => {
+                NetOp::Modify(ModifyKind::Any)
+            }
+            (Some(NetOp::Create(kind)), NetOp::Create(_)) => NetOp::Create(*kind),
+            // The content changes matter more than the metadata ones
+            (Some(NetOp::Modify(kind)), NetOp::Modify(ModifyKind::Metadata(_)))
+            | (Some(NetOp::Modify(kind)), NetOp::Create(_)) => NetOp::Modify(*kind),
+            (Some(NetOp::Modify(_)), NetOp::Modify(kind)) => NetOp::Modify(kind),
+            (Some(_), op) => op,
+        };
+
+        self.replace(path, merged, now);
+    }
+
+    fn replace(&mut self, path: PathBuf, op: NetOp, now: Instant) {
+        self.next_seq += 1;
+        self.pending.insert(
+            path,
+            Pending {
+                op,
+                due: now + self.window,
+                seq: self.next_seq,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WINDOW: Duration = Duration::from_millis(100);
+
+    fn event(kind: EventKind, path: &str) -> Event {
+        Event::new(kind).add_path(PathBuf::from(path))
+    }
+
+    fn rename(mode: RenameMode, path: &str) -> Event {
+        event(EventKind::Modify(ModifyKind::Name(mode)), path)
+    }
+
+    fn summary(events: &[Event]) -> Vec<(EventKind, PathBuf)> {
+        events
+            .iter()
+            .map(|event| (event.kind, event.paths[0].clone()))
+            .collect()
+    }
+
+    #[test]
+    fn events_are_due_once_the_path_is_quiet() {
+        let mut settler = Settler::new(WINDOW);
+        let start = Instant::now();
+        settler.push(event(EventKind::Create(CreateKind::File), "/s/a"), start);
+        let later = start + WINDOW / 2;
+        settler.push(event(EventKind::Modify(ModifyKind::Any), "/s/a"), later);
+
+        assert_eq!(settler.next_due(), Some(later + WINDOW));
+        assert!(settler.take_due(start + WINDOW).is_empty());
+        let events = settler.take_due(later + WINDOW);
+        assert_eq!(
+            summary(&events),
+            [(EventKind::Modify(ModifyKind::Any), PathBuf::from("/s/a"))]
+        );
+        assert_eq!(settler.next_due(), None);
+    }
+
+    #[test]
+    fn merge_keeps_the_net_operation() {
+        let now = Instant::now();
+        let data = ModifyKind::Data(DataChange::Content);
+        let metadata = ModifyKind::Metadata(notify::event::MetadataKind::Any);
+        let cases = [
+            (
+                vec![EventKind::Create(CreateKind::File), EventKind::Modify(data)],
+                EventKind::Modify(ModifyKind::Any),
+            ),
+            (
+                vec![EventKind::Modify(data), EventKind::Modify(metadata)],
+                EventKind::Modify(data),
+            ),
+            (
+                vec![EventKind::Modify(data), EventKind::Remove(RemoveKind::File)],
+                EventKind::Remove(RemoveKind::File),
+            ),
+            (
+                vec![
+                    EventKind::Remove(RemoveKind::File),
+                    EventKind::Create(CreateKind::File),
+                ],
+                EventKind::Modify(ModifyKind::Any),
+            ),
+            (
+                vec![EventKind::Access(notify::event::AccessKind::Any)],
+                EventKind::Any,
+            ),
+        ];
+
+        for (kinds, expected) in cases {
+            let mut settler = Settler::new(WINDOW);
+            for kind in &kinds {
+                settler.push(event(*kind, "/s/a"), now);
+            }
+            let events = settler.take_all();
+            let net = events.first().map_or(EventKind::Any, |event| event.kind);
+            assert_eq!(net, expected, "{:?}", kinds);
+            assert!(events.len() <= 1, "{:?}", kinds);
+        }
+    }
+
+    #[test]
+    fn rename_then_modify_keeps_both() {
+        let mut settler = Settler::new(WINDOW);
+        let now = Instant::now();
+        settler.push(rename(RenameMode::From, "/s/a"), now);
+        settler.push(rename(RenameMode::To, "/s/b"), now);
+        let modify = EventKind::Modify(ModifyKind::Data(DataChange::Any));
+        settler.push(event(modify, "/s/b"), now);
+
+        assert_eq!(
+            summary(&settler.take_due(now + WINDOW)),
+            [
+                (
+                    EventKind::Modify(ModifyKind::Name(RenameMode::From)),
+                    PathBuf::from("/s/a")
+                ),
+                (
+                    EventKind::Modify(ModifyKind::Name(RenameMode::To)),
+                    PathBuf::from("/s/b")
+                ),
+                (modify, PathBuf::from("/s/b")),
+            ]
+        );
+    }
+
+    #[test]
+    fn renamed_back_is_dropped() {
+        let mut settler = Settler::new(WINDOW);
+        let now = Instant::now();
+        settler.push(rename(RenameMode::From, "/s/a"), now);
+        settler.push(rename(RenameMode::To, "/s/b"), now);
+        settler.push(rename(RenameMode::From, "/s/b"), now);
+        settler.push(rename(RenameMode::To, "/s/a"), now);
+
+        assert!(settler.take_all().is_empty());
+    }
+
+    #[test]
+    fn removing_a_renamed_path_removes_its_source() {
+        let mut settler = Settler::new(WINDOW);
+        let now = Instant::now();
+        settler.push(rename(RenameMode::From, "/s/a"), now);
+        settler.push(rename(RenameMode::To, "/s/b"), now);
+        settler.push(event(EventKind::Remove(RemoveKind::File), "/s/b"), now);
+
+        assert_eq!(
+            summary(&settler.take_all()),
+            [(EventKind::Remove(RemoveKind::File), PathBuf::from("/s/a"))]
+        );
+    }
+
+    #[test]
+    fn rename_without_second_half_is_a_removal() {
+        let mut settler = Settler::new(WINDOW);
+        let now = Instant::now();
+        settler.push(rename(RenameMode::From, "/s/a"), now);
+
+        assert_eq!(settler.next_due(), Some(now + WINDOW));
+        assert_eq!(
+            summary(&settler.take_due(now + WINDOW)),
+            [(EventKind::Remove(RemoveKind::Any), PathBuf::from("/s/a"))]
+        );
+    }
+
+    #[test]
+    fn changes_in_a_renamed_dir_follow_it() {
+        let mut settler = Settler::new(WINDOW);
+        let now = Instant::now();
+        let modify = EventKind::Modify(ModifyKind::Data(DataChange::Any));
+        settler.push(event(modify, "/s/dir/f"), now);
+        settler.push(rename(RenameMode::From, "/s/dir"), now);
+        settler.push(rename(RenameMode::To, "/s/moved"), now);
+
+        let events = summary(&settler.take_all());
+        assert_eq!(events.len(), 3);
+        assert_eq!(events[2], (modify, PathBuf::from("/s/moved/f")));
+    }
+}
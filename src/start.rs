@@ -1,94 +1,255 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::process::abort;
+use std::sync::Arc;
+use std::time::Duration;
 
-use clap::Parser;
-use notify::{Config, Event, RecommendedWatcher, Watcher};
-use tokio::fs::canonicalize;
-use tokio::sync::mpsc::unbounded_channel;
-use tokio_stream::wrappers::UnboundedReceiverStream;
+use clap::{CommandFactory, FromArgMatches};
+use oxsync::{
+    ConfigFile, FilterConfig, LocalTarget, Profile, RemoteTarget, SyncConfig, SyncEngine,
+    CONFIG_FILE_NAME, STATE_DIR_NAME, TCP_SCHEME,
+};
 
-use crate::utils::Utils;
-use crate::{Args, LOG_TRACE};
+use crate::{Args, Cli, Command};
 
 pub(crate) struct Start;
 
-impl Start {
-    pub async fn parse_args() {
-        let mut args = Args::parse();
-
-        LOG_TRACE.set(args.trace).unwrap();
+pub(crate) enum Mode {
+    Watch,
+    Sync,
+    /// Serve the dir to a remote oxsync
+    Agent(LocalTarget),
+    /// Serve the dir to the remote oxsync connecting to the address with the token
+    Serve(String, String, LocalTarget),
+}
 
-        if !Path::new(&args.source_dir).exists() {
-            eprintln!(
-                "source dir : '{}' does not exists",
-                Utils::fmt_path(&args.source_dir)
-            );
+impl Start {
+    pub async fn parse_args() -> (Mode, Vec<SyncEngine>) {
+        // `Cli::parse` would also require the top level args when a subcommand is used
+        let matches = Cli::command().get_matches();
+        let (args, mode) = match Command::from_arg_matches(&matches) {
+            Ok(Command::Watch(args)) => (args, Mode::Watch),
+            Ok(Command::Sync(args)) => (args, Mode::Sync),
+            Ok(Command::Agent(agent_args)) => {
+                let target = LocalTarget::new(agent_args.root).with_fsync(agent_args.fsync);
+                return (Mode::Agent(target), Vec::new());
+            }
+            Ok(Command::Serve(serve_args)) => {
+                let target = LocalTarget::new(serve_args.root).with_fsync(serve_args.fsync);
+                let mode = Mode::Serve(serve_args.listen, serve_args.token, target);
+                return (mode, Vec::new());
+            }
+            Err(_) => (
+                Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit()),
+                Mode::Watch,
+            ),
+        };
+
+        let mut configs = Vec::new();
+        let profiles = Self::load_profiles(&args);
+        if args.source_dir.is_some() && profiles.len() > 1 {
+            eprintln!("<SOURCE_DIR> and <TARGET_DIR> can't override several profiles");
             abort()
         }
-
-        if !Path::new(&args.target_dir).exists() {
-            eprintln!(
-                "target dir : '{}' does not exists",
-                Utils::fmt_path(&args.target_dir)
-            );
-            abort()
+        for (name, profile) in profiles {
+            let dirs = (args.source_dir.clone(), args.target_dir.clone());
+            let agent_command = args.agent_command.clone().or(profile.agent_command.clone());
+            let config = Self::merge_profile(&args, dirs, profile);
+            configs.push((name, config, agent_command));
         }
-
-        args.source_dir = canonicalize(Path::new(&args.source_dir))
-            .await
-            .unwrap_or_else(|_| {
+        if configs.is_empty() && (args.source_dir.is_some() || args.pair.is_empty()) {
+            let dirs = (args.source_dir.clone(), args.target_dir.clone());
+            let config = Self::merge_profile(&args, dirs, Profile::default());
+            configs.push((None, config, args.agent_command.clone()));
+        }
+        for pair in &args.pair {
+            let Some((source_dir, target_dir)) = pair.split_once('=') else {
                 eprintln!(
-                    "impossible to convert source dir '{}' to a valid path",
-                    Utils::fmt_path(&args.source_dir)
+                    "pair : '{}' must be formatted as <SOURCE_DIR>=<TARGET_DIR>",
+                    pair
                 );
                 abort()
+            };
+            let dirs = (Some(source_dir.into()), Some(target_dir.into()));
+            let config = Self::merge_profile(&args, dirs, Profile::default());
+            configs.push((None, config, args.agent_command.clone()));
+        }
+
+        let several_pairs = configs.len() > 1;
+        let mut engines = Vec::with_capacity(configs.len());
+        for (name, mut config, agent_command) in configs {
+            let label = several_pairs.then(|| {
+                name.unwrap_or_else(|| {
+                    let dir_name = config.source_dir.file_name().unwrap_or_default();
+                    dir_name.to_string_lossy().into_owned()
+                })
             });
 
-        args.target_dir = canonicalize(Path::new(&args.target_dir))
-            .await
-            .unwrap_or_else(|_| {
-                eprintln!(
-                    "impossible to convert target dir '{}' to a valid path",
-                    Utils::fmt_path(&args.target_dir)
-                );
+            let tcp_addr = config
+                .target_dir
+                .to_str()
+                .filter(|target| target.starts_with(TCP_SCHEME))
+                .map(str::to_string);
+            // The index of remote targets is kept on the local side
+            if tcp_addr.is_some() || agent_command.is_some() {
+                let state_dir = config.source_dir.join(STATE_DIR_NAME);
+                config.state_dir.get_or_insert(state_dir);
+            }
+            let engine = match (tcp_addr, agent_command) {
+                (Some(addr), _) => {
+                    let Some(token) = &args.token else {
+                        eprintln!("target : '{}' requires --token or OXSYNC_TOKEN", addr);
+                        abort()
+                    };
+                    let target = RemoteTarget::connect_tcp(&addr, token)
+                        .await
+                        .unwrap_or_else(|err| {
+                            eprintln!("target : failed to connect to '{}', error: {}", addr, err);
+                            abort()
+                        });
+                    let target = target.with_max_connections(config.concurrency);
+                    SyncEngine::with_target(config, label, Arc::new(target))
+                }
+                (None, Some(command)) => {
+                    let target = RemoteTarget::spawn(&command, &config.target_dir)
+                        .await
+                        .unwrap_or_else(|err| {
+                            eprintln!("agent command : '{}' failed, error: {}", command, err);
+                            abort()
+                        });
+                    let target = target.with_max_connections(config.concurrency);
+                    SyncEngine::with_target(config, label, Arc::new(target))
+                }
+                (None, None) => SyncEngine::new(config, label),
+            };
+            let engine = engine.unwrap_or_else(|err| {
+                eprintln!("{}", err);
                 abort()
             });
+            engines.push(engine);
+        }
 
-        let mut excluded_paths = Vec::new();
-
-        for path in &args.exclude {
-            let full_path = if !path.starts_with(&args.source_dir) {
-                args.source_dir.as_path().join(path)
-            } else {
-                path.to_path_buf()
-            };
+        (mode, engines)
+    }
 
-            excluded_paths.push(Utils::path_to_verbatim(&full_path));
-        }
+    /// The profiles come from the `--config` file, or from the `oxsync.toml` file of the working
+    /// dir when it exists. Without `--profile`, the default profile of the file is used
+    fn load_profiles(args: &Args) -> Vec<(Option<String>, Profile)> {
+        let config_path = match &args.config {
+            Some(config_path) => config_path.clone(),
+            None if Path::new(CONFIG_FILE_NAME).is_file() => PathBuf::from(CONFIG_FILE_NAME),
+            None if !args.profile.is_empty() => {
+                eprintln!("no '{}' config file found", CONFIG_FILE_NAME);
+                abort()
+            }
+            None => return Vec::new(),
+        };
 
-        if args.ide_mode {
-            excluded_paths.push(Utils::path_to_verbatim(&args.source_dir.join(".idea")));
-            excluded_paths.push(Utils::path_to_verbatim(&args.source_dir.join(".git")));
-            args.no_temporary_editor_files = true;
-            args.no_creation_events = true;
+        let config_file = ConfigFile::load(&config_path).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            abort()
+        });
+
+        if args.profile.is_empty() {
+            return match config_file.profile(None) {
+                Ok(profile) => vec![(None, profile.clone())],
+                // A config file isn't required when the dirs are given
+                Err(_) if args.source_dir.is_some() || !args.pair.is_empty() => Vec::new(),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    abort()
+                }
+            };
         }
 
-        excluded_paths.shrink_to_fit();
-        Utils::set_excluded_paths(excluded_paths);
-
-        Utils::set_args(args);
+        args.profile
+            .iter()
+            .map(|name| match config_file.profile(Some(name)) {
+                Ok(profile) => (Some(name.clone()), profile.clone()),
+                Err(err) => {
+                    eprintln!("{}", err);
+                    abort()
+                }
+            })
+            .collect()
     }
 
-    pub fn fs_watcher() -> notify::Result<(
-        RecommendedWatcher,
-        UnboundedReceiverStream<notify::Result<Event>>,
-    )> {
-        let (tx, rx) = unbounded_channel();
-
-        // Automatically select the best implementation for your platform.
-        // You can also access each implementation directly e.g. INotifyWatcher.
-        let watcher = RecommendedWatcher::new(move |res| tx.send(res).unwrap(), Config::default())?;
+    /// The CLI args override the values of the profile
+    fn merge_profile(
+        args: &Args,
+        dirs: (Option<PathBuf>, Option<PathBuf>),
+        profile: Profile,
+    ) -> SyncConfig {
+        let Some(source_dir) = dirs.0.or(profile.source) else {
+            eprintln!("source dir : missing, give <SOURCE_DIR> or set `source` in a profile");
+            abort()
+        };
+        let Some(target_dir) = dirs.1.or(profile.target) else {
+            eprintln!("target dir : missing, give <TARGET_DIR> or set `target` in a profile");
+            abort()
+        };
+
+        let mut exclude_patterns = if args.exclude.is_empty() {
+            profile.exclude
+        } else {
+            args.exclude.clone()
+        };
+        let include_patterns = if args.include.is_empty() {
+            profile.include
+        } else {
+            args.include.clone()
+        };
+
+        let preserve = if args.preserve.is_empty() {
+            profile.preserve
+        } else {
+            args.preserve.iter().copied().map(Into::into).collect()
+        };
+
+        let ide_mode = args.ide_mode || profile.ide_mode;
+        if ide_mode {
+            exclude_patterns.push("/.idea".to_string());
+            exclude_patterns.push("/.git".to_string());
+        }
 
-        Ok((watcher, UnboundedReceiverStream::new(rx)))
+        SyncConfig {
+            source_dir,
+            target_dir,
+            filter: FilterConfig {
+                exclude_patterns,
+                include_patterns,
+                use_gitignore: args.gitignore || profile.gitignore,
+                no_temporary_editor_files: ide_mode
+                    || args.no_temporary_editor_files
+                    || profile.no_temporary_editor_files,
+            },
+            no_creation_events: ide_mode || args.no_creation_events || profile.no_creation_events,
+            statistics: args.statistics || profile.statistics,
+            no_initial_sync: args.no_initial_sync || profile.no_initial_sync,
+            delete: args.delete || profile.delete,
+            delta: args.delta || profile.delta,
+            fsync: args.fsync || profile.fsync,
+            state_dir: args.state_dir.clone().or(profile.state_dir),
+            settle: Duration::from_millis(args.settle.or(profile.settle).unwrap_or(0)),
+            concurrency: args.concurrency.or(profile.concurrency).unwrap_or(4).max(1),
+            two_way: args.two_way || profile.two_way,
+            on_target_change: args
+                .on_target_change
+                .map(Into::into)
+                .or(profile.on_target_change)
+                .unwrap_or_default(),
+            preserve,
+            symlinks: args
+                .symlinks
+                .map(Into::into)
+                .or(profile.symlinks)
+                .unwrap_or_default(),
+            hard_links: args.hard_links || profile.hard_links,
+            trash: args.trash || profile.trash,
+            trash_retention: args
+                .trash_retention
+                .or(profile.trash_retention)
+                .unwrap_or(30),
+            trace: args.trace,
+        }
     }
 }
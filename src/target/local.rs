@@ -0,0 +1,435 @@
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime};
+
+use async_trait::async_trait;
+use blake3::Hash;
+use tokio::fs;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::{AsyncSeekExt, AsyncWriteExt};
+
+use crate::attributes::Attributes;
+use crate::delta::Delta;
+use crate::target::{SyncTarget, TargetMetadata, STATE_DIR_NAME, TRASH_DIR_NAME};
+use crate::utils::{PathType, Utils};
+
+/// Files are written to a hidden temp file of the same dir, then renamed over their path. The
+/// temp files left by an interrupted run start with this prefix
+pub const TEMP_FILE_PREFIX: &str = ".oxsync-tmp-";
+
+/// How long a temp file must be left unwritten before it is removed, the newer ones may still be
+/// written by another run syncing to the same dir
+const TEMP_FILE_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Makes the names of the temp files unique, concurrent writes of a path don't share one
+static TEMP_FILE_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// A target dir of the local filesystem
+#[derive(Debug, Clone)]
+pub struct LocalTarget {
+    root: PathBuf,
+    fsync: bool,
+    follow_symlinks: bool,
+}
+
+impl LocalTarget {
+    pub fn new(root: PathBuf) -> Self {
+        Self {
+            root,
+            fsync: false,
+            follow_symlinks: false,
+        }
+    }
+
+    /// Flush the written files to the disk before renaming them over their path, so they survive
+    /// a power loss
+    pub fn with_fsync(mut self, fsync: bool) -> Self {
+        self.fsync = fsync;
+        self
+    }
+
+    /// Report what the symlinks point to instead of the symlinks themselves
+    pub fn with_follow_symlinks(mut self, follow_symlinks: bool) -> Self {
+        self.follow_symlinks = follow_symlinks;
+        self
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn fsync(&self) -> bool {
+        self.fsync
+    }
+
+    /// Move the fully written `temp_path` file to `path`, readers never see it partially written
+    pub(crate) async fn persist(
+        &self,
+        file: File,
+        temp_path: &Path,
+        path: &Path,
+    ) -> io::Result<()> {
+        if self.fsync {
+            file.sync_all().await?;
+        }
+        drop(file);
+
+        let dest_path = self.full_path(path);
+        fs::rename(temp_path, &dest_path).await?;
+        // The rename itself is only durable once the dir is flushed
+        #[cfg(unix)]
+        if self.fsync {
+            if let Some(parent) = dest_path.parent() {
+                File::open(parent).await?.sync_all().await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copy the file `path` to `temp_path` and open the copy, to be patched then persisted over
+    /// the file
+    pub(crate) async fn copy_to_patch(&self, path: &Path, temp_path: &Path) -> io::Result<File> {
+        fs::copy(self.full_path(path), temp_path).await?;
+        OpenOptions::new().write(true).open(temp_path).await
+    }
+
+    fn full_path(&self, path: &Path) -> PathBuf {
+        self.root.join(path)
+    }
+
+    fn temp_path(&self, path: &Path) -> PathBuf {
+        let mut name = OsString::from(TEMP_FILE_PREFIX);
+        name.push(path.file_name().unwrap_or_default());
+        name.push(Self::unique_suffix());
+        self.full_path(path).with_file_name(name)
+    }
+
+    /// Unique among the processes and the writes of this one
+    pub(crate) fn unique_suffix() -> String {
+        let count = TEMP_FILE_COUNT.fetch_add(1, Ordering::Relaxed);
+        format!(".{}-{}", process::id(), count)
+    }
+
+    /// Create the symlink `link_path`, to be moved to `path`
+    #[cfg(unix)]
+    async fn symlink(&self, _path: &Path, link: &Path, link_path: &Path) -> io::Result<()> {
+        fs::symlink(link, link_path).await
+    }
+
+    /// Windows tells the symlinks to dirs apart, they are created for the dirs existing already
+    #[cfg(windows)]
+    async fn symlink(&self, path: &Path, link: &Path, link_path: &Path) -> io::Result<()> {
+        let parent = self.full_path(path.parent().unwrap_or(Path::new("")));
+        if parent.join(link).is_dir() {
+            fs::symlink_dir(link, link_path).await
+        } else {
+            fs::symlink_file(link, link_path).await
+        }
+    }
+
+    /// The dirs of the root holding the state and the trash of oxsync
+    fn is_internal_dir(name: &OsStr) -> bool {
+        name == STATE_DIR_NAME || name == TRASH_DIR_NAME
+    }
+
+    fn is_temp_file(name: &OsStr) -> bool {
+        name.to_str()
+            .is_some_and(|name| name.starts_with(TEMP_FILE_PREFIX))
+    }
+}
+
+#[async_trait]
+impl SyncTarget for LocalTarget {
+    async fn write_file(&self, path: &Path, src_path: &Path) -> io::Result<Hash> {
+        let src_file = File::open(src_path).await?;
+        let permissions = src_file.metadata().await?.permissions();
+        let temp_path = self.temp_path(path);
+
+        let result = async {
+            let mut temp_file = File::create(&temp_path).await?;
+            let hash = Utils::copy_and_hash(src_file, &mut temp_file).await?;
+            fs::set_permissions(&temp_path, permissions).await?;
+            self.persist(temp_file, &temp_path, path).await?;
+            Ok(hash)
+        }
+        .await;
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path).await;
+        }
+
+        result
+    }
+
+    async fn create_dir(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(self.full_path(path)).await
+    }
+
+    async fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(self.full_path(path)).await
+    }
+
+    async fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        fs::remove_dir_all(self.full_path(path)).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(self.full_path(from), self.full_path(to)).await
+    }
+
+    async fn create_symlink(&self, path: &Path, link: &Path) -> io::Result<()> {
+        let temp_path = self.temp_path(path);
+
+        let result = async {
+            self.symlink(path, link, &temp_path).await?;
+            fs::rename(&temp_path, self.full_path(path)).await
+        }
+        .await;
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path).await;
+        }
+
+        result
+    }
+
+    async fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        fs::read_link(self.full_path(path)).await
+    }
+
+    async fn hard_link(&self, path: &Path, original: &Path) -> io::Result<()> {
+        let temp_path = self.temp_path(path);
+
+        let result = async {
+            fs::hard_link(self.full_path(original), &temp_path).await?;
+            fs::rename(&temp_path, self.full_path(path)).await
+        }
+        .await;
+        // Also left by a rename over a link of the same file, which does nothing
+        let _ = fs::remove_file(&temp_path).await;
+
+        result
+    }
+
+    #[cfg(unix)]
+    async fn same_file(&self, path: &Path, other: &Path) -> io::Result<bool> {
+        use std::os::unix::fs::MetadataExt;
+
+        let metadata = fs::symlink_metadata(self.full_path(path)).await?;
+        let other_metadata = fs::symlink_metadata(self.full_path(other)).await?;
+        Ok(metadata.dev() == other_metadata.dev() && metadata.ino() == other_metadata.ino())
+    }
+
+    #[cfg(not(unix))]
+    async fn same_file(&self, _path: &Path, _other: &Path) -> io::Result<bool> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "hard links are only compared on Unix",
+        ))
+    }
+
+    async fn stat(&self, path: &Path) -> io::Result<TargetMetadata> {
+        let metadata = if self.follow_symlinks {
+            fs::metadata(self.full_path(path)).await?
+        } else {
+            fs::symlink_metadata(self.full_path(path)).await?
+        };
+
+        Ok(TargetMetadata {
+            path_type: if metadata.is_symlink() {
+                PathType::Symlink
+            } else if metadata.is_dir() {
+                PathType::Dir
+            } else {
+                PathType::File
+            },
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        })
+    }
+
+    async fn hash(&self, path: &Path) -> io::Result<Hash> {
+        Utils::hash_reader(File::open(self.full_path(path)).await?).await
+    }
+
+    async fn read_dir(&self, path: &Path) -> io::Result<Vec<(OsString, PathType)>> {
+        let mut entries = fs::read_dir(self.full_path(path)).await?;
+        let mut names = Vec::new();
+
+        while let Some(entry) = entries.next_entry().await? {
+            if path.as_os_str().is_empty() && Self::is_internal_dir(&entry.file_name())
+                || Self::is_temp_file(&entry.file_name())
+            {
+                continue;
+            }
+
+            let file_type = entry.file_type().await?;
+            let path_type = if file_type.is_symlink() && !self.follow_symlinks {
+                PathType::Symlink
+            } else if file_type.is_dir() || file_type.is_symlink() && entry.path().is_dir() {
+                PathType::Dir
+            } else {
+                PathType::File
+            };
+            names.push((entry.file_name(), path_type));
+        }
+
+        Ok(names)
+    }
+
+    async fn block_hashes(&self, path: &Path, block_size: usize) -> io::Result<Vec<Hash>> {
+        let (_, blocks) = Delta::hash_blocks(&self.full_path(path), block_size).await?;
+        Ok(blocks.hashes)
+    }
+
+    async fn write_blocks(
+        &self,
+        path: &Path,
+        src_path: &Path,
+        changed: &[usize],
+        block_size: usize,
+        len: u64,
+    ) -> io::Result<()> {
+        let mut src_file = File::open(src_path).await?;
+        let temp_path = self.temp_path(path);
+
+        let result = async {
+            let mut temp_file = self.copy_to_patch(path, &temp_path).await?;
+            let mut buffer = vec![0; block_size];
+            for &index in changed {
+                let offset = (index * block_size) as u64;
+                src_file.seek(SeekFrom::Start(offset)).await?;
+                let read = Delta::read_block(&mut src_file, &mut buffer).await?;
+
+                temp_file.seek(SeekFrom::Start(offset)).await?;
+                temp_file.write_all(&buffer[..read]).await?;
+            }
+            temp_file.set_len(len).await?;
+            temp_file.flush().await?;
+            self.persist(temp_file, &temp_path, path).await
+        }
+        .await;
+        if result.is_err() {
+            let _ = fs::remove_file(&temp_path).await;
+        }
+
+        result
+    }
+
+    async fn set_attributes(&self, path: &Path, attributes: &Attributes) -> io::Result<()> {
+        attributes.apply(self.full_path(path)).await
+    }
+
+    /// Only the root dir must be readable, the paths which can't be read or removed are skipped
+    async fn remove_temp_files(&self) -> io::Result<usize> {
+        let mut removed = 0;
+        let mut dirs = vec![self.root.clone()];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = match fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(err) if dir == self.root => return Err(err),
+                Err(_) => continue,
+            };
+            while let Ok(Some(entry)) = entries.next_entry().await {
+                if dir == self.root && Self::is_internal_dir(&entry.file_name()) {
+                    continue;
+                }
+                let Ok(file_type) = entry.file_type().await else {
+                    continue;
+                };
+
+                if file_type.is_dir() {
+                    dirs.push(entry.path());
+                } else if Self::is_temp_file(&entry.file_name()) {
+                    let is_old = entry
+                        .metadata()
+                        .await
+                        .ok()
+                        .and_then(|metadata| metadata.modified().ok())
+                        .and_then(|modified| SystemTime::now().duration_since(modified).ok())
+                        .is_some_and(|age| age > TEMP_FILE_RETENTION);
+                    if is_old && fs::remove_file(entry.path()).await.is_ok() {
+                        removed += 1;
+                    }
+                }
+            }
+        }
+
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn temp_files_are_hidden_then_removed() {
+        let root = TempDir::new().unwrap();
+        let target = LocalTarget::new(root.path().to_path_buf());
+        std::fs::create_dir_all(root.path().join("dir")).unwrap();
+        std::fs::create_dir_all(root.path().join(STATE_DIR_NAME)).unwrap();
+        let temp_path = target.temp_path(Path::new("dir/f"));
+        std::fs::write(&temp_path, b"partial").unwrap();
+        let old_modified = SystemTime::now() - TEMP_FILE_RETENTION * 2;
+        filetime::set_file_mtime(&temp_path, old_modified.into()).unwrap();
+        // Still written by another run
+        let written_path = target.temp_path(Path::new("dir/g"));
+        std::fs::write(&written_path, b"partial").unwrap();
+        std::fs::write(root.path().join("dir/g"), b"synced").unwrap();
+
+        let entries = target.read_dir(Path::new("dir")).await.unwrap();
+        assert_eq!(entries, [(OsString::from("g"), PathType::File)]);
+        assert_eq!(
+            target.read_dir(Path::new("")).await.unwrap(),
+            [(OsString::from("dir"), PathType::Dir)]
+        );
+
+        assert_eq!(target.remove_temp_files().await.unwrap(), 1);
+        assert!(!temp_path.exists());
+        assert!(written_path.exists());
+        assert!(root.path().join("dir/g").exists());
+    }
+
+    #[test]
+    fn temp_paths_are_unique() {
+        let target = LocalTarget::new(PathBuf::from("/root"));
+        let path = Path::new("dir/f");
+
+        assert_ne!(target.temp_path(path), target.temp_path(path));
+        assert!(target.temp_path(path).starts_with("/root/dir"));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn hard_links_replace_the_file() {
+        let root = TempDir::new().unwrap();
+        let target = LocalTarget::new(root.path().to_path_buf());
+        std::fs::write(root.path().join("f"), b"content").unwrap();
+        std::fs::write(root.path().join("g"), b"other").unwrap();
+
+        target
+            .hard_link(Path::new("g"), Path::new("f"))
+            .await
+            .unwrap();
+        assert!(target
+            .same_file(Path::new("g"), Path::new("f"))
+            .await
+            .unwrap());
+        assert_eq!(std::fs::read(root.path().join("g")).unwrap(), b"content");
+        // Linking again the same file leaves no temp file behind
+        target
+            .hard_link(Path::new("g"), Path::new("f"))
+            .await
+            .unwrap();
+        let names = std::fs::read_dir(root.path()).unwrap();
+        assert!(!names
+            .map(|entry| entry.unwrap().file_name())
+            .any(|name| LocalTarget::is_temp_file(&name)));
+    }
+}
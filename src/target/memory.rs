@@ -0,0 +1,411 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::io;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use blake3::Hash;
+use tokio::fs;
+
+use crate::attributes::Attributes;
+use crate::target::{SyncTarget, TargetMetadata};
+use crate::utils::PathType;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MemoryEntry {
+    File {
+        content: Vec<u8>,
+        modified: SystemTime,
+    },
+    Dir,
+    /// Never followed
+    Symlink {
+        link: PathBuf,
+    },
+}
+
+/// A target kept in memory, to run the engine without writing anything to the disk
+#[derive(Debug, Default)]
+pub struct MemoryTarget {
+    /// The root dir isn't stored, it always exists
+    entries: Mutex<BTreeMap<PathBuf, MemoryEntry>>,
+}
+
+impl MemoryTarget {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A copy of every file and dir of the target
+    pub fn snapshot(&self) -> BTreeMap<PathBuf, MemoryEntry> {
+        self.entries().clone()
+    }
+
+    /// The content of the file `path`, `None` when it isn't a file
+    pub fn read(&self, path: &Path) -> Option<Vec<u8>> {
+        match self.entries().get(path) {
+            Some(MemoryEntry::File { content, .. }) => Some(content.clone()),
+            _ => None,
+        }
+    }
+
+    fn entries(&self) -> MutexGuard<'_, BTreeMap<PathBuf, MemoryEntry>> {
+        self.entries.lock().unwrap()
+    }
+
+    fn check_parent(entries: &BTreeMap<PathBuf, MemoryEntry>, path: &Path) -> io::Result<()> {
+        match path.parent() {
+            Some(parent) if parent != Path::new("") => match entries.get(parent) {
+                Some(MemoryEntry::Dir) => Ok(()),
+                Some(_) => Err(not_a_dir(parent)),
+                None => Err(not_found(parent)),
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+#[async_trait]
+impl SyncTarget for MemoryTarget {
+    async fn write_file(&self, path: &Path, src_path: &Path) -> io::Result<Hash> {
+        let content = fs::read(src_path).await?;
+        let hash = blake3::hash(&content);
+
+        let mut entries = self.entries();
+        Self::check_parent(&entries, path)?;
+        if let Some(MemoryEntry::Dir) = entries.get(path) {
+            return Err(is_a_dir(path));
+        }
+        entries.insert(
+            path.to_path_buf(),
+            MemoryEntry::File {
+                content,
+                modified: SystemTime::now(),
+            },
+        );
+
+        Ok(hash)
+    }
+
+    async fn create_dir(&self, path: &Path) -> io::Result<()> {
+        let mut entries = self.entries();
+
+        let mut dir = PathBuf::new();
+        for component in path.components() {
+            dir.push(component);
+            match entries.get(&dir) {
+                Some(MemoryEntry::Dir) => {}
+                Some(_) => return Err(not_a_dir(&dir)),
+                None => {
+                    entries.insert(dir.clone(), MemoryEntry::Dir);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn remove_file(&self, path: &Path) -> io::Result<()> {
+        let mut entries = self.entries();
+        match entries.get(path) {
+            Some(MemoryEntry::File { .. } | MemoryEntry::Symlink { .. }) => {
+                entries.remove(path);
+                Ok(())
+            }
+            Some(MemoryEntry::Dir) => Err(is_a_dir(path)),
+            None => Err(not_found(path)),
+        }
+    }
+
+    async fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        let mut entries = self.entries();
+        match entries.get(path) {
+            Some(MemoryEntry::Dir) => {
+                entries.retain(|entry_path, _| !entry_path.starts_with(path));
+                Ok(())
+            }
+            Some(_) => Err(not_a_dir(path)),
+            None => Err(not_found(path)),
+        }
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let mut entries = self.entries();
+        if !entries.contains_key(from) {
+            return Err(not_found(from));
+        }
+        if from == to {
+            return Ok(());
+        }
+        Self::check_parent(&entries, to)?;
+
+        entries.retain(|entry_path, _| !entry_path.starts_with(to));
+        let moved: Vec<PathBuf> = entries
+            .keys()
+            .filter(|entry_path| entry_path.starts_with(from))
+            .cloned()
+            .collect();
+        for entry_path in moved {
+            let entry = entries.remove(&entry_path).unwrap();
+            let new_path = to.join(entry_path.strip_prefix(from).unwrap());
+            entries.insert(new_path, entry);
+        }
+
+        Ok(())
+    }
+
+    async fn create_symlink(&self, path: &Path, link: &Path) -> io::Result<()> {
+        let mut entries = self.entries();
+        Self::check_parent(&entries, path)?;
+        if let Some(MemoryEntry::Dir) = entries.get(path) {
+            return Err(is_a_dir(path));
+        }
+        entries.insert(
+            path.to_path_buf(),
+            MemoryEntry::Symlink {
+                link: link.to_path_buf(),
+            },
+        );
+
+        Ok(())
+    }
+
+    async fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        match self.entries().get(path) {
+            Some(MemoryEntry::Symlink { link }) => Ok(link.clone()),
+            Some(_) => Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("'{}' is not a symlink", path.display()),
+            )),
+            None => Err(not_found(path)),
+        }
+    }
+
+    /// Entries are independent values, the linked files are copied instead
+    async fn hard_link(&self, _path: &Path, _original: &Path) -> io::Result<()> {
+        Err(io::Error::new(
+            ErrorKind::Unsupported,
+            "the memory target can't link files",
+        ))
+    }
+
+    async fn same_file(&self, _path: &Path, _other: &Path) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    async fn stat(&self, path: &Path) -> io::Result<TargetMetadata> {
+        if path == Path::new("") {
+            return Ok(TargetMetadata {
+                path_type: PathType::Dir,
+                len: 0,
+                modified: None,
+            });
+        }
+
+        match self.entries().get(path) {
+            Some(MemoryEntry::File { content, modified }) => Ok(TargetMetadata {
+                path_type: PathType::File,
+                len: content.len() as u64,
+                modified: Some(*modified),
+            }),
+            Some(MemoryEntry::Dir) => Ok(TargetMetadata {
+                path_type: PathType::Dir,
+                len: 0,
+                modified: None,
+            }),
+            Some(MemoryEntry::Symlink { link }) => Ok(TargetMetadata {
+                path_type: PathType::Symlink,
+                len: link.as_os_str().len() as u64,
+                modified: None,
+            }),
+            None => Err(not_found(path)),
+        }
+    }
+
+    async fn hash(&self, path: &Path) -> io::Result<Hash> {
+        match self.entries().get(path) {
+            Some(MemoryEntry::File { content, .. }) => Ok(blake3::hash(content)),
+            Some(MemoryEntry::Dir) => Err(is_a_dir(path)),
+            Some(MemoryEntry::Symlink { .. }) => Err(is_a_symlink(path)),
+            None => Err(not_found(path)),
+        }
+    }
+
+    async fn read_dir(&self, path: &Path) -> io::Result<Vec<(OsString, PathType)>> {
+        let entries = self.entries();
+        if path != Path::new("") && entries.get(path) != Some(&MemoryEntry::Dir) {
+            return Err(not_found(path));
+        }
+
+        Ok(entries
+            .iter()
+            .filter(|(entry_path, _)| entry_path.parent() == Some(path))
+            .map(|(entry_path, entry)| {
+                let path_type = match entry {
+                    MemoryEntry::File { .. } => PathType::File,
+                    MemoryEntry::Dir => PathType::Dir,
+                    MemoryEntry::Symlink { .. } => PathType::Symlink,
+                };
+                (entry_path.file_name().unwrap().to_os_string(), path_type)
+            })
+            .collect())
+    }
+
+    async fn block_hashes(&self, path: &Path, block_size: usize) -> io::Result<Vec<Hash>> {
+        match self.entries().get(path) {
+            Some(MemoryEntry::File { content, .. }) => {
+                Ok(content.chunks(block_size).map(blake3::hash).collect())
+            }
+            Some(MemoryEntry::Dir) => Err(is_a_dir(path)),
+            Some(MemoryEntry::Symlink { .. }) => Err(is_a_symlink(path)),
+            None => Err(not_found(path)),
+        }
+    }
+
+    async fn write_blocks(
+        &self,
+        path: &Path,
+        src_path: &Path,
+        changed: &[usize],
+        block_size: usize,
+        len: u64,
+    ) -> io::Result<()> {
+        let src_content = fs::read(src_path).await?;
+
+        let mut entries = self.entries();
+        let Some(MemoryEntry::File { content, modified }) = entries.get_mut(path) else {
+            return Err(not_found(path));
+        };
+        content.resize(len as usize, 0);
+        for &index in changed {
+            let start = (index * block_size).min(src_content.len());
+            let end = (start + block_size)
+                .min(src_content.len())
+                .min(content.len());
+            content[start..end].copy_from_slice(&src_content[start..end]);
+        }
+        *modified = SystemTime::now();
+
+        Ok(())
+    }
+
+    /// Only the modification time of the files is kept
+    async fn set_attributes(&self, path: &Path, attributes: &Attributes) -> io::Result<()> {
+        match self.entries().get_mut(path) {
+            Some(MemoryEntry::File { modified, .. }) => {
+                if let Some(source_modified) = attributes.modified {
+                    *modified = source_modified;
+                }
+                Ok(())
+            }
+            Some(MemoryEntry::Dir | MemoryEntry::Symlink { .. }) => Ok(()),
+            None => Err(not_found(path)),
+        }
+    }
+
+    /// Entries are replaced at once, there are no temp files
+    async fn remove_temp_files(&self) -> io::Result<usize> {
+        Ok(0)
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(
+        ErrorKind::NotFound,
+        format!("'{}' does not exists", path.display()),
+    )
+}
+
+fn not_a_dir(path: &Path) -> io::Error {
+    io::Error::other(format!("'{}' is not a dir", path.display()))
+}
+
+fn is_a_dir(path: &Path) -> io::Error {
+    io::Error::other(format!("'{}' is a dir", path.display()))
+}
+
+fn is_a_symlink(path: &Path) -> io::Error {
+    io::Error::other(format!("'{}' is a symlink", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn writes_need_an_existing_parent_dir() {
+        let target = MemoryTarget::new();
+        let src = tempfile::NamedTempFile::new().unwrap();
+
+        let err = target
+            .write_file(Path::new("dir/f"), src.path())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        let err = target
+            .create_symlink(Path::new("dir/l"), Path::new("f"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert!(target.snapshot().is_empty());
+    }
+
+    #[tokio::test]
+    async fn files_and_dirs_are_not_replaced_by_each_other() {
+        let target = MemoryTarget::new();
+        let src = tempfile::NamedTempFile::new().unwrap();
+        target.create_dir(Path::new("dir")).await.unwrap();
+        target.write_file(Path::new("f"), src.path()).await.unwrap();
+
+        assert!(target
+            .write_file(Path::new("dir"), src.path())
+            .await
+            .is_err());
+        assert!(target.create_dir(Path::new("f/sub")).await.is_err());
+        assert!(target.remove_file(Path::new("dir")).await.is_err());
+        assert!(target.remove_dir(Path::new("f")).await.is_err());
+        assert!(target.hash(Path::new("dir")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn renamed_dirs_move_their_content() {
+        let target = MemoryTarget::new();
+        let src = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(src.path(), b"content").unwrap();
+        target.create_dir(Path::new("dir/sub")).await.unwrap();
+        target
+            .write_file(Path::new("dir/sub/f"), src.path())
+            .await
+            .unwrap();
+        target.create_dir(Path::new("other/old")).await.unwrap();
+
+        target
+            .rename(Path::new("dir"), Path::new("other"))
+            .await
+            .unwrap();
+        assert_eq!(
+            target.read(Path::new("other/sub/f")),
+            Some(b"content".to_vec())
+        );
+        assert!(target.stat(Path::new("other/old")).await.is_err());
+        assert!(target.stat(Path::new("dir")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn hard_links_are_unsupported() {
+        let target = MemoryTarget::new();
+        let err = target
+            .hard_link(Path::new("g"), Path::new("f"))
+            .await
+            .unwrap_err();
+
+        assert_eq!(err.kind(), ErrorKind::Unsupported);
+        assert!(!target
+            .same_file(Path::new("g"), Path::new("f"))
+            .await
+            .unwrap());
+    }
+}
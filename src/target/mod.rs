@@ -0,0 +1,212 @@
+use std::ffi::OsString;
+use std::fmt::Debug;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use blake3::Hash;
+use serde::{Deserialize, Serialize};
+
+pub use local::{LocalTarget, TEMP_FILE_PREFIX};
+pub use memory::{MemoryEntry, MemoryTarget};
+pub use remote::{RemoteTarget, TCP_SCHEME};
+
+use crate::attributes::Attributes;
+use crate::utils::PathType;
+
+mod local;
+mod memory;
+mod remote;
+
+/// Dir at the root of a target holding the state of oxsync, it is never synced
+pub const STATE_DIR_NAME: &str = ".oxsync";
+
+/// Dir at the root of a target where the deleted paths are moved to with `--trash`, it is never
+/// synced either
+pub const TRASH_DIR_NAME: &str = ".oxsync-trash";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TargetMetadata {
+    pub path_type: PathType,
+    pub len: u64,
+    pub modified: Option<SystemTime>,
+}
+
+/// Where the changes of the source dir are written. The paths are relative to the root of the
+/// target, an empty path being the root itself
+#[async_trait]
+pub trait SyncTarget: Debug + Send + Sync {
+    /// Write the content of the local `src_path` file to `path`, replacing it when it exists. The
+    /// parent dir must exist. Returns the hash of the content, computed while it is read
+    async fn write_file(&self, path: &Path, src_path: &Path) -> io::Result<Hash>;
+
+    /// Create the dir `path` along with its missing parents
+    async fn create_dir(&self, path: &Path) -> io::Result<()>;
+
+    async fn remove_file(&self, path: &Path) -> io::Result<()>;
+
+    /// Remove the dir `path` along with its content
+    async fn remove_dir(&self, path: &Path) -> io::Result<()>;
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+
+    /// Create the symlink `path` pointing to `link`, replacing the file or symlink already there.
+    /// The parent dir must exist
+    async fn create_symlink(&self, path: &Path, link: &Path) -> io::Result<()>;
+
+    /// Where the symlink `path` points to
+    async fn read_link(&self, path: &Path) -> io::Result<PathBuf>;
+
+    /// Create `path` as a hard link of the file `original`, replacing the file already there. The
+    /// parent dir must exist. Fails with `ErrorKind::Unsupported` when the target can't link files
+    async fn hard_link(&self, path: &Path, original: &Path) -> io::Result<()>;
+
+    /// Whether `path` and `other` are hard links of the same file
+    async fn same_file(&self, path: &Path, other: &Path) -> io::Result<bool>;
+
+    /// Fails with `ErrorKind::NotFound` when `path` doesn't exist. Symlinks are reported as such,
+    /// not followed
+    async fn stat(&self, path: &Path) -> io::Result<TargetMetadata>;
+
+    async fn hash(&self, path: &Path) -> io::Result<Hash>;
+
+    /// The names and types of the entries of the dir `path`
+    async fn read_dir(&self, path: &Path) -> io::Result<Vec<(OsString, PathType)>>;
+
+    /// The hashes of the blocks of `block_size` bytes of the file `path`, for the delta mode.
+    /// Fails with `ErrorKind::Unsupported` when the target can't update files by blocks
+    async fn block_hashes(&self, path: &Path, block_size: usize) -> io::Result<Vec<Hash>>;
+
+    /// Overwrite the blocks of the file `path` at the `changed` indexes with the ones of the local
+    /// `src_path` file, then truncate or extend it to `len` bytes. Like `write_file`, readers never
+    /// see the file partially patched
+    async fn write_blocks(
+        &self,
+        path: &Path,
+        src_path: &Path,
+        changed: &[usize],
+        block_size: usize,
+        len: u64,
+    ) -> io::Result<()>;
+
+    /// Give the attributes of a source path to `path`, the ones left unset are unchanged
+    async fn set_attributes(&self, path: &Path, attributes: &Attributes) -> io::Result<()>;
+
+    /// Remove the temp files left by the writes of an interrupted run, returns their number
+    async fn remove_temp_files(&self) -> io::Result<usize>;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::agent::Agent;
+
+    /// Writes, reads and removes paths through the trait, the same way on every target
+    async fn round_trip(target: &dyn SyncTarget) {
+        let src = TempDir::new().unwrap();
+        let src_path = src.path().join("f");
+        std::fs::write(&src_path, b"aaaabbbbcccc").unwrap();
+
+        target.create_dir(Path::new("dir/sub")).await.unwrap();
+        let stat = target.stat(Path::new("dir")).await.unwrap();
+        assert_eq!(stat.path_type, PathType::Dir);
+
+        let path = Path::new("dir/f");
+        let hash = target.write_file(path, &src_path).await.unwrap();
+        assert_eq!(hash, blake3::hash(b"aaaabbbbcccc"));
+        assert_eq!(target.hash(path).await.unwrap(), hash);
+        let stat = target.stat(path).await.unwrap();
+        assert_eq!(stat.path_type, PathType::File);
+        assert_eq!(stat.len, 12);
+
+        let mut entries = target.read_dir(Path::new("dir")).await.unwrap();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            entries,
+            [
+                (OsString::from("f"), PathType::File),
+                (OsString::from("sub"), PathType::Dir),
+            ]
+        );
+
+        // The second and the last blocks changed, the file grew
+        let block_hashes = target.block_hashes(path, 4).await.unwrap();
+        assert_eq!(block_hashes.len(), 3);
+        assert_eq!(block_hashes[1], blake3::hash(b"bbbb"));
+        std::fs::write(&src_path, b"aaaaXXXXccccdd").unwrap();
+        target
+            .write_blocks(path, &src_path, &[1, 3], 4, 14)
+            .await
+            .unwrap();
+        assert_eq!(
+            target.hash(path).await.unwrap(),
+            blake3::hash(b"aaaaXXXXccccdd")
+        );
+
+        let modified = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let attributes = Attributes {
+            modified: Some(modified),
+            ..Attributes::default()
+        };
+        target.set_attributes(path, &attributes).await.unwrap();
+        assert_eq!(target.stat(path).await.unwrap().modified, Some(modified));
+
+        let renamed = Path::new("dir/sub/g");
+        target.rename(path, renamed).await.unwrap();
+        let err = target.stat(path).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert_eq!(target.stat(renamed).await.unwrap().len, 14);
+
+        let link = Path::new("dir/l");
+        target
+            .create_symlink(link, Path::new("sub/g"))
+            .await
+            .unwrap();
+        assert_eq!(target.read_link(link).await.unwrap(), Path::new("sub/g"));
+        assert_eq!(
+            target.stat(link).await.unwrap().path_type,
+            PathType::Symlink
+        );
+        target.remove_file(link).await.unwrap();
+
+        target.remove_dir(Path::new("dir")).await.unwrap();
+        let err = target.stat(renamed).await.unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert!(target.read_dir(Path::new("")).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn local_target_round_trip() {
+        let root = TempDir::new().unwrap();
+        round_trip(&LocalTarget::new(root.path().to_path_buf())).await;
+    }
+
+    #[tokio::test]
+    async fn memory_target_round_trip() {
+        round_trip(&MemoryTarget::new()).await;
+    }
+
+    #[tokio::test]
+    async fn remote_target_round_trip() {
+        let root = TempDir::new().unwrap();
+        let local = LocalTarget::new(root.path().to_path_buf());
+        let (client, server) = tokio::io::duplex(64 * 1024);
+        let (server_reader, server_writer) = tokio::io::split(server);
+        let agent = tokio::spawn(Agent::serve(local, None, server_reader, server_writer));
+
+        let (reader, writer) = tokio::io::split(client);
+        let target = RemoteTarget::connect("test".to_string(), reader, writer)
+            .await
+            .unwrap();
+        round_trip(&target).await;
+
+        drop(target);
+        agent.await.unwrap().unwrap();
+    }
+}
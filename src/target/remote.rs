@@ -0,0 +1,704 @@
+use std::ffi::OsString;
+use std::fmt::{Debug, Formatter};
+use std::io;
+use std::io::{ErrorKind, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::sync::Mutex;
+use std::time::{Duration, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use blake3::Hash;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, BufReader, BufWriter};
+use tokio::net::TcpStream;
+use tokio::process::{Child, Command};
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use crate::attributes::Attributes;
+use crate::delta::Delta;
+use crate::protocol::{
+    decode_os_str, encode_os_str, encode_path, read_frame, write_frame, Envelope, Request,
+    Response, ATTRIBUTES_PROTOCOL_VERSION, AUTH_PROTOCOL_VERSION, CHUNK_SIZE,
+    DELTA_PROTOCOL_VERSION, HARD_LINK_PROTOCOL_VERSION, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+    SYMLINK_PROTOCOL_VERSION,
+};
+use crate::target::{SyncTarget, TargetMetadata};
+use crate::utils::PathType;
+use crate::warn;
+
+/// Prefix of the targets served by `oxsync serve`
+pub const TCP_SCHEME: &str = "tcp://";
+
+/// Number of times an operation is sent again after the connection to the agent is lost
+const RETRIES: u32 = 3;
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// A target dir served by an `oxsync agent` or `oxsync serve`, each operation waiting for the
+/// acknowledgement of the agent. The concurrent operations use their own connection, up to
+/// `with_max_connections`. The connections are opened again when they are lost, and the
+/// transfers resume where they stopped
+pub struct RemoteTarget {
+    transport: Transport,
+    /// The open connections not used by an operation
+    idle: Mutex<Vec<Connection>>,
+    /// One permit per connection that can be used at the same time
+    permits: Semaphore,
+}
+
+enum Transport {
+    /// Command started with the root of the agent as last argument
+    Command {
+        command: String,
+        root: PathBuf,
+    },
+    Tcp {
+        addr: String,
+        token: String,
+    },
+    /// Connection opened by the caller, which can't be opened again
+    Streams {
+        description: String,
+    },
+}
+
+struct Connection {
+    reader: Box<dyn AsyncRead + Send + Unpin>,
+    writer: Box<dyn AsyncWrite + Send + Unpin>,
+    next_id: u64,
+    /// Version of the protocol agreed on during the handshake
+    version: u32,
+    /// Killed when the connection is dropped
+    _child: Option<Child>,
+}
+
+/// A connection used by an operation, given back to the pool when dropped unless it is lost
+struct PooledConnection<'a> {
+    idle: &'a Mutex<Vec<Connection>>,
+    connection: Option<Connection>,
+    _permit: SemaphorePermit<'a>,
+}
+
+impl RemoteTarget {
+    /// Start `command` with `root` as last argument, like `ssh host oxsync agent`, and talk to
+    /// the agent through its stdin and stdout. The command is split into words like a shell
+    /// would, quotes included, but it isn't run by a shell
+    pub async fn spawn(command: &str, root: &Path) -> io::Result<Self> {
+        let transport = Transport::Command {
+            command: command.to_string(),
+            root: root.to_path_buf(),
+        };
+        Self::open(transport).await
+    }
+
+    /// Connect to an `oxsync serve` listening on `addr`, given as `host:port` or
+    /// `tcp://host:port`, and authenticate with the `token` it was started with
+    pub async fn connect_tcp(addr: &str, token: &str) -> io::Result<Self> {
+        let addr = addr.strip_prefix(TCP_SCHEME).unwrap_or(addr);
+        let transport = Transport::Tcp {
+            addr: addr.to_string(),
+            token: token.to_string(),
+        };
+        Self::open(transport).await
+    }
+
+    /// Talk to an agent through an already open connection
+    pub async fn connect<R, W>(description: String, reader: R, writer: W) -> io::Result<Self>
+    where
+        R: AsyncRead + Send + Unpin + 'static,
+        W: AsyncWrite + Send + Unpin + 'static,
+    {
+        let mut connection = Connection {
+            reader: Box::new(reader),
+            writer: Box::new(writer),
+            next_id: 0,
+            version: 0,
+            _child: None,
+        };
+        connection.handshake(None).await?;
+
+        Ok(Self {
+            transport: Transport::Streams { description },
+            idle: Mutex::new(vec![connection]),
+            permits: Semaphore::new(1),
+        })
+    }
+
+    /// Open up to `max_connections` connections to the agent, one per concurrent operation. The
+    /// targets given an open connection keep using only this one
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        if !matches!(self.transport, Transport::Streams { .. }) {
+            self.permits = Semaphore::new(max_connections.max(1));
+        }
+        self
+    }
+
+    async fn open(transport: Transport) -> io::Result<Self> {
+        let connection = transport.connect().await?;
+
+        Ok(Self {
+            transport,
+            idle: Mutex::new(vec![connection]),
+            permits: Semaphore::new(1),
+        })
+    }
+
+    /// An idle connection, or a new one when they are all used or lost
+    async fn connection(&self) -> io::Result<PooledConnection<'_>> {
+        let permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("the semaphore is never closed");
+        let idle = self.idle.lock().unwrap().pop();
+        let connection = match idle {
+            Some(connection) => connection,
+            None => self.transport.connect().await?,
+        };
+
+        Ok(PooledConnection {
+            idle: &self.idle,
+            connection: Some(connection),
+            _permit: permit,
+        })
+    }
+
+    /// Send the request again when the connection can't be opened, or when it is lost and the
+    /// request is idempotent. The agent may have applied the others before the connection was lost
+    async fn request(&self, request: Request) -> io::Result<Response> {
+        let mut attempt = 0;
+        loop {
+            let result = match self.connection().await {
+                Ok(mut connection) => {
+                    let result = connection.get().exchange(request.clone()).await;
+                    if result.is_err() {
+                        connection.discard();
+                    }
+                    match result {
+                        Err(err) if !request.is_idempotent() => return Err(err),
+                        result => result,
+                    }
+                }
+                Err(err) => Err(err),
+            };
+
+            match result {
+                Ok(response) => return into_result(response),
+                Err(err) if attempt < RETRIES => self.wait_before_retry(err, &mut attempt).await,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    async fn request_done(&self, request: Request) -> io::Result<()> {
+        match self.request(request).await? {
+            Response::Done => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Agents older than `version` fail with `ErrorKind::Unsupported`
+    async fn require_version(&self, version: u32) -> io::Result<()> {
+        let mut connection = self.connection().await?;
+        if connection.get().version >= version {
+            Ok(())
+        } else {
+            Err(io::Error::new(
+                ErrorKind::Unsupported,
+                format!("the agent '{}' is too old", self.transport),
+            ))
+        }
+    }
+
+    async fn wait_before_retry(&self, err: io::Error, attempt: &mut u32) {
+        *attempt += 1;
+        warn!(
+            "connection to '{}' lost, retrying ({}/{}), error: {}",
+            self.transport, attempt, RETRIES, err
+        );
+        tokio::time::sleep(RETRY_DELAY).await;
+    }
+
+    /// Send the file by chunks and return its hash. The agent tells how many bytes it already
+    /// has, when a previous attempt was interrupted, these are only read to be hashed. The outer
+    /// error means that the connection is lost
+    async fn upload(
+        connection: &mut Connection,
+        path: &[u8],
+        file: &mut File,
+        len: u64,
+        transfer_id: &str,
+    ) -> io::Result<Result<Hash, io::Error>> {
+        let response = connection
+            .exchange(Request::WriteStart {
+                path: path.to_vec(),
+                len,
+                transfer_id: transfer_id.to_string(),
+            })
+            .await?;
+        let resume_offset = match into_result(response) {
+            Ok(Response::Resume { offset }) => offset,
+            Ok(response) => return Ok(Err(unexpected(response))),
+            Err(err) => return Ok(Err(err)),
+        };
+
+        if let Err(err) = file.seek(SeekFrom::Start(0)).await {
+            return Ok(Err(err));
+        }
+        let mut hasher = blake3::Hasher::new();
+        let mut buffer = vec![0; CHUNK_SIZE];
+        let mut offset = 0;
+        while offset < len {
+            let max_len = CHUNK_SIZE.min((len - offset) as usize);
+            let read = match file.read(&mut buffer[..max_len]).await {
+                Ok(read) => read,
+                Err(err) => return Ok(Err(err)),
+            };
+            if read == 0 {
+                return Ok(Err(io::Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "the file was truncated during the transfer",
+                )));
+            }
+            hasher.update(&buffer[..read]);
+
+            // The part of the chunk received by a previous attempt isn't sent again
+            let skipped = resume_offset.saturating_sub(offset).min(read as u64) as usize;
+            if skipped < read {
+                let request = Request::WriteChunk {
+                    offset: offset + skipped as u64,
+                    data: buffer[skipped..read].to_vec(),
+                };
+                if let Err(err) = into_result(connection.exchange(request).await?) {
+                    return Ok(Err(err));
+                }
+            }
+            offset += read as u64;
+        }
+
+        let response = connection.exchange(Request::WriteEnd).await?;
+        Ok(into_result(response).map(|_| hasher.finalize()))
+    }
+
+    /// Send the changed blocks, then the length which moves the patched copy of the agent over
+    /// the file. The copy is lost with the connection, all the blocks are sent again on the next
+    /// one. The outer error means that the connection is lost
+    async fn patch(
+        connection: &mut Connection,
+        path: &[u8],
+        file: &mut File,
+        changed: &[usize],
+        block_size: usize,
+        len: u64,
+    ) -> io::Result<io::Result<()>> {
+        let mut buffer = vec![0; block_size];
+        for &index in changed {
+            let offset = (index * block_size) as u64;
+            let read = async {
+                file.seek(SeekFrom::Start(offset)).await?;
+                Delta::read_block(file, &mut buffer).await
+            }
+            .await;
+            let read = match read {
+                Ok(read) => read,
+                Err(err) => return Ok(Err(err)),
+            };
+
+            let request = Request::WriteBlock {
+                path: path.to_vec(),
+                offset,
+                data: buffer[..read].to_vec(),
+            };
+            if let Err(err) = into_result(connection.exchange(request).await?) {
+                return Ok(Err(err));
+            }
+        }
+
+        let request = Request::SetLen {
+            path: path.to_vec(),
+            len,
+        };
+        Ok(into_result(connection.exchange(request).await?).map(|_| ()))
+    }
+}
+
+impl Transport {
+    async fn connect(&self) -> io::Result<Connection> {
+        let mut token = None;
+        let mut connection = match self {
+            Transport::Command { command, root } => {
+                let Some(parts) = shlex::split(command) else {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidInput,
+                        "unterminated quote or escape in the command",
+                    ));
+                };
+                let mut parts = parts.into_iter();
+                let Some(program) = parts.next() else {
+                    return Err(io::Error::new(ErrorKind::InvalidInput, "empty command"));
+                };
+
+                let mut child = Command::new(program)
+                    .args(parts)
+                    .arg(root)
+                    .stdin(Stdio::piped())
+                    .stdout(Stdio::piped())
+                    .kill_on_drop(true)
+                    .spawn()?;
+
+                Connection {
+                    reader: Box::new(BufReader::new(child.stdout.take().unwrap())),
+                    writer: Box::new(child.stdin.take().unwrap()),
+                    next_id: 0,
+                    version: 0,
+                    _child: Some(child),
+                }
+            }
+            Transport::Tcp {
+                addr,
+                token: tcp_token,
+            } => {
+                token = Some(tcp_token.as_str());
+                let stream = TcpStream::connect(addr).await?;
+                stream.set_nodelay(true)?;
+                let (reader, writer) = stream.into_split();
+
+                Connection {
+                    reader: Box::new(BufReader::new(reader)),
+                    writer: Box::new(BufWriter::new(writer)),
+                    next_id: 0,
+                    version: 0,
+                    _child: None,
+                }
+            }
+            Transport::Streams { description } => {
+                return Err(io::Error::new(
+                    ErrorKind::NotConnected,
+                    format!("the connection to '{}' can't be opened again", description),
+                ))
+            }
+        };
+        connection.handshake(token).await?;
+
+        Ok(connection)
+    }
+}
+
+impl std::fmt::Display for Transport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Transport::Command { command, .. } => write!(f, "{}", command),
+            Transport::Tcp { addr, .. } => write!(f, "{}{}", TCP_SCHEME, addr),
+            Transport::Streams { description } => write!(f, "{}", description),
+        }
+    }
+}
+
+impl Connection {
+    /// Agree on the version of the protocol with the agent, then give it the `token` it requires
+    async fn handshake(&mut self, token: Option<&str>) -> io::Result<()> {
+        let response = self
+            .exchange(Request::Hello {
+                min_version: MIN_PROTOCOL_VERSION,
+                max_version: PROTOCOL_VERSION,
+            })
+            .await?;
+
+        match into_result(response)? {
+            Response::Welcome { version }
+                if (MIN_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&version) =>
+            {
+                self.version = version;
+            }
+            response => return Err(unexpected(response)),
+        }
+
+        let Some(token) = token else {
+            return Ok(());
+        };
+        if self.version < AUTH_PROTOCOL_VERSION {
+            return Err(io::Error::new(
+                ErrorKind::Unsupported,
+                "the agent is too old to authenticate",
+            ));
+        }
+        let request = Request::Authenticate {
+            token: token.to_string(),
+        };
+        match into_result(self.exchange(request).await?)? {
+            Response::Done => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Send a request and wait for its acknowledgement. Errors mean that the connection is lost,
+    /// the errors of the agent are part of the response
+    async fn exchange(&mut self, request: Request) -> io::Result<Response> {
+        let id = self.next_id;
+        self.next_id += 1;
+        write_frame(&mut self.writer, &Envelope { id, body: request }).await?;
+
+        match read_frame::<_, Envelope<Response>>(&mut self.reader).await? {
+            Some(envelope) if envelope.id == id => Ok(envelope.body),
+            Some(envelope) => Err(io::Error::new(
+                ErrorKind::InvalidData,
+                format!("response {} received instead of {}", envelope.id, id),
+            )),
+            None => Err(io::Error::new(
+                ErrorKind::UnexpectedEof,
+                "the agent closed the connection",
+            )),
+        }
+    }
+}
+
+impl PooledConnection<'_> {
+    fn get(&mut self) -> &mut Connection {
+        self.connection.as_mut().unwrap()
+    }
+
+    /// Drop the connection instead of giving it back, once it is lost
+    fn discard(&mut self) {
+        self.connection = None;
+    }
+}
+
+impl Drop for PooledConnection<'_> {
+    fn drop(&mut self) {
+        if let Some(connection) = self.connection.take() {
+            self.idle.lock().unwrap().push(connection);
+        }
+    }
+}
+
+impl Debug for RemoteTarget {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("RemoteTarget")
+            .field("transport", &self.transport.to_string())
+            .finish()
+    }
+}
+
+#[async_trait]
+impl SyncTarget for RemoteTarget {
+    async fn write_file(&self, path: &Path, src_path: &Path) -> io::Result<Hash> {
+        let mut file = File::open(src_path).await?;
+        let metadata = file.metadata().await?;
+        let len = metadata.len();
+        // A new transfer starts when the source file changed since the interrupted one
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        let transfer_id = format!("{}-{}", len, modified.as_nanos());
+        let path = encode_path(path);
+
+        let mut attempt = 0;
+        loop {
+            let result = match self.connection().await {
+                Ok(mut connection) => {
+                    let result =
+                        Self::upload(connection.get(), &path, &mut file, len, &transfer_id).await;
+                    if result.is_err() {
+                        connection.discard();
+                    }
+                    result
+                }
+                Err(err) => Err(err),
+            };
+
+            match result {
+                Ok(result) => return result,
+                Err(err) if attempt < RETRIES => self.wait_before_retry(err, &mut attempt).await,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    async fn create_dir(&self, path: &Path) -> io::Result<()> {
+        self.request_done(Request::CreateDir {
+            path: encode_path(path),
+        })
+        .await
+    }
+
+    async fn remove_file(&self, path: &Path) -> io::Result<()> {
+        self.request_done(Request::RemoveFile {
+            path: encode_path(path),
+        })
+        .await
+    }
+
+    async fn remove_dir(&self, path: &Path) -> io::Result<()> {
+        self.request_done(Request::RemoveDir {
+            path: encode_path(path),
+        })
+        .await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        self.request_done(Request::Rename {
+            from: encode_path(from),
+            to: encode_path(to),
+        })
+        .await
+    }
+
+    async fn create_symlink(&self, path: &Path, link: &Path) -> io::Result<()> {
+        self.require_version(SYMLINK_PROTOCOL_VERSION).await?;
+        self.request_done(Request::CreateSymlink {
+            path: encode_path(path),
+            link: encode_os_str(link.as_os_str()),
+        })
+        .await
+    }
+
+    async fn read_link(&self, path: &Path) -> io::Result<PathBuf> {
+        self.require_version(SYMLINK_PROTOCOL_VERSION).await?;
+        let request = Request::ReadLink {
+            path: encode_path(path),
+        };
+        match self.request(request).await? {
+            Response::Link(link) => Ok(PathBuf::from(decode_os_str(&link)?)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    async fn hard_link(&self, path: &Path, original: &Path) -> io::Result<()> {
+        self.require_version(HARD_LINK_PROTOCOL_VERSION).await?;
+        self.request_done(Request::HardLink {
+            path: encode_path(path),
+            original: encode_path(original),
+        })
+        .await
+    }
+
+    async fn same_file(&self, path: &Path, other: &Path) -> io::Result<bool> {
+        self.require_version(HARD_LINK_PROTOCOL_VERSION).await?;
+        let request = Request::SameFile {
+            path: encode_path(path),
+            other: encode_path(other),
+        };
+        match self.request(request).await? {
+            Response::SameFile(same_file) => Ok(same_file),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    async fn stat(&self, path: &Path) -> io::Result<TargetMetadata> {
+        let request = Request::Stat {
+            path: encode_path(path),
+        };
+        match self.request(request).await? {
+            Response::Stat(metadata) => Ok(metadata),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    async fn hash(&self, path: &Path) -> io::Result<Hash> {
+        let request = Request::Hash {
+            path: encode_path(path),
+        };
+        match self.request(request).await? {
+            Response::Hash(bytes) => Ok(Hash::from(bytes)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    async fn read_dir(&self, path: &Path) -> io::Result<Vec<(OsString, PathType)>> {
+        let request = Request::ReadDir {
+            path: encode_path(path),
+        };
+        match self.request(request).await? {
+            Response::Entries(entries) => entries
+                .into_iter()
+                .map(|(name, path_type)| Ok((decode_os_str(&name)?, path_type)))
+                .collect(),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    async fn block_hashes(&self, path: &Path, block_size: usize) -> io::Result<Vec<Hash>> {
+        self.require_version(DELTA_PROTOCOL_VERSION).await?;
+        let request = Request::BlockHashes {
+            path: encode_path(path),
+            block_size: block_size as u64,
+        };
+        match self.request(request).await? {
+            Response::BlockHashes(hashes) => Ok(hashes.into_iter().map(Hash::from).collect()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    async fn write_blocks(
+        &self,
+        path: &Path,
+        src_path: &Path,
+        changed: &[usize],
+        block_size: usize,
+        len: u64,
+    ) -> io::Result<()> {
+        self.require_version(DELTA_PROTOCOL_VERSION).await?;
+        let path = encode_path(path);
+        let mut file = File::open(src_path).await?;
+
+        let mut attempt = 0;
+        loop {
+            let result = match self.connection().await {
+                Ok(mut connection) => {
+                    let result =
+                        Self::patch(connection.get(), &path, &mut file, changed, block_size, len)
+                            .await;
+                    if result.is_err() {
+                        connection.discard();
+                    }
+                    result
+                }
+                Err(err) => Err(err),
+            };
+
+            match result {
+                Ok(result) => return result,
+                Err(err) if attempt < RETRIES => self.wait_before_retry(err, &mut attempt).await,
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    async fn set_attributes(&self, path: &Path, attributes: &Attributes) -> io::Result<()> {
+        self.require_version(ATTRIBUTES_PROTOCOL_VERSION).await?;
+        self.request_done(Request::SetAttributes {
+            path: encode_path(path),
+            attributes: attributes.clone(),
+        })
+        .await
+    }
+
+    /// The agent writes the transferred files to its state dir and cleans it itself
+    async fn remove_temp_files(&self) -> io::Result<usize> {
+        Ok(0)
+    }
+}
+
+/// The errors sent by the agent
+fn into_result(response: Response) -> io::Result<Response> {
+    match response {
+        Response::Error { not_found, message } => {
+            let kind = if not_found {
+                ErrorKind::NotFound
+            } else {
+                ErrorKind::Other
+            };
+            Err(io::Error::new(kind, message))
+        }
+        response => Ok(response),
+    }
+}
+
+fn unexpected(response: Response) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("unexpected response from the agent: {:?}", response),
+    )
+}
@@ -0,0 +1,226 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use time::{Date, Duration, Month, OffsetDateTime};
+
+use crate::pair::SyncPair;
+use crate::target::{SyncTarget, TRASH_DIR_NAME};
+use crate::utils::{PathType, Utils};
+use crate::{err, info};
+
+/// The deleted paths of a target are moved to a dir of its trash named after the day they were
+/// deleted, `<TRASH_DIR_NAME>/<YYYY-MM-DD>/<path>`, and removed for good once it is too old
+pub(crate) struct Trash;
+
+impl Trash {
+    /// Remove the path from `dir`, or move it to the trash of `dir` when it is enabled
+    pub async fn remove(
+        pair: &SyncPair,
+        dir: &dyn SyncTarget,
+        relative_path: &Path,
+        path_type: &PathType,
+    ) -> io::Result<()> {
+        if !pair.config.trash {
+            return match path_type {
+                PathType::Dir => dir.remove_dir(relative_path).await,
+                PathType::File | PathType::Symlink => dir.remove_file(relative_path).await,
+            };
+        }
+
+        let mut trash_path = Path::new(TRASH_DIR_NAME)
+            .join(Self::day_name(Self::today()))
+            .join(relative_path);
+        // Deleted again the same day
+        if let Ok(trash_metadata) = dir.stat(&trash_path).await {
+            // Like the dir of paths deleted before it, it is merged into the one in the trash
+            if *path_type == PathType::Dir && trash_metadata.path_type == PathType::Dir {
+                for (name, entry_type) in dir.read_dir(relative_path).await? {
+                    Box::pin(Self::remove(
+                        pair,
+                        dir,
+                        &relative_path.join(name),
+                        &entry_type,
+                    ))
+                    .await?;
+                }
+                return dir.remove_dir(relative_path).await;
+            }
+            trash_path = Utils::timestamped_path(&trash_path, "deleted");
+        }
+        dir.create_dir(trash_path.parent().unwrap()).await?;
+        dir.rename(relative_path, &trash_path).await
+    }
+
+    /// Remove the days of the trash of `dir` older than the retention period
+    pub async fn purge(pair: &SyncPair, dir: &dyn SyncTarget) {
+        if !pair.config.trash {
+            return;
+        }
+        let Ok(entries) = dir.read_dir(Path::new(TRASH_DIR_NAME)).await else {
+            return;
+        };
+        let retention = Duration::days(pair.config.trash_retention.min(u64::from(u32::MAX)) as i64);
+        let oldest_day = Self::today().checked_sub(retention).unwrap_or(Date::MIN);
+
+        for (name, path_type) in entries {
+            let Some(day) = name.to_str().and_then(Self::parse_day) else {
+                continue;
+            };
+            if path_type != PathType::Dir || day >= oldest_day {
+                continue;
+            }
+
+            let day_path = PathBuf::from(TRASH_DIR_NAME).join(&name);
+            match dir.remove_dir(&day_path).await {
+                Ok(()) => info!(
+                    "{}trash of {} purged",
+                    pair.log_prefix(),
+                    Self::day_name(day)
+                ),
+                Err(err) => err!(
+                    "{}failed to purge the trash of {}, error: {}",
+                    pair.log_prefix(),
+                    Self::day_name(day),
+                    err.to_string()
+                ),
+            }
+        }
+    }
+
+    fn today() -> Date {
+        OffsetDateTime::now_local()
+            .unwrap_or_else(|_| OffsetDateTime::now_utc())
+            .date()
+    }
+
+    fn day_name(day: Date) -> String {
+        format!(
+            "{:04}-{:02}-{:02}",
+            day.year(),
+            u8::from(day.month()),
+            day.day()
+        )
+    }
+
+    /// The other names found in the trash are left alone
+    fn parse_day(name: &str) -> Option<Date> {
+        let mut parts = name.splitn(3, '-');
+        let year = parts.next()?.parse().ok()?;
+        let month = Month::try_from(parts.next()?.parse::<u8>().ok()?).ok()?;
+        let day = parts.next()?.parse().ok()?;
+        Date::from_calendar_date(year, month, day)
+            .ok()
+            .filter(|date| Self::day_name(*date) == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::config::SyncConfig;
+    use crate::target::{MemoryTarget, SyncTarget};
+
+    fn memory_pair(source: &TempDir, trash: bool) -> (SyncPair, Arc<MemoryTarget>) {
+        let mut config = SyncConfig::new(source.path().to_path_buf(), PathBuf::new());
+        config.trash = trash;
+        let target = Arc::new(MemoryTarget::new());
+        let pair = SyncPair::new(config, None, target.clone()).unwrap();
+        (pair, target)
+    }
+
+    async fn write(target: &MemoryTarget, path: &str, content: &[u8]) {
+        let src = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(src.path(), content).unwrap();
+        let path = Path::new(path);
+        target.create_dir(path.parent().unwrap()).await.unwrap();
+        target.write_file(path, src.path()).await.unwrap();
+    }
+
+    fn today_path(path: &str) -> PathBuf {
+        Path::new(TRASH_DIR_NAME)
+            .join(Trash::day_name(Trash::today()))
+            .join(path)
+    }
+
+    #[tokio::test]
+    async fn removed_paths_are_moved_to_the_day_of_the_trash() {
+        let source = TempDir::new().unwrap();
+        let (pair, target) = memory_pair(&source, true);
+        write(&target, "dir/f", b"first").await;
+
+        Trash::remove(&pair, target.as_ref(), Path::new("dir/f"), &PathType::File)
+            .await
+            .unwrap();
+        assert!(target.stat(Path::new("dir/f")).await.is_err());
+        assert_eq!(target.read(&today_path("dir/f")), Some(b"first".to_vec()));
+
+        // Deleted again the same day, the first one is kept
+        write(&target, "dir/f", b"second").await;
+        Trash::remove(&pair, target.as_ref(), Path::new("dir/f"), &PathType::File)
+            .await
+            .unwrap();
+        assert_eq!(target.read(&today_path("dir/f")), Some(b"first".to_vec()));
+        let trashed = target.read_dir(&today_path("dir")).await.unwrap();
+        assert_eq!(trashed.len(), 2);
+
+        // Merged into the dir already in the trash
+        write(&target, "dir/g", b"g").await;
+        Trash::remove(&pair, target.as_ref(), Path::new("dir"), &PathType::Dir)
+            .await
+            .unwrap();
+        assert!(target.stat(Path::new("dir")).await.is_err());
+        assert_eq!(target.read(&today_path("dir/g")), Some(b"g".to_vec()));
+        assert_eq!(target.read(&today_path("dir/f")), Some(b"first".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn removed_paths_are_deleted_without_the_trash() {
+        let source = TempDir::new().unwrap();
+        let (pair, target) = memory_pair(&source, false);
+        write(&target, "dir/f", b"f").await;
+
+        Trash::remove(&pair, target.as_ref(), Path::new("dir"), &PathType::Dir)
+            .await
+            .unwrap();
+        assert!(target.snapshot().is_empty());
+    }
+
+    #[tokio::test]
+    async fn days_older_than_the_retention_are_purged() {
+        let source = TempDir::new().unwrap();
+        let (pair, target) = memory_pair(&source, true);
+        assert_eq!(pair.config.trash_retention, 30);
+        let day = |days_ago| Trash::day_name(Trash::today() - Duration::days(days_ago));
+        for name in [day(31), day(30), day(0), "notes".to_string()] {
+            write(&target, &format!("{}/{}/f", TRASH_DIR_NAME, name), b"f").await;
+        }
+
+        Trash::purge(&pair, target.as_ref()).await;
+        let mut names: Vec<_> = target
+            .read_dir(Path::new(TRASH_DIR_NAME))
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|(name, _)| name.into_string().unwrap())
+            .collect();
+        names.sort();
+        let mut expected = vec![day(30), day(0), "notes".to_string()];
+        expected.sort();
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn only_the_day_names_are_parsed() {
+        let day = Date::from_calendar_date(2024, Month::January, 5).unwrap();
+
+        assert_eq!(Trash::parse_day("2024-01-05"), Some(day));
+        assert_eq!(Trash::parse_day(&Trash::day_name(day)), Some(day));
+        assert_eq!(Trash::parse_day("2024-1-5"), None);
+        assert_eq!(Trash::parse_day("2024-02-30"), None);
+        assert_eq!(Trash::parse_day("notes"), None);
+    }
+}
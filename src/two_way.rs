@@ -0,0 +1,837 @@
+use std::collections::{BTreeMap, HashSet};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use blake3::Hash;
+use notify::Event;
+use tokio::time::Instant;
+
+use crate::config::SymlinkPolicy;
+use crate::file_operations::handle_remove_err;
+use crate::filter::FilterDecision;
+use crate::pair::SyncPair;
+use crate::scan::ScanSummary;
+use crate::target::{LocalTarget, SyncTarget, TargetMetadata};
+use crate::trash::Trash;
+use crate::utils::{FileStore, PathMetadata, PathType, Utils};
+use crate::{err, info, warn};
+
+/// Syncs the changes of the source dir and of the target dir both ways. The hashes of the file
+/// store tell which side changed since the last sync, a file changed on both sides is a conflict
+pub(crate) struct TwoWay;
+
+/// What was done to sync a path
+#[derive(Debug, PartialEq)]
+enum Outcome {
+    Identical,
+    Copied,
+    /// A dir on both sides, just `created` on one of them
+    Dir {
+        created: bool,
+    },
+    Deleted,
+    Conflict,
+    /// A dir deleted on one side while its content changed on the other, copied back to it
+    RestoredDir,
+    Failed,
+    Skipped,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Side {
+    Source,
+    Target,
+}
+
+impl TwoWay {
+    /// Walk both dirs and sync the changes made on each side since the last sync. The paths of
+    /// the file store which weren't found are removed from it
+    pub async fn reconcile(pair: &SyncPair, file_store: &FileStore) -> ScanSummary {
+        let start_time = Instant::now();
+        let mut summary = ScanSummary::default();
+        let mut synced_paths = HashSet::new();
+        Self::sync_tree(
+            pair,
+            file_store,
+            PathBuf::new(),
+            &mut summary,
+            &mut synced_paths,
+        )
+        .await;
+        file_store.retain(|path, _| synced_paths.contains(path));
+
+        info!(
+            "{}Two-way sync done in {} ms - {} copied, {} dirs created, {} deleted, {} identical, {} conflicts, {} failed",
+            pair.log_prefix(),
+            start_time.elapsed().as_millis(),
+            summary.copied,
+            summary.created,
+            summary.deleted,
+            summary.identical,
+            summary.conflicts,
+            summary.failed
+        );
+
+        summary
+    }
+
+    /// Sync the paths of a change of either dir, the changes of the target dir being given with
+    /// the matching paths of the source dir
+    pub async fn handle_event(
+        pair: &SyncPair,
+        file_store: &FileStore,
+        emit_time: Instant,
+        event: Event,
+    ) {
+        for path in event.paths {
+            let v_path = Utils::path_to_verbatim(&path);
+            let relative_path = pair.relative_path(&v_path).to_path_buf();
+
+            let outcome = Self::sync_path(pair, file_store, &relative_path, emit_time).await;
+            // The content of a dir moved into one side comes without events of its own
+            if matches!(
+                outcome,
+                Outcome::Dir { created: true } | Outcome::RestoredDir
+            ) {
+                let mut summary = ScanSummary::default();
+                let mut synced_paths = HashSet::new();
+                Self::sync_tree(
+                    pair,
+                    file_store,
+                    relative_path,
+                    &mut summary,
+                    &mut synced_paths,
+                )
+                .await;
+            }
+        }
+    }
+
+    /// Sync the content of the dir `relative_path`, found on both sides
+    async fn sync_tree(
+        pair: &SyncPair,
+        file_store: &FileStore,
+        relative_path: PathBuf,
+        summary: &mut ScanSummary,
+        synced_paths: &mut HashSet<PathBuf>,
+    ) {
+        let source = Self::source(pair);
+        // Along with the dirs the followed symlinks on the way point to, on each side
+        let followed_dirs = [
+            vec![pair.config.source_dir.clone()],
+            vec![pair.config.target_dir.clone()],
+        ];
+        let mut dirs_to_visit = vec![(relative_path, followed_dirs)];
+
+        while let Some((dir, followed_dirs)) = dirs_to_visit.pop() {
+            // A name missing from a side means the path was deleted there, both must be read
+            let entries = match (
+                source.read_dir(&dir).await,
+                pair.target.read_dir(&dir).await,
+            ) {
+                (Ok(source_entries), Ok(target_entries)) => [source_entries, target_entries],
+                (Err(err), _) | (_, Err(err)) => {
+                    err!(
+                        "{}failed to read dir '{}', error: {}",
+                        pair.log_prefix(),
+                        Utils::fmt_path(&pair.config.source_dir.join(&dir)),
+                        err.to_string()
+                    );
+                    summary.failed += 1;
+                    continue;
+                }
+            };
+            // Whether the name is a dir on either side
+            let mut names = BTreeMap::new();
+            for (name, path_type) in entries.into_iter().flatten() {
+                *names.entry(name).or_insert(false) |= path_type == PathType::Dir;
+            }
+
+            for (name, is_dir) in names {
+                let relative_path = dir.join(name);
+                let mut followed_dirs = followed_dirs.clone();
+                let [source_followed_dirs, target_followed_dirs] = &mut followed_dirs;
+                if is_dir
+                    && (Utils::is_symlink_loop(
+                        pair,
+                        &pair.config.source_dir.join(&relative_path),
+                        source_followed_dirs,
+                    )
+                    .await
+                        || Utils::is_symlink_loop(
+                            pair,
+                            &pair.config.target_dir.join(&relative_path),
+                            target_followed_dirs,
+                        )
+                        .await)
+                {
+                    continue;
+                }
+                match pair.filter().decision(&relative_path, is_dir) {
+                    FilterDecision::Included => {}
+                    FilterDecision::NotIncluded if is_dir => {
+                        // Included paths may still be found below
+                        dirs_to_visit.push((relative_path, followed_dirs));
+                        continue;
+                    }
+                    _ => continue,
+                }
+
+                let outcome =
+                    Self::sync_path(pair, file_store, &relative_path, Instant::now()).await;
+                if !matches!(outcome, Outcome::Deleted | Outcome::Skipped) {
+                    synced_paths.insert(pair.config.source_dir.join(&relative_path));
+                }
+                match outcome {
+                    Outcome::Identical => summary.identical += 1,
+                    Outcome::Copied => summary.copied += 1,
+                    Outcome::Dir { created } => {
+                        if created {
+                            summary.created += 1;
+                        }
+                        dirs_to_visit.push((relative_path, followed_dirs));
+                    }
+                    Outcome::Deleted => summary.deleted += 1,
+                    Outcome::Conflict => summary.conflicts += 1,
+                    Outcome::RestoredDir => {
+                        summary.conflicts += 1;
+                        dirs_to_visit.push((relative_path, followed_dirs));
+                    }
+                    Outcome::Failed => summary.failed += 1,
+                    Outcome::Skipped => {}
+                }
+            }
+        }
+    }
+
+    /// Sync `relative_path` from the side it changed on since the last sync
+    async fn sync_path(
+        pair: &SyncPair,
+        file_store: &FileStore,
+        relative_path: &Path,
+        emit_time: Instant,
+    ) -> Outcome {
+        let v_path = pair.config.source_dir.join(relative_path);
+        let path_str = &Utils::fmt_path(relative_path);
+        let src_metadata = Self::source(pair).stat(relative_path).await.ok();
+        let dest_metadata = pair.target.stat(relative_path).await.ok();
+
+        let is_dir = [&src_metadata, &dest_metadata]
+            .into_iter()
+            .flatten()
+            .any(|metadata| metadata.path_type == PathType::Dir);
+        if relative_path.as_os_str().is_empty() || !pair.is_synced(relative_path, is_dir) {
+            return Outcome::Skipped;
+        }
+        // Skipped symlinks are left alone on both sides
+        let is_symlink = [&src_metadata, &dest_metadata]
+            .into_iter()
+            .flatten()
+            .any(|metadata| metadata.path_type == PathType::Symlink);
+        if is_symlink && pair.config.symlinks == SymlinkPolicy::Skip {
+            return Outcome::Skipped;
+        }
+        let known = file_store.get(&v_path);
+
+        match (src_metadata, dest_metadata) {
+            (None, None) => {
+                file_store.remove_all(&v_path);
+                Outcome::Skipped
+            }
+            (Some(src_metadata), Some(dest_metadata))
+                if src_metadata.path_type != dest_metadata.path_type =>
+            {
+                err!(
+                    "{}'{}' is a {} on one side and a {} on the other, it is not synced",
+                    pair.log_prefix(),
+                    path_str,
+                    type_str(&src_metadata.path_type),
+                    type_str(&dest_metadata.path_type)
+                );
+                Outcome::Failed
+            }
+            (Some(src_metadata), Some(_)) if src_metadata.path_type == PathType::Dir => {
+                Self::store_dir(file_store, v_path);
+                Outcome::Dir { created: false }
+            }
+            (Some(src_metadata), Some(dest_metadata)) => {
+                let known = known.filter(|known| known.path_type == src_metadata.path_type);
+                Self::sync_file(
+                    pair,
+                    file_store,
+                    relative_path,
+                    known.as_ref(),
+                    &src_metadata,
+                    &dest_metadata,
+                    emit_time,
+                )
+                .await
+            }
+            (Some(src_metadata), None) => {
+                let known = known.filter(|known| known.path_type == src_metadata.path_type);
+                // A file modified since it was deleted on the target is copied again
+                let is_dir = src_metadata.path_type == PathType::Dir;
+                let deleted_on_target = match &known {
+                    None => false,
+                    Some(_) if is_dir => {
+                        Self::is_unchanged_dir(pair, file_store, relative_path, Side::Source).await
+                    }
+                    Some(known) => {
+                        Self::source_hash(pair, Some(known), relative_path, &src_metadata)
+                            .await
+                            .ok()
+                            == known.hash
+                    }
+                };
+
+                if deleted_on_target {
+                    Self::remove(pair, file_store, relative_path, Side::Source, emit_time).await
+                } else if known.is_some() && is_dir {
+                    Self::restore_dir(pair, file_store, relative_path, Side::Source, emit_time)
+                        .await
+                } else {
+                    Self::copy(pair, file_store, relative_path, Side::Source, emit_time).await
+                }
+            }
+            (None, Some(dest_metadata)) => {
+                let known = known.filter(|known| known.path_type == dest_metadata.path_type);
+                let is_dir = dest_metadata.path_type == PathType::Dir;
+                let deleted_on_source = match &known {
+                    None => false,
+                    Some(_) if is_dir => {
+                        Self::is_unchanged_dir(pair, file_store, relative_path, Side::Target).await
+                    }
+                    Some(known) => {
+                        Self::target_hash(pair, Some(known), relative_path, &dest_metadata)
+                            .await
+                            .ok()
+                            == known.target_hash
+                    }
+                };
+
+                if deleted_on_source {
+                    Self::remove(pair, file_store, relative_path, Side::Target, emit_time).await
+                } else if known.is_some() && is_dir {
+                    Self::restore_dir(pair, file_store, relative_path, Side::Target, emit_time)
+                        .await
+                } else {
+                    Self::copy(pair, file_store, relative_path, Side::Target, emit_time).await
+                }
+            }
+        }
+    }
+
+    /// Copy the file found on both sides from the one which changed since the last sync
+    async fn sync_file(
+        pair: &SyncPair,
+        file_store: &FileStore,
+        relative_path: &Path,
+        known: Option<&PathMetadata>,
+        src_metadata: &TargetMetadata,
+        dest_metadata: &TargetMetadata,
+        emit_time: Instant,
+    ) -> Outcome {
+        let hashes = (
+            Self::source_hash(pair, known, relative_path, src_metadata).await,
+            Self::target_hash(pair, known, relative_path, dest_metadata).await,
+        );
+        let (src_hash, dest_hash) = match hashes {
+            (Ok(src_hash), Ok(dest_hash)) => (src_hash, dest_hash),
+            (Err(err), _) | (_, Err(err)) => {
+                err!(
+                    "{}failed to hash '{}', error: {}",
+                    pair.log_prefix(),
+                    Utils::fmt_path(relative_path),
+                    err.to_string()
+                );
+                return Outcome::Failed;
+            }
+        };
+
+        if src_hash == dest_hash {
+            let v_path = pair.config.source_dir.join(relative_path);
+            let path_type = src_metadata.path_type.clone();
+            Self::store_file(
+                file_store,
+                v_path,
+                path_type,
+                src_hash,
+                Some(src_metadata),
+                Some(dest_metadata),
+            );
+            return Outcome::Identical;
+        }
+
+        // Never synced, both sides are new
+        let src_changed = known.is_none_or(|known| known.hash != Some(src_hash));
+        let dest_changed = known.is_none_or(|known| known.target_hash != Some(dest_hash));
+        match (src_changed, dest_changed) {
+            (true, false) => {
+                Self::copy(pair, file_store, relative_path, Side::Source, emit_time).await
+            }
+            (false, true) => {
+                Self::copy(pair, file_store, relative_path, Side::Target, emit_time).await
+            }
+            _ => Self::conflict(pair, file_store, relative_path, emit_time).await,
+        }
+    }
+
+    /// Keep the target copy next to the file as `<name>.conflict-<timestamp>` on both sides, then
+    /// copy the source file over the target one
+    async fn conflict(
+        pair: &SyncPair,
+        file_store: &FileStore,
+        relative_path: &Path,
+        emit_time: Instant,
+    ) -> Outcome {
+        let path_str = &Utils::fmt_path(relative_path);
+        let conflict_path = Utils::timestamped_path(relative_path, "conflict");
+
+        if let Err(err) = pair.target.rename(relative_path, &conflict_path).await {
+            err!(
+                "{}failed to keep the target copy of '{}' in conflict, error: {}",
+                pair.log_prefix(),
+                path_str,
+                err.to_string()
+            );
+            return Outcome::Failed;
+        }
+        warn!(
+            "{}conflict on '{}', changed on both sides since the last sync, the target copy is kept as '{}'",
+            pair.log_prefix(),
+            path_str,
+            Utils::fmt_path(&conflict_path)
+        );
+
+        Self::copy(pair, file_store, &conflict_path, Side::Target, emit_time).await;
+        match Self::copy(pair, file_store, relative_path, Side::Source, emit_time).await {
+            Outcome::Failed => Outcome::Failed,
+            _ => Outcome::Conflict,
+        }
+    }
+
+    /// Copy the file or create the dir `relative_path` of the `from` side to the other one
+    async fn copy(
+        pair: &SyncPair,
+        file_store: &FileStore,
+        relative_path: &Path,
+        from: Side,
+        emit_time: Instant,
+    ) -> Outcome {
+        let source = Self::source(pair);
+        let (from_dir, from_side, to): (&Path, &dyn SyncTarget, &dyn SyncTarget) = match from {
+            Side::Source => (&pair.config.source_dir, &source, pair.target.as_ref()),
+            Side::Target => (&pair.config.target_dir, pair.target.as_ref(), &source),
+        };
+        let v_path = pair.config.source_dir.join(relative_path);
+        let path_str = &Utils::fmt_path(relative_path);
+        let from_path = from_dir.join(relative_path);
+        let from_type = from_side
+            .stat(relative_path)
+            .await
+            .map(|metadata| metadata.path_type);
+
+        if let Ok(PathType::Symlink) = from_type {
+            let parent = relative_path.parent().unwrap_or(Path::new(""));
+            let created = match from_side.read_link(relative_path).await {
+                Ok(link) => {
+                    let link = Utils::synced_link(pair, from_dir, relative_path, link);
+                    match to.create_dir(parent).await {
+                        Ok(()) => to.create_symlink(relative_path, &link).await.map(|()| link),
+                        Err(err) => Err(err),
+                    }
+                }
+                Err(err) => Err(err),
+            };
+            let link = match created {
+                Ok(link) => link,
+                Err(err) => {
+                    err!(
+                        "{}failed to copy '{}', error: {}",
+                        pair.log_prefix(),
+                        path_str,
+                        err.to_string()
+                    );
+                    return Outcome::Failed;
+                }
+            };
+            let action_verb = match from {
+                Side::Source => "created",
+                Side::Target => "pulled",
+            };
+            pair.print_action(action_verb, "symlink", path_str, &emit_time);
+            let hash = Utils::hash_link(&link);
+            Self::store_file(file_store, v_path, PathType::Symlink, hash, None, None);
+            return Outcome::Copied;
+        }
+
+        if let Ok(PathType::Dir) = from_type {
+            if let Err(err) = to.create_dir(relative_path).await {
+                err!(
+                    "{}failed to copy '{}', error: {}",
+                    pair.log_prefix(),
+                    path_str,
+                    err.to_string()
+                );
+                return Outcome::Failed;
+            }
+            Utils::preserve_attributes(pair, to, &from_path, relative_path, path_str).await;
+            let action_verb = match from {
+                Side::Source => "created",
+                Side::Target => "pulled",
+            };
+            pair.print_action(action_verb, "dir", path_str, &emit_time);
+            Self::store_dir(file_store, v_path);
+            return Outcome::Dir { created: true };
+        }
+
+        let parent = relative_path.parent().unwrap_or(Path::new(""));
+        // Taken before reading the content, a later change makes it outdated
+        let src_metadata = match from {
+            Side::Source => source.stat(relative_path).await.ok(),
+            Side::Target => None,
+        };
+        let written = match to.create_dir(parent).await {
+            Ok(()) => to.write_file(relative_path, &from_path).await,
+            Err(err) => Err(err),
+        };
+        let hash = match written {
+            Ok(hash) => hash,
+            Err(err) => {
+                err!(
+                    "{}failed to copy '{}', error: {}",
+                    pair.log_prefix(),
+                    path_str,
+                    err.to_string()
+                );
+                return Outcome::Failed;
+            }
+        };
+        // Before the source is stat, the stored modification time is the preserved one
+        Utils::preserve_attributes(pair, to, &from_path, relative_path, path_str).await;
+        let action_verb = match from {
+            Side::Source => "copied",
+            Side::Target => "pulled",
+        };
+        pair.print_action(action_verb, "file", path_str, &emit_time);
+
+        let src_metadata = match from {
+            Side::Source => src_metadata,
+            Side::Target => source.stat(relative_path).await.ok(),
+        };
+        let dest_metadata = pair.target.stat(relative_path).await.ok();
+        Self::store_file(
+            file_store,
+            v_path,
+            PathType::File,
+            hash,
+            src_metadata.as_ref(),
+            dest_metadata.as_ref(),
+        );
+        Outcome::Copied
+    }
+
+    /// Remove the path of the `from` side, deleted on the other one
+    async fn remove(
+        pair: &SyncPair,
+        file_store: &FileStore,
+        relative_path: &Path,
+        from: Side,
+        emit_time: Instant,
+    ) -> Outcome {
+        let source = Self::source(pair);
+        let (dir, action_verb): (&dyn SyncTarget, _) = match from {
+            Side::Source => (&source, "deleted from source"),
+            Side::Target => (pair.target.as_ref(), "deleted"),
+        };
+        let path_str = &Utils::fmt_path(relative_path);
+        let Ok(metadata) = dir.stat(relative_path).await else {
+            return Outcome::Skipped;
+        };
+
+        let removed = Trash::remove(pair, dir, relative_path, &metadata.path_type).await;
+        let path_type_str = type_str(&metadata.path_type);
+        match removed {
+            Ok(()) => pair.print_action(action_verb, path_type_str, path_str, &emit_time),
+            Err(err) if err.kind() == ErrorKind::NotFound => {}
+            Err(err) => {
+                handle_remove_err(pair, err, path_str, metadata.path_type);
+                return Outcome::Failed;
+            }
+        }
+        file_store.remove_all(&pair.config.source_dir.join(relative_path));
+
+        Outcome::Deleted
+    }
+
+    /// Whether every synced path inside the dir of the `side` is the one of the last sync, the dir
+    /// can then be deleted there
+    async fn is_unchanged_dir(
+        pair: &SyncPair,
+        file_store: &FileStore,
+        relative_path: &Path,
+        side: Side,
+    ) -> bool {
+        let source = Self::source(pair);
+        let dir: &dyn SyncTarget = match side {
+            Side::Source => &source,
+            Side::Target => pair.target.as_ref(),
+        };
+
+        let mut dirs_to_visit = vec![relative_path.to_path_buf()];
+        while let Some(relative_dir) = dirs_to_visit.pop() {
+            let Ok(entries) = dir.read_dir(&relative_dir).await else {
+                return false;
+            };
+            for (name, path_type) in entries {
+                let relative_path = relative_dir.join(name);
+                let is_skipped_symlink =
+                    path_type == PathType::Symlink && pair.config.symlinks == SymlinkPolicy::Skip;
+                if is_skipped_symlink || !pair.is_synced(&relative_path, path_type == PathType::Dir)
+                {
+                    continue;
+                }
+                // Created since the last sync
+                let Some(known) = file_store
+                    .get(&pair.config.source_dir.join(&relative_path))
+                    .filter(|known| known.path_type == path_type)
+                else {
+                    return false;
+                };
+                if path_type == PathType::Dir {
+                    dirs_to_visit.push(relative_path);
+                    continue;
+                }
+
+                let Ok(metadata) = dir.stat(&relative_path).await else {
+                    return false;
+                };
+                let unchanged = match side {
+                    Side::Source => {
+                        Self::source_hash(pair, Some(&known), &relative_path, &metadata)
+                            .await
+                            .ok()
+                            == known.hash
+                    }
+                    Side::Target => {
+                        Self::target_hash(pair, Some(&known), &relative_path, &metadata)
+                            .await
+                            .ok()
+                            == known.target_hash
+                    }
+                };
+                if !unchanged {
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    /// Copy the dir of the `from` side back to the other one, where it was deleted while its
+    /// content changed on the `from` side. Its content is synced like a dir never synced
+    async fn restore_dir(
+        pair: &SyncPair,
+        file_store: &FileStore,
+        relative_path: &Path,
+        from: Side,
+        emit_time: Instant,
+    ) -> Outcome {
+        warn!(
+            "{}conflict on '{}', deleted on one side while its content changed on the other, it is copied back",
+            pair.log_prefix(),
+            Utils::fmt_path(relative_path)
+        );
+        file_store.remove_all(&pair.config.source_dir.join(relative_path));
+
+        match Self::copy(pair, file_store, relative_path, from, emit_time).await {
+            Outcome::Dir { .. } => Outcome::RestoredDir,
+            outcome => outcome,
+        }
+    }
+
+    /// The hash of the source file, from the file store while its size and modification time are
+    /// unchanged
+    async fn source_hash(
+        pair: &SyncPair,
+        known: Option<&PathMetadata>,
+        relative_path: &Path,
+        src_metadata: &TargetMetadata,
+    ) -> std::io::Result<Hash> {
+        if src_metadata.path_type == PathType::Symlink {
+            let link = Self::source(pair).read_link(relative_path).await?;
+            let link = Utils::synced_link(pair, &pair.config.source_dir, relative_path, link);
+            return Ok(Utils::hash_link(&link));
+        }
+        let indexed = known
+            .filter(|known| {
+                known.len == src_metadata.len
+                    && known.modified.is_some()
+                    && known.modified == src_metadata.modified
+            })
+            .and_then(|known| known.hash);
+
+        match indexed {
+            Some(hash) => Ok(hash),
+            None => Self::source(pair).hash(relative_path).await,
+        }
+    }
+
+    /// The hash of the target file, from the file store while its size and modification time are
+    /// the ones of the last sync
+    async fn target_hash(
+        pair: &SyncPair,
+        known: Option<&PathMetadata>,
+        relative_path: &Path,
+        dest_metadata: &TargetMetadata,
+    ) -> std::io::Result<Hash> {
+        if dest_metadata.path_type == PathType::Symlink {
+            let link = pair.target.read_link(relative_path).await?;
+            let link = Utils::synced_link(pair, &pair.config.target_dir, relative_path, link);
+            return Ok(Utils::hash_link(&link));
+        }
+        let indexed = known
+            .filter(|known| known.target_unchanged(dest_metadata))
+            .and_then(|known| known.target_hash);
+
+        match indexed {
+            Some(hash) => Ok(hash),
+            None => pair.target.hash(relative_path).await,
+        }
+    }
+
+    /// Both sides have the content of `hash`, or symlinks pointing to the path of `hash`
+    fn store_file(
+        file_store: &FileStore,
+        v_path: PathBuf,
+        path_type: PathType,
+        hash: Hash,
+        src_metadata: Option<&TargetMetadata>,
+        dest_metadata: Option<&TargetMetadata>,
+    ) {
+        file_store.insert(
+            v_path,
+            PathMetadata {
+                len: src_metadata.map_or(0, |metadata| metadata.len),
+                modified: src_metadata.and_then(|metadata| metadata.modified),
+                target_len: dest_metadata.map_or(0, |metadata| metadata.len),
+                target_modified: dest_metadata.and_then(|metadata| metadata.modified),
+                ..PathMetadata::file(path_type, Some(hash))
+            },
+        );
+    }
+
+    fn store_dir(file_store: &FileStore, v_path: PathBuf) {
+        file_store.insert(v_path, PathMetadata::dir());
+    }
+
+    /// The source dir, written like a target
+    fn source(pair: &SyncPair) -> LocalTarget {
+        LocalTarget::new(pair.config.source_dir.clone())
+            .with_fsync(pair.config.fsync)
+            .with_follow_symlinks(pair.config.symlinks == SymlinkPolicy::Follow)
+    }
+}
+
+fn type_str(path_type: &PathType) -> &'static str {
+    match path_type {
+        PathType::File => "file",
+        PathType::Dir => "dir",
+        PathType::Symlink => "symlink",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::Arc;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::config::SyncConfig;
+
+    fn two_way_pair(source: &TempDir, target: &TempDir) -> SyncPair {
+        let mut config = SyncConfig::new(source.path().to_path_buf(), target.path().to_path_buf());
+        config.two_way = true;
+        let target = LocalTarget::new(config.target_dir.clone()).with_follow_symlinks(true);
+        SyncPair::new(config, None, Arc::new(target)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn changes_are_synced_from_the_side_they_were_made_on() {
+        let (source, target) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        fs::write(source.path().join("f"), b"source").unwrap();
+        fs::write(source.path().join("g"), b"g").unwrap();
+        fs::create_dir(target.path().join("dir")).unwrap();
+        fs::write(target.path().join("dir/h"), b"target").unwrap();
+        let pair = two_way_pair(&source, &target);
+        let file_store = FileStore::default();
+
+        let summary = TwoWay::reconcile(&pair, &file_store).await;
+        assert_eq!((summary.copied, summary.created, summary.failed), (3, 1, 0));
+        assert_eq!(fs::read(target.path().join("f")).unwrap(), b"source");
+        assert_eq!(fs::read(source.path().join("dir/h")).unwrap(), b"target");
+
+        fs::write(target.path().join("f"), b"changed on target").unwrap();
+        fs::remove_file(source.path().join("g")).unwrap();
+        let summary = TwoWay::reconcile(&pair, &file_store).await;
+        assert_eq!((summary.copied, summary.deleted), (1, 1));
+        assert_eq!(
+            fs::read(source.path().join("f")).unwrap(),
+            b"changed on target"
+        );
+        assert!(!target.path().join("g").exists());
+    }
+
+    #[tokio::test]
+    async fn files_changed_on_both_sides_are_kept_in_conflict() {
+        let (source, target) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        fs::write(source.path().join("f"), b"source").unwrap();
+        fs::write(target.path().join("f"), b"target").unwrap();
+        let pair = two_way_pair(&source, &target);
+
+        let summary = TwoWay::reconcile(&pair, &FileStore::default()).await;
+        assert_eq!(summary.conflicts, 1);
+        assert_eq!(fs::read(target.path().join("f")).unwrap(), b"source");
+        let conflict_copies: Vec<_> = fs::read_dir(source.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .filter(|name| name.starts_with("f.conflict-"))
+            .collect();
+        assert_eq!(conflict_copies.len(), 1);
+        let conflict_path = target.path().join(&conflict_copies[0]);
+        assert_eq!(fs::read(conflict_path).unwrap(), b"target");
+    }
+
+    #[tokio::test]
+    async fn deleted_dirs_are_copied_back_when_their_content_changed() {
+        let (source, target) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+        fs::create_dir_all(source.path().join("d/sub")).unwrap();
+        fs::write(source.path().join("d/sub/f"), b"synced").unwrap();
+        fs::create_dir(source.path().join("e")).unwrap();
+        fs::write(source.path().join("e/f"), b"synced").unwrap();
+        let pair = two_way_pair(&source, &target);
+        let file_store = FileStore::default();
+        TwoWay::reconcile(&pair, &file_store).await;
+
+        fs::remove_dir_all(target.path().join("d")).unwrap();
+        fs::write(source.path().join("d/sub/new"), b"never synced").unwrap();
+        fs::remove_dir_all(target.path().join("e")).unwrap();
+        let summary = TwoWay::reconcile(&pair, &file_store).await;
+        assert_eq!((summary.conflicts, summary.deleted), (1, 1));
+        assert_eq!(
+            fs::read(target.path().join("d/sub/new")).unwrap(),
+            b"never synced"
+        );
+        assert_eq!(fs::read(target.path().join("d/sub/f")).unwrap(), b"synced");
+        assert!(!source.path().join("e").exists());
+
+        // Synced again, the dir is deleted like any other
+        fs::remove_dir_all(source.path().join("d")).unwrap();
+        let summary = TwoWay::reconcile(&pair, &file_store).await;
+        assert_eq!((summary.conflicts, summary.deleted), (0, 1));
+        assert!(!target.path().join("d").exists());
+    }
+}
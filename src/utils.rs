@@ -1,65 +1,271 @@
 use core::fmt::Debug;
-use std::path::{Component, Path, PathBuf, Prefix};
-use std::process::abort;
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap};
+use std::fs::Metadata;
+use std::hash::BuildHasherDefault;
+use std::io;
+use std::io::ErrorKind;
+#[cfg(windows)]
+use std::path::{Component, Prefix};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
 use std::time::SystemTime;
 
+use ahash::AHasher;
 use blake3::Hash;
-use lru::LruCache;
 use notify::event::{ModifyKind, RenameMode};
 use notify::{Event, EventKind};
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
 use tokio::fs;
-use tokio::sync::OnceCell;
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::time::Instant;
 
+use crate::attributes::Attributes;
+use crate::config::{Preserve, SymlinkPolicy, TargetChangePolicy};
+use crate::delta::{Blocks, Delta, BLOCK_SIZE};
 use crate::file_operations::FileOperationsManager;
-use crate::{err, info, warn, Args};
+use crate::pair::SyncPair;
+use crate::target::{SyncTarget, TargetMetadata};
+use crate::two_way::TwoWay;
+use crate::{err, warn};
+
+/// Size of the buffer files are read with, the memory used doesn't depend on their size
+pub(crate) const READ_BUFFER_SIZE: usize = 128 * 1024;
 
 pub struct Utils;
 
-static ARGS: OnceCell<Args> = OnceCell::const_new();
-static EXCLUDED_PATHS: OnceCell<Vec<PathBuf>> = OnceCell::const_new();
+/// What is known of the synced paths of the source dir, shared by the workers and saved to the
+/// index between runs
+#[derive(Debug, Default)]
+pub struct FileStore {
+    paths: Mutex<Paths>,
+    /// A synced path of each source file with several hard links, the other ones are linked to it
+    links: Mutex<HashMap<FileId, PathBuf, BuildHasherDefault<AHasher>>>,
+}
+
+type Paths = HashMap<PathBuf, PathMetadata, BuildHasherDefault<AHasher>>;
+
+/// Device and inode numbers of a source file, shared by its hard links
+pub type FileId = (u64, u64);
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize)]
 pub enum PathType {
     File,
     Dir,
+    /// Only reported when the symlinks aren't followed
+    Symlink,
 }
 
 #[derive(Debug, Clone)]
 pub struct PathMetadata {
     pub path_type: PathType,
+    /// Hash of the source file when it was last synced
     pub hash: Option<Hash>,
+    /// Hash of the target file when it was last synced, a change of one side or the other tells
+    /// which way a two-way sync goes
+    pub target_hash: Option<Hash>,
+    /// Size of the source file when it was hashed
+    pub len: u64,
+    /// Modification time of the source file when it was hashed, the hash is still valid while it
+    /// is unchanged
+    pub modified: Option<SystemTime>,
     pub last_change: SystemTime,
+    /// Size of the target file right after it was last written
+    pub target_len: u64,
+    /// Modification time of the target file right after it was last written, the target hash is
+    /// still valid while it and the size are unchanged
+    pub target_modified: Option<SystemTime>,
+    /// Blocks of the content written to the target, in delta mode
+    pub blocks: Option<Blocks>,
+    /// Identity of the source file when it has several hard links, found again by each scan
+    pub file_id: Option<FileId>,
 }
 
-impl Utils {
-    pub fn set_args(args: Args) {
-        ARGS.set(args).unwrap();
+impl FileStore {
+    pub fn get(&self, path: &Path) -> Option<PathMetadata> {
+        self.paths().get(path).cloned()
     }
-    pub fn args<'a>() -> &'a Args {
-        ARGS.get().unwrap()
+
+    pub fn contains(&self, path: &Path) -> bool {
+        self.paths().contains_key(path)
     }
 
-    pub fn set_excluded_paths(excluded_paths: Vec<PathBuf>) {
-        EXCLUDED_PATHS.set(excluded_paths).unwrap();
+    pub fn insert(&self, path: PathBuf, metadata: PathMetadata) {
+        let mut paths = self.paths();
+        if let Some(file_id) = metadata.file_id {
+            self.add_link(&paths, &path, file_id);
+        }
+        paths.insert(path, metadata);
     }
-    pub fn excluded_paths<'a>() -> &'a Vec<PathBuf> {
-        EXCLUDED_PATHS.get().unwrap()
+
+    /// Apply `f` to the metadata of `path`, `false` when the path is unknown
+    pub fn update(&self, path: &Path, f: impl FnOnce(&mut PathMetadata)) -> bool {
+        let mut paths = self.paths();
+        let Some(metadata) = paths.get_mut(path) else {
+            return false;
+        };
+        f(metadata);
+        if let Some(file_id) = metadata.file_id {
+            self.add_link(&paths, path, file_id);
+        }
+        true
     }
 
-    pub fn fmt_path(path: &Path) -> String {
-        if let Some(path_str) = path.to_str() {
-            // Check if the path starts with the prefix and remove the first three characters
-            return path_str
-                .strip_prefix(r"\\?\")
-                .map_or_else(|| path_str.to_owned(), |stripped| stripped.to_string());
+    /// The synced path the other hard links of the source file `file_id` are linked to, `None`
+    /// when it is `path` itself
+    pub fn linked_path(&self, path: &Path, file_id: FileId) -> Option<PathBuf> {
+        let paths = self.paths();
+        self.links
+            .lock()
+            .unwrap()
+            .get(&file_id)
+            .filter(|linked_path| {
+                *linked_path != path && Self::has_id(&paths, linked_path, file_id)
+            })
+            .cloned()
+    }
+
+    /// The synced paths of the source file `file_id`
+    pub fn hard_links(&self, file_id: FileId) -> Vec<PathBuf> {
+        self.paths()
+            .iter()
+            .filter(|(_, metadata)| metadata.file_id == Some(file_id))
+            .map(|(path, _)| path.clone())
+            .collect()
+    }
+
+    /// Forget `path` along with the paths inside it
+    pub fn remove_all(&self, path: &Path) {
+        self.paths()
+            .retain(|known_path, _| !known_path.starts_with(path));
+    }
+
+    /// Move the metadata of `from` and of the paths inside it to `to`, returns the one of `from`
+    pub fn rename(&self, from: &Path, to: &Path) -> Option<PathMetadata> {
+        let mut paths = self.paths();
+        let moved_paths = paths
+            .keys()
+            .filter(|path| path.starts_with(from) && *path != from)
+            .cloned()
+            .collect::<Vec<_>>();
+        for moved_path in moved_paths {
+            let metadata = paths.remove(&moved_path).unwrap();
+            paths.insert(to.join(moved_path.strip_prefix(from).unwrap()), metadata);
+        }
+
+        let metadata = paths.remove(from);
+        if let Some(metadata) = &metadata {
+            paths.insert(to.to_path_buf(), metadata.clone());
+        }
+        metadata
+    }
+
+    pub fn retain(&self, f: impl FnMut(&PathBuf, &mut PathMetadata) -> bool) {
+        self.paths().retain(f);
+    }
+
+    pub fn for_each(&self, mut f: impl FnMut(&PathBuf, &PathMetadata)) {
+        for (path, metadata) in self.paths().iter() {
+            f(path, metadata);
+        }
+    }
+
+    fn paths(&self) -> MutexGuard<'_, Paths> {
+        self.paths.lock().unwrap()
+    }
+
+    /// The first path synced of a source file is kept until it is removed, renamed or rewritten
+    /// as another file
+    fn add_link(&self, paths: &Paths, path: &Path, file_id: FileId) {
+        let mut links = self.links.lock().unwrap();
+        let is_linked = links
+            .get(&file_id)
+            .is_some_and(|linked_path| Self::has_id(paths, linked_path, file_id));
+        if !is_linked {
+            links.insert(file_id, path.to_path_buf());
+        }
+    }
+
+    fn has_id(paths: &Paths, path: &Path, file_id: FileId) -> bool {
+        paths
+            .get(path)
+            .is_some_and(|metadata| metadata.file_id == Some(file_id))
+    }
+}
+
+impl PathMetadata {
+    /// Only the type is known, changed just now
+    pub fn new(path_type: PathType) -> Self {
+        Self {
+            path_type,
+            hash: None,
+            target_hash: None,
+            len: 0,
+            modified: None,
+            last_change: SystemTime::now(),
+            target_len: 0,
+            target_modified: None,
+            blocks: None,
+            file_id: None,
+        }
+    }
+
+    pub fn dir() -> Self {
+        Self::new(PathType::Dir)
+    }
+
+    /// A file or a symlink with the content of `hash` on both sides
+    pub fn file(path_type: PathType, hash: Option<Hash>) -> Self {
+        Self {
+            hash,
+            target_hash: hash,
+            ..Self::new(path_type)
         }
+    }
 
-        err!("Path contains invalid Unicode");
-        abort()
+    /// Whether the target file is still the one last written, with the exact same size and
+    /// modification time
+    pub fn target_unchanged(&self, dest_metadata: &TargetMetadata) -> bool {
+        self.target_modified.is_some()
+            && self.target_modified == dest_metadata.modified
+            && self.target_len == dest_metadata.len
+    }
+}
+
+impl Utils {
+    /// `<name>.<label>-<timestamp>`, next to the path
+    pub fn timestamped_path(path: &Path, label: &str) -> PathBuf {
+        let now = OffsetDateTime::now_local().unwrap_or_else(|_| OffsetDateTime::now_utc());
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(
+            ".{}-{:04}{:02}{:02}-{:02}{:02}{:02}",
+            label,
+            now.year(),
+            u8::from(now.month()),
+            now.day(),
+            now.hour(),
+            now.minute(),
+            now.second()
+        ));
+        path.with_file_name(name)
+    }
+
+    /// The path as displayed in the logs, its invalid Unicode replaced by `U+FFFD`
+    pub fn fmt_path(path: &Path) -> String {
+        let path_str = path.to_string_lossy();
+        // Check if the path starts with the prefix and remove the first three characters
+        #[cfg(windows)]
+        return path_str
+            .strip_prefix(r"\\?\")
+            .map_or_else(|| path_str.to_string(), |stripped| stripped.to_string());
+        #[cfg(not(windows))]
+        return path_str.into_owned();
     }
 
     /// See `https://github.com/dherman/verbatim`
+    #[cfg(windows)]
     pub fn path_to_verbatim(path: &Path) -> PathBuf {
         let mut components = path.components();
         match components.next() {
@@ -80,119 +286,666 @@ impl Utils {
         }
     }
 
+    /// Verbatim paths only exist on Windows, other platforms already use the canonical form
+    #[cfg(not(windows))]
+    pub fn path_to_verbatim(path: &Path) -> PathBuf {
+        path.to_path_buf()
+    }
+
+    /// Size and modification time of the target file just written. Without them, the file is
+    /// hashed the next time it is compared
+    pub async fn target_stamp(pair: &SyncPair, relative_path: &Path) -> (u64, Option<SystemTime>) {
+        match pair.target.stat(relative_path).await {
+            Ok(dest_metadata) => (dest_metadata.len, dest_metadata.modified),
+            Err(_) => (0, None),
+        }
+    }
+
+    pub async fn hash_file(path: &Path) -> Option<Hash> {
+        let file = File::open(path).await.ok()?;
+        Self::hash_reader(file).await.ok()
+    }
+
+    /// Hash the content of `reader` by chunks
+    pub async fn hash_reader<R: AsyncRead + Unpin>(mut reader: R) -> io::Result<Hash> {
+        let mut hasher = blake3::Hasher::new();
+        let mut buffer = vec![0; READ_BUFFER_SIZE];
+        loop {
+            let read = reader.read(&mut buffer).await?;
+            if read == 0 {
+                return Ok(hasher.finalize());
+            }
+            hasher.update(&buffer[..read]);
+        }
+    }
+
+    /// Write the content of `reader` to `writer` and return its hash, in a single read
+    pub async fn copy_and_hash<R, W>(mut reader: R, mut writer: W) -> io::Result<Hash>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut hasher = blake3::Hasher::new();
+        let mut buffer = vec![0; READ_BUFFER_SIZE];
+        loop {
+            let read = reader.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+            writer.write_all(&buffer[..read]).await?;
+        }
+
+        writer.flush().await?;
+        Ok(hasher.finalize())
+    }
+
+    /// The hash of the source file, along with its blocks in delta mode
+    pub async fn hash_source(pair: &SyncPair, path: &Path) -> (Option<Hash>, Option<Blocks>) {
+        if !pair.config.delta {
+            return (Self::hash_file(path).await, None);
+        }
+
+        match Delta::hash_blocks(path, BLOCK_SIZE).await {
+            Ok((hash, blocks)) => (Some(hash), Some(blocks)),
+            Err(_) => (None, None),
+        }
+    }
+
+    /// In delta mode, only the blocks which differ from `known_blocks` or from the target are
+    /// written when the file already exists on the target. Returns the hash of the content
+    /// written when the file is copied whole, `None` when it is patched
     pub async fn copy_file(
+        pair: &SyncPair,
         src_path: &Path,
-        dest_path: &Path,
+        relative_path: &Path,
         path_str: &str,
         emit_time: Instant,
-    ) -> Result<(), ()> {
-        if let Err(err) = fs::copy(src_path, dest_path).await {
-            err!("failed to copy '{}', error: {}", path_str, err.to_string());
-            Err(())
-        } else {
-            Self::print_action("copied", "file", path_str, &emit_time);
-            Ok(())
+        src_blocks: Option<&Blocks>,
+        known_blocks: Option<&Blocks>,
+    ) -> Result<Option<Hash>, ()> {
+        if let Some(src_blocks) = src_blocks.filter(|blocks| blocks.len > BLOCK_SIZE as u64) {
+            let result =
+                Delta::patch(pair, src_path, relative_path, src_blocks, known_blocks).await;
+            match result {
+                Ok(Some(sent)) => {
+                    Self::preserve_attributes(
+                        pair,
+                        pair.target.as_ref(),
+                        src_path,
+                        relative_path,
+                        path_str,
+                    )
+                    .await;
+                    let action = format!(
+                        "patched, {} of {} blocks sent",
+                        sent,
+                        src_blocks.hashes.len()
+                    );
+                    pair.print_action(&action, "file", path_str, &emit_time);
+                    return Ok(None);
+                }
+                // Copied whole below
+                Ok(None) => {}
+                Err(err) => {
+                    err!(
+                        "{}failed to copy '{}', error: {}",
+                        pair.log_prefix(),
+                        path_str,
+                        err.to_string()
+                    );
+                    return Err(());
+                }
+            }
+        }
+
+        match pair.target.write_file(relative_path, src_path).await {
+            Ok(hash) => {
+                Self::preserve_attributes(
+                    pair,
+                    pair.target.as_ref(),
+                    src_path,
+                    relative_path,
+                    path_str,
+                )
+                .await;
+                pair.print_action("copied", "file", path_str, &emit_time);
+                Ok(Some(hash))
+            }
+            Err(err) => {
+                err!(
+                    "{}failed to copy '{}', error: {}",
+                    pair.log_prefix(),
+                    path_str,
+                    err.to_string()
+                );
+                Err(())
+            }
+        }
+    }
+
+    /// Give the preserved attributes of the local `src_path` to the `relative_path` of `target`,
+    /// a failure is logged but the content stays synced
+    pub async fn preserve_attributes(
+        pair: &SyncPair,
+        target: &dyn SyncTarget,
+        src_path: &Path,
+        relative_path: &Path,
+        path_str: &str,
+    ) {
+        if pair.config.preserve.is_empty() {
+            return;
+        }
+
+        let result = match Attributes::read(src_path, &pair.config.preserve).await {
+            Ok(attributes) => target.set_attributes(relative_path, &attributes).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            err!(
+                "{}failed to preserve the attributes of '{}', error: {}",
+                pair.log_prefix(),
+                path_str,
+                err.to_string()
+            );
+        }
+    }
+
+    /// Give the target `dirs` the times of the source ones again, once the writes of their
+    /// entries changed them. The deepest dirs come first, the ones gone since are skipped
+    pub async fn restore_dir_times(pair: &SyncPair, dirs: impl IntoIterator<Item = PathBuf>) {
+        let preserve = &pair.config.preserve;
+        if !preserve.contains(&Preserve::Times) && !preserve.contains(&Preserve::All) {
+            return;
+        }
+
+        let mut dirs: Vec<_> = dirs
+            .into_iter()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        dirs.sort_by_key(|dir| Reverse(dir.components().count()));
+        for dir in dirs {
+            let src_path = pair.config.source_dir.join(&dir);
+            let result = match Attributes::read(&src_path, &[Preserve::Times]).await {
+                Ok(attributes) => pair.target.set_attributes(&dir, &attributes).await,
+                Err(err) => Err(err),
+            };
+            match result {
+                Err(err) if err.kind() != ErrorKind::NotFound => {
+                    err!(
+                        "{}failed to preserve the times of '{}', error: {}",
+                        pair.log_prefix(),
+                        Self::fmt_path(&dir),
+                        err.to_string()
+                    );
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Recreate the symlink `src_path` on the target, returns where it points to there
+    pub async fn copy_symlink(
+        pair: &SyncPair,
+        src_path: &Path,
+        relative_path: &Path,
+        path_str: &str,
+        emit_time: Instant,
+    ) -> Result<PathBuf, ()> {
+        let result = match fs::read_link(src_path).await {
+            Ok(link) => {
+                let link = Self::synced_link(pair, &pair.config.source_dir, relative_path, link);
+                pair.target
+                    .create_symlink(relative_path, &link)
+                    .await
+                    .map(|()| link)
+            }
+            Err(err) => Err(err),
+        };
+
+        match result {
+            Ok(link) => {
+                pair.print_action("created", "symlink", path_str, &emit_time);
+                Ok(link)
+            }
+            Err(err) => {
+                err!(
+                    "{}failed to copy '{}', error: {}",
+                    pair.log_prefix(),
+                    path_str,
+                    err.to_string()
+                );
+                Err(())
+            }
+        }
+    }
+
+    /// Where the symlink `relative_path` of the `root` dir points to once synced. With the
+    /// `relative` policy, the absolute links into `root` are made relative to the dir of the link
+    pub fn synced_link(
+        pair: &SyncPair,
+        root: &Path,
+        relative_path: &Path,
+        link: PathBuf,
+    ) -> PathBuf {
+        if pair.config.symlinks != SymlinkPolicy::Relative || !link.is_absolute() {
+            return link;
+        }
+        let v_link = Self::path_to_verbatim(&link);
+        let Ok(linked_path) = v_link.strip_prefix(root) else {
+            return link;
+        };
+
+        let mut relative_link = PathBuf::new();
+        for _ in relative_path
+            .parent()
+            .into_iter()
+            .flat_map(Path::components)
+        {
+            relative_link.push("..");
+        }
+        relative_link.push(linked_path);
+        if relative_link.as_os_str().is_empty() {
+            relative_link.push(".");
+        }
+        relative_link
+    }
+
+    /// Whether `path` is a followed symlink to one of the `followed_dirs` it is reached through,
+    /// or to a dir containing them. What it points to is added to them otherwise
+    pub async fn is_symlink_loop(
+        pair: &SyncPair,
+        path: &Path,
+        followed_dirs: &mut Vec<PathBuf>,
+    ) -> bool {
+        if pair.config.symlinks != SymlinkPolicy::Follow
+            || !fs::symlink_metadata(path)
+                .await
+                .is_ok_and(|metadata| metadata.is_symlink())
+        {
+            return false;
+        }
+        let Ok(real_dir) = fs::canonicalize(path).await else {
+            return false;
+        };
+
+        if followed_dirs
+            .iter()
+            .any(|followed_dir| followed_dir.starts_with(&real_dir))
+        {
+            warn!(
+                "{}symlink loop on '{}', not followed",
+                pair.log_prefix(),
+                Self::fmt_path(path)
+            );
+            return true;
+        }
+        followed_dirs.push(real_dir);
+        false
+    }
+
+    /// Symlinks are compared by where they point to
+    pub fn hash_link(link: &Path) -> Hash {
+        blake3::hash(link.as_os_str().as_encoded_bytes())
+    }
+
+    /// Identity of the file, only for the ones with several hard links
+    #[cfg(unix)]
+    pub fn file_id(metadata: &Metadata) -> Option<FileId> {
+        use std::os::unix::fs::MetadataExt;
+
+        (metadata.is_file() && metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+    }
+
+    #[cfg(not(unix))]
+    pub fn file_id(_metadata: &Metadata) -> Option<FileId> {
+        None
+    }
+
+    /// The synced path the source file must be linked to on the target, as another hard link of
+    /// it. `None` when the hard links aren't preserved
+    pub fn hard_link_of(
+        pair: &SyncPair,
+        file_store: &FileStore,
+        v_path: &Path,
+        src_metadata: &Metadata,
+    ) -> Option<PathBuf> {
+        if !pair.config.hard_links {
+            return None;
+        }
+        file_store.linked_path(v_path, Self::file_id(src_metadata)?)
+    }
+
+    /// Make the target file a hard link of the target file of `original`. `Ok(false)` when the
+    /// target can't link files or `original` isn't there, it must be copied then
+    pub async fn link_file(
+        pair: &SyncPair,
+        original: &Path,
+        relative_path: &Path,
+        path_str: &str,
+        emit_time: Instant,
+    ) -> Result<bool, ()> {
+        let original_path = pair.relative_path(original);
+
+        match pair.target.hard_link(relative_path, original_path).await {
+            Ok(()) => {
+                pair.print_action("linked", "file", path_str, &emit_time);
+                Ok(true)
+            }
+            Err(err) if matches!(err.kind(), ErrorKind::Unsupported | ErrorKind::NotFound) => {
+                Ok(false)
+            }
+            Err(err) => {
+                err!(
+                    "{}failed to link '{}' to '{}', error: {}",
+                    pair.log_prefix(),
+                    path_str,
+                    Utils::fmt_path(original_path),
+                    err.to_string()
+                );
+                Err(())
+            }
+        }
+    }
+
+    /// Apply the target change policy when the target file was changed since it was last written,
+    /// `Ok(true)` then. `Err` when the source file must not be copied over it
+    pub async fn check_target_change(
+        pair: &SyncPair,
+        path_metadata: &PathMetadata,
+        relative_path: &Path,
+        path_str: &str,
+    ) -> Result<bool, ()> {
+        let Some(written_hash) = path_metadata.target_hash else {
+            return Ok(false);
+        };
+        let Ok(dest_metadata) = pair.target.stat(relative_path).await else {
+            return Ok(false);
+        };
+        // The content is only hashed when its size or modification time changed since it was
+        // written
+        if dest_metadata.path_type != PathType::File
+            || path_metadata.target_unchanged(&dest_metadata)
+            || pair
+                .target
+                .hash(relative_path)
+                .await
+                .map_or(true, |hash| hash == written_hash)
+        {
+            return Ok(false);
+        }
+
+        match pair.config.on_target_change {
+            TargetChangePolicy::Overwrite => {
+                warn!(
+                    "{}file '{}' changed on the target since it was last written, overwritten",
+                    pair.log_prefix(),
+                    path_str
+                );
+                Ok(true)
+            }
+            TargetChangePolicy::Skip => {
+                warn!(
+                    "{}file '{}' not copied : changed on the target since it was last written",
+                    pair.log_prefix(),
+                    path_str
+                );
+                Err(())
+            }
+            TargetChangePolicy::Backup => {
+                let backup_path = Self::timestamped_path(relative_path, "backup");
+                if let Err(err) = pair.target.rename(relative_path, &backup_path).await {
+                    err!(
+                        "{}failed to back up '{}', changed on the target, error: {}",
+                        pair.log_prefix(),
+                        path_str,
+                        err.to_string()
+                    );
+                    return Err(());
+                }
+                warn!(
+                    "{}file '{}' changed on the target since it was last written, kept as '{}'",
+                    pair.log_prefix(),
+                    path_str,
+                    Utils::fmt_path(&backup_path)
+                );
+                Ok(true)
+            }
         }
     }
 
     pub async fn create_dirs(
-        dest_path: &Path,
+        pair: &SyncPair,
+        relative_path: &Path,
         path_str: &str,
         emit_time: &Instant,
         dependency: bool,
     ) -> Result<(), ()> {
-        if let Err(err) = fs::create_dir_all(&dest_path).await {
+        // The dir would be created where the symlink points to
+        if pair.target_path_type(relative_path).await == Some(PathType::Symlink) {
+            let _ = pair.target.remove_file(relative_path).await;
+        }
+        if let Err(err) = pair.target.create_dir(relative_path).await {
             if dependency {
                 err!(
-                    "failed to create dirs for '{}', error: {}",
+                    "{}failed to create dirs for '{}', error: {}",
+                    pair.log_prefix(),
                     path_str,
                     err.to_string()
                 );
             } else {
-                err!("failed to copy '{}', error: {}", path_str, err.to_string());
+                err!(
+                    "{}failed to copy '{}', error: {}",
+                    pair.log_prefix(),
+                    path_str,
+                    err.to_string()
+                );
             }
 
             Err(())
         } else {
+            let src_path = pair.config.source_dir.join(relative_path);
+            Self::preserve_attributes(
+                pair,
+                pair.target.as_ref(),
+                &src_path,
+                relative_path,
+                path_str,
+            )
+            .await;
             if !dependency {
-                Self::print_action("created", "dir", path_str, emit_time);
+                pair.print_action("created", "dir", path_str, emit_time);
             }
             Ok(())
         }
     }
 
-    pub fn get_destination_path_and_dirs(relative_path: &Path) -> (PathBuf, PathBuf) {
-        let dest_path = Self::get_destination_path(relative_path);
-        let dirs = dest_path.parent().unwrap().to_path_buf();
-
-        (dest_path, dirs)
-    }
-
-    pub fn get_destination_path(relative_path: &Path) -> PathBuf {
-        Path::new(&Self::args().target_dir).join(relative_path)
-    }
-
     pub async fn handle_event(
-        event: Event,
-        file_store: &mut LruCache<PathBuf, PathMetadata>,
+        pair: &SyncPair,
+        mut event: Event,
+        file_store: &FileStore,
         emit_time: Instant,
         rename_from: &mut Option<PathBuf>,
     ) {
+        let filter_is_outdated = {
+            let filter = pair.filter();
+            event.paths.iter().any(|path| filter.is_ignore_file(path))
+        };
+        if filter_is_outdated && !matches!(event.kind, EventKind::Access(_)) {
+            pair.reload_filter().await;
+        }
+
+        // Seen through a symlink, the changes of the dir it points to are reported there too
+        if !event.paths.is_empty() {
+            event
+                .paths
+                .retain(|path| !pair.is_inside_symlink(&Self::path_to_verbatim(path)));
+            if event.paths.is_empty() {
+                return;
+            }
+        }
+
+        if pair.config.two_way {
+            if !matches!(event.kind, EventKind::Access(_)) {
+                TwoWay::handle_event(pair, file_store, emit_time, event).await;
+            }
+            return;
+        }
+
+        // The writes of the entries of a dir change its times, and the ones of its parents when
+        // they are created along the way
+        let parent_dirs: Vec<_> = match event.kind {
+            EventKind::Access(_) | EventKind::Modify(ModifyKind::Metadata(_)) => Vec::new(),
+            _ => event
+                .paths
+                .iter()
+                .filter_map(|path| {
+                    Self::path_to_verbatim(path)
+                        .strip_prefix(&pair.config.source_dir)
+                        .ok()
+                        .map(Path::to_path_buf)
+                })
+                .flat_map(|path| {
+                    path.ancestors()
+                        .skip(1)
+                        .map(Path::to_path_buf)
+                        .collect::<Vec<_>>()
+                })
+                .collect(),
+        };
+
         match event.kind {
             EventKind::Create(_) => {
-                if !Utils::args().no_creation_events {
-                    FileOperationsManager::create(file_store, emit_time, event).await;
+                if !pair.config.no_creation_events {
+                    FileOperationsManager::create(pair, file_store, emit_time, event).await;
                 }
             }
             EventKind::Modify(kind) => match kind {
                 ModifyKind::Name(rename) => match rename {
                     RenameMode::From => {
-                        FileOperationsManager::rename(file_store, emit_time, event, rename_from)
-                            .await;
+                        FileOperationsManager::rename(
+                            pair,
+                            file_store,
+                            emit_time,
+                            event,
+                            rename_from,
+                        )
+                        .await;
                     }
                     RenameMode::To => {
-                        FileOperationsManager::rename(file_store, emit_time, event, rename_from)
-                            .await;
+                        FileOperationsManager::rename(
+                            pair,
+                            file_store,
+                            emit_time,
+                            event,
+                            rename_from,
+                        )
+                        .await;
                     }
+                    // inotify reports both halves of a rename before this event
+                    RenameMode::Both => {}
+                    // FSEvents doesn't tell which side of the rename the path is on
                     _ => {
-                        FileOperationsManager::copy(file_store, emit_time, event).await;
+                        if event.paths.iter().all(|path| path.exists()) {
+                            FileOperationsManager::copy(pair, file_store, emit_time, event).await;
+                        } else {
+                            FileOperationsManager::remove(pair, file_store, emit_time, event).await;
+                        }
                     }
                 },
+                ModifyKind::Metadata(_) => {
+                    FileOperationsManager::update_metadata(pair, file_store, emit_time, event)
+                        .await;
+                }
                 _ => {
-                    FileOperationsManager::copy(file_store, emit_time, event).await;
+                    FileOperationsManager::copy(pair, file_store, emit_time, event).await;
                 }
             },
             EventKind::Remove(_) => {
-                FileOperationsManager::remove(file_store, emit_time, event).await;
+                FileOperationsManager::remove(pair, file_store, emit_time, event).await;
             }
             EventKind::Access(_) => {}
             _ => {
                 warn!("Unknown event: {:?}", event)
             }
         }
+        Self::restore_dir_times(pair, parent_dirs).await;
     }
+}
 
-    pub fn print_action(action_verb: &str, type_path: &str, path_str: &str, emit_time: &Instant) {
-        if Utils::args().statistics {
-            let elapsed = emit_time.elapsed().as_micros();
-            if elapsed >= 1000 {
-                info!(
-                    "{} '{}' {} in {} ms",
-                    type_path,
-                    path_str,
-                    action_verb,
-                    elapsed / 1000
-                );
-            } else {
-                info!(
-                    "{} '{}' {} in {} μs",
-                    type_path, path_str, action_verb, elapsed
-                );
-            }
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
 
-            return;
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::config::SyncConfig;
+    use crate::scan::Scanner;
+    use crate::target::{MemoryEntry, MemoryTarget, SyncTarget};
+
+    /// Sync a file, change it on both sides, then sync it again with `policy`
+    async fn sync_after_target_change(
+        policy: TargetChangePolicy,
+    ) -> BTreeMap<PathBuf, MemoryEntry> {
+        let source = TempDir::new().unwrap();
+        let mut config = SyncConfig::new(source.path().to_path_buf(), PathBuf::new());
+        config.on_target_change = policy;
+        let target = Arc::new(MemoryTarget::new());
+        let pair = SyncPair::new(config, None, target.clone()).unwrap();
+        let file_store = FileStore::default();
+        let src_path = source.path().join("f");
+        std::fs::write(&src_path, b"synced").unwrap();
+        Scanner::reconcile(&pair, &file_store).await;
+
+        let edit = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(edit.path(), b"changed on the target").unwrap();
+        target
+            .write_file(Path::new("f"), edit.path())
+            .await
+            .unwrap();
+        std::fs::write(&src_path, b"changed on the source").unwrap();
+        Scanner::reconcile(&pair, &file_store).await;
+
+        target.snapshot()
+    }
+
+    fn content(entries: &BTreeMap<PathBuf, MemoryEntry>, path: &Path) -> Vec<u8> {
+        match &entries[path] {
+            MemoryEntry::File { content, .. } => content.clone(),
+            entry => panic!("'{}' is not a file : {:?}", path.display(), entry),
         }
+    }
+
+    #[tokio::test]
+    async fn changed_target_files_are_overwritten_by_default() {
+        let entries = sync_after_target_change(TargetChangePolicy::Overwrite).await;
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(content(&entries, Path::new("f")), b"changed on the source");
+    }
+
+    #[tokio::test]
+    async fn changed_target_files_can_be_kept() {
+        let entries = sync_after_target_change(TargetChangePolicy::Skip).await;
+
+        assert_eq!(entries.len(), 1);
+        assert_eq!(content(&entries, Path::new("f")), b"changed on the target");
+    }
+
+    #[tokio::test]
+    async fn changed_target_files_can_be_backed_up() {
+        let entries = sync_after_target_change(TargetChangePolicy::Backup).await;
 
-        info!("{} '{}' {}", type_path, path_str, action_verb);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(content(&entries, Path::new("f")), b"changed on the source");
+        let backup_path = entries
+            .keys()
+            .find(|path| path.to_str().unwrap().starts_with("f.backup-"))
+            .unwrap();
+        assert_eq!(content(&entries, backup_path), b"changed on the target");
     }
 }
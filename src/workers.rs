@@ -0,0 +1,252 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use notify::event::ModifyKind;
+use notify::{Event, EventKind};
+use tokio::sync::Mutex;
+use tokio::task::{Id, JoinSet};
+use tokio::time::Instant;
+
+use crate::pair::SyncPair;
+use crate::utils::{FileStore, Utils};
+use crate::{err, trace};
+
+/// What the workers need to sync an event, shared by all of them
+#[derive(Clone)]
+pub(crate) struct EventHandler {
+    pub pair: Arc<SyncPair>,
+    pub file_store: Arc<FileStore>,
+    /// First half of a rename, waiting for the second one
+    rename_from: Arc<Mutex<Option<PathBuf>>>,
+}
+
+impl EventHandler {
+    pub fn new(pair: Arc<SyncPair>) -> Self {
+        Self {
+            pair,
+            file_store: Arc::new(FileStore::default()),
+            rename_from: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub async fn handle_event(&self, event: Event, emit_time: Instant) {
+        if self.pair.config.trace {
+            trace!("{}{:?}", self.pair.log_prefix(), event);
+        }
+
+        // Only the renames wait for each other, the pool runs them alone anyway
+        if matches!(event.kind, EventKind::Modify(ModifyKind::Name(_))) {
+            let mut rename_from = self.rename_from.lock().await;
+            Utils::handle_event(
+                &self.pair,
+                event,
+                &self.file_store,
+                emit_time,
+                &mut rename_from,
+            )
+            .await
+        } else {
+            Utils::handle_event(&self.pair, event, &self.file_store, emit_time, &mut None).await
+        }
+    }
+}
+
+/// Syncs the events on a bounded number of tasks. An event waits for the earlier events of its
+/// paths, of the dirs containing them and of the paths inside them, the other ones are synced in
+/// parallel
+pub(crate) struct WorkerPool {
+    handler: EventHandler,
+    concurrency: usize,
+    queue: VecDeque<Job>,
+    workers: JoinSet<()>,
+    running: HashMap<Id, Job>,
+}
+
+struct Job {
+    event: Option<Event>,
+    emit_time: Instant,
+    paths: Vec<PathBuf>,
+    /// Run alone, after the earlier events and before the later ones
+    exclusive: bool,
+}
+
+impl Job {
+    fn new(event: Event, emit_time: Instant) -> Self {
+        // The two halves of a rename share the path being renamed
+        let exclusive =
+            event.paths.is_empty() || matches!(event.kind, EventKind::Modify(ModifyKind::Name(_)));
+        Self {
+            paths: event.paths.clone(),
+            event: Some(event),
+            emit_time,
+            exclusive,
+        }
+    }
+
+    fn conflicts_with(&self, other: &Job) -> bool {
+        self.exclusive
+            || other.exclusive
+            || self.paths.iter().any(|path| {
+                other
+                    .paths
+                    .iter()
+                    .any(|other_path| path.starts_with(other_path) || other_path.starts_with(path))
+            })
+    }
+}
+
+impl WorkerPool {
+    pub fn new(handler: EventHandler, concurrency: usize) -> Self {
+        Self {
+            handler,
+            concurrency: concurrency.max(1),
+            queue: VecDeque::new(),
+            workers: JoinSet::new(),
+            running: HashMap::new(),
+        }
+    }
+
+    /// Sync the event once the events it depends on are synced
+    pub fn push(&mut self, event: Event, emit_time: Instant) {
+        self.queue.push_back(Job::new(event, emit_time));
+        self.start_ready();
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.running.is_empty()
+    }
+
+    /// Wait for an event to be synced, then start the ones waiting for it
+    pub async fn join_next(&mut self) {
+        let id = match self.workers.join_next_with_id().await {
+            Some(Ok((id, ()))) => id,
+            Some(Err(join_err)) => {
+                err!(
+                    "{}worker failed, error: {}",
+                    self.handler.pair.log_prefix(),
+                    join_err
+                );
+                join_err.id()
+            }
+            None => return,
+        };
+        self.running.remove(&id);
+        self.start_ready();
+    }
+
+    /// Wait for all the events to be synced
+    pub async fn join_all(&mut self) {
+        while !self.is_idle() {
+            self.join_next().await;
+        }
+    }
+
+    fn start_ready(&mut self) {
+        while self.running.len() < self.concurrency {
+            let Some(index) = Self::next_ready(&self.queue, self.running.values()) else {
+                break;
+            };
+
+            let mut job = self.queue.remove(index).unwrap();
+            let handler = self.handler.clone();
+            let event = job.event.take().unwrap();
+            let emit_time = job.emit_time;
+            let handle = self
+                .workers
+                .spawn(async move { handler.handle_event(event, emit_time).await });
+            self.running.insert(handle.id(), job);
+        }
+    }
+
+    /// Index of the first queued job conflicting neither with the running ones nor with the ones
+    /// queued before it. The jobs queued after an exclusive one wait for it
+    fn next_ready<'a>(
+        queue: &VecDeque<Job>,
+        running: impl Iterator<Item = &'a Job> + Clone,
+    ) -> Option<usize> {
+        for (index, job) in queue.iter().enumerate() {
+            let is_ready = running.clone().all(|running| !job.conflicts_with(running))
+                && queue
+                    .range(..index)
+                    .all(|queued| !job.conflicts_with(queued));
+            if is_ready {
+                return Some(index);
+            }
+            if job.exclusive {
+                return None;
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use notify::event::{CreateKind, RenameMode};
+
+    use super::*;
+
+    fn job(kind: EventKind, path: &str) -> Job {
+        Job::new(
+            Event::new(kind).add_path(PathBuf::from(path)),
+            Instant::now(),
+        )
+    }
+
+    fn create(path: &str) -> Job {
+        job(EventKind::Create(CreateKind::Any), path)
+    }
+
+    fn rename(path: &str) -> Job {
+        job(EventKind::Modify(ModifyKind::Name(RenameMode::From)), path)
+    }
+
+    #[test]
+    fn independent_paths_run_in_parallel() {
+        let running = [create("/s/a")];
+        let queue = VecDeque::from([create("/s/b")]);
+
+        assert_eq!(WorkerPool::next_ready(&queue, running.iter()), Some(0));
+    }
+
+    #[test]
+    fn a_path_waits_for_its_dirs_and_its_children() {
+        let running = [create("/s/a")];
+        let queue = VecDeque::from([create("/s/a/f"), create("/s/b")]);
+        assert_eq!(WorkerPool::next_ready(&queue, running.iter()), Some(1));
+
+        // The paths queued after a dir wait for it too
+        let queue = VecDeque::from([create("/s"), create("/s/b")]);
+        assert_eq!(WorkerPool::next_ready(&queue, running.iter()), None);
+    }
+
+    #[test]
+    fn a_path_waits_for_its_earlier_events() {
+        let queue = VecDeque::from([create("/s/a"), create("/s/a"), create("/s/b")]);
+
+        assert_eq!(WorkerPool::next_ready(&queue, [].iter()), Some(0));
+        let queue = queue.into_iter().skip(1).collect();
+        assert_eq!(
+            WorkerPool::next_ready(&queue, [create("/s/a")].iter()),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn renames_run_alone() {
+        let queue = VecDeque::from([rename("/s/a"), create("/s/b")]);
+        assert_eq!(
+            WorkerPool::next_ready(&queue, [create("/s/c")].iter()),
+            None
+        );
+        assert_eq!(WorkerPool::next_ready(&queue, [].iter()), Some(0));
+
+        let queue = VecDeque::from([create("/s/b")]);
+        assert_eq!(
+            WorkerPool::next_ready(&queue, [rename("/s/a")].iter()),
+            None
+        );
+    }
+}